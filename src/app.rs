@@ -3,8 +3,9 @@ use std::sync::{Arc, Mutex};
 use crate::{
     app_state::AppState,
     components::{
-        file_loader::FileLoader, labels::Labeler, main_panel::MainPanel, top_menu::TopMenu,
-        top_panel::TopPanel, Component,
+        dir_watcher::DirWatcher, duplicate_finder::DuplicateFinder, embedder::Embedder,
+        file_loader::FileLoader, image_viewer::ImageViewer, labels::Labeler, main_panel::MainPanel,
+        prompt_presets::PromptPresetsWindow, top_menu::TopMenu, top_panel::TopPanel, Component,
     },
     enums::BroadcastMsg,
 };
@@ -28,6 +29,11 @@ impl DeskApp {
         let main_panel = MainPanel::new();
         let file_loader = FileLoader::new();
         let labeler = Labeler::new();
+        let dir_watcher = DirWatcher::new();
+        let prompt_presets = PromptPresetsWindow::new();
+        let embedder = Embedder::new();
+        let image_viewer = ImageViewer::new();
+        let duplicate_finder = DuplicateFinder::new();
 
         Self {
             action_rx,
@@ -39,6 +45,11 @@ impl DeskApp {
                 Box::new(main_panel),
                 Box::new(file_loader),
                 Box::new(labeler),
+                Box::new(dir_watcher),
+                Box::new(prompt_presets),
+                Box::new(embedder),
+                Box::new(image_viewer),
+                Box::new(duplicate_finder),
             ],
         }
     }
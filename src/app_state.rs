@@ -1,8 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::{
-    components::labels,
-    enums::{BroadcastMsg, DirectoryFiles, FileWithLabel},
+    config::{SUPPORTED_IMAGE_FORMATS, THUMBNAIL_CACHE_BUDGET_MB},
+    enums::{BroadcastMsg, DirectoryFiles, ImageStructured, LabelSource, VisionPreprocess},
+    index_db::IndexDb,
     ollama_state::OllamaState,
 };
 use tokio::sync::mpsc::UnboundedSender;
@@ -11,30 +12,67 @@ use tokio::sync::mpsc::UnboundedSender;
 pub struct AppState {
     #[serde(skip)]
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    #[serde(skip)]
+    pub index: IndexDb,
+    #[serde(skip)]
+    labeling_run: Option<i64>,
     pub ollama_state: OllamaState,
+    // -- directories and files live in the index, these are only read for the legacy import
+    #[serde(default, skip_serializing)]
     pub directories: Vec<PathBuf>,
     pub formats: Vec<String>,
-    pub dir_files: Vec<DirectoryFiles>,
+    #[serde(default)]
+    pub thumbnail_cache_mb: u64,
+    // -- moved to the per model settings of `OllamaState`
+    #[serde(default, skip_serializing)]
+    vision_preprocess: Option<VisionPreprocess>,
+    #[serde(default, skip_serializing)]
+    dir_files: Vec<DirectoryFiles>,
 }
 
 static APP_STATE_KEY: &str = "app_state";
 
 impl AppState {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        // -- get storage values
-        if let Some(storage) = cc.storage {
-            let s = eframe::get_value(storage, APP_STATE_KEY).unwrap_or_default();
-            println!("{:?}", s);
-            return s;
-        }
-
-        Self {
+        let mut state = Self {
             action_tx: None,
+            index: IndexDb::default(),
+            labeling_run: None,
             ollama_state: OllamaState::new(cc, String::from("http://127.0.0.1:11434/")),
             directories: vec![],
             formats: vec![],
+            thumbnail_cache_mb: THUMBNAIL_CACHE_BUDGET_MB,
+            vision_preprocess: None,
             dir_files: vec![],
+        };
+
+        // -- get storage values
+        if let Some(storage) = cc.storage {
+            state = eframe::get_value(storage, APP_STATE_KEY).unwrap_or_default();
+        }
+
+        state.index = IndexDb::open_default();
+        state
+            .index
+            .import_legacy(&state.directories, &state.dir_files);
+        state.dir_files.clear();
+        state.directories = state.index.directories();
+        state.formats = migrate_formats(&state.formats);
+        // -- the former global settings apply to the chosen model
+        if let (Some(settings), Some(model)) = (
+            state.vision_preprocess.take(),
+            state.ollama_state.vision_model.clone(),
+        ) {
+            state
+                .ollama_state
+                .vision_preprocess
+                .entry(model)
+                .or_insert(settings);
         }
+        if state.thumbnail_cache_mb == 0 {
+            state.thumbnail_cache_mb = THUMBNAIL_CACHE_BUDGET_MB;
+        }
+        state
     }
 
     pub fn init(&mut self) {
@@ -46,45 +84,42 @@ impl AppState {
         eframe::set_value(storage, APP_STATE_KEY, self);
     }
 
+    pub fn add_directory(&mut self, path: PathBuf) {
+        self.index.add_directory(&path.to_string_lossy());
+        if !self.directories.contains(&path) {
+            self.directories.push(path);
+        }
+    }
+
     pub fn remove_directory(&mut self, path: PathBuf) {
         self.directories.retain(|p| *p != path);
-        self.dir_files
-            .retain(|p| p.dir != path.to_string_lossy().to_string());
+        self.index.remove_directory(&path.to_string_lossy());
     }
 
-    fn save_files_from_dir(&mut self, path: PathBuf, files: Vec<String>) {
-        let dir = path.to_string_lossy().to_string();
-        if self.dir_files.iter().all(|item| item.dir != dir) {
-            let mut d_files = vec![];
-            for f in files.iter() {
-                d_files.push(FileWithLabel {
-                    file: f.to_string(),
-                    labels: vec![],
-                });
-            }
-
-            let d_files = DirectoryFiles {
-                dir,
-                files_with_labels: d_files,
-            };
-            self.dir_files.push(d_files);
-        }
+    /// Formats searched in a directory, its own override or the global ones.
+    pub fn dir_formats(&self, dir: &Path) -> Vec<String> {
+        self.index
+            .directory_formats()
+            .remove(dir)
+            .unwrap_or_else(|| self.formats.clone())
     }
 
-    fn add_labels_to_file(&mut self, file: String, labels: String) {
-        println!("File: {}, labels: {}", file, labels);
+    fn add_labels_to_file(&mut self, file: String, answer: ImageStructured, source: LabelSource) {
+        println!("File: {}, labels: {:?}", file, answer.labels);
 
-        let l_labels: Vec<String> = labels
-            .split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(String::from)
-            .collect();
+        self.index
+            .set_labels(&file, &answer.labels, self.labeling_run);
+        self.index.set_answer_details(&file, &answer, &source);
+    }
 
-        for dir in self.dir_files.iter_mut() {
-            if let Some(f_file) = dir.files_with_labels.iter_mut().find(|f| f.file == file) {
-                f_file.labels = l_labels.clone();
-            }
+    fn start_labeling_run(&mut self) {
+        self.finish_labeling_run();
+        self.labeling_run = self.index.start_labeling_run();
+    }
+
+    fn finish_labeling_run(&mut self) {
+        if let Some(run_id) = self.labeling_run.take() {
+            self.index.finish_labeling_run(run_id);
         }
     }
 
@@ -92,11 +127,51 @@ impl AppState {
         self.ollama_state.update(msg.clone());
 
         match msg {
-            BroadcastMsg::DirectoryFiles(path, files) => {
-                self.save_files_from_dir(path, files);
+            BroadcastMsg::GetLabelsForImage(file, answer, source) => {
+                self.add_labels_to_file(file, answer, source);
             }
-            BroadcastMsg::GetLabelsForImage(file, labels) => {
-                self.add_labels_to_file(file, labels);
+            BroadcastMsg::RelabelOutdated => {
+                let preset = self.ollama_state.prompts.active().version();
+                let cleared = self.index.clear_outdated_labels(preset, &self.formats);
+                println!("CLEARED {} OUTDATED LABELS", cleared);
+            }
+            BroadcastMsg::VisionRequestFailed(file, error) => {
+                self.index.set_label_error(&file, &error);
+            }
+            BroadcastMsg::GetDescriptionForImage(file, description) => {
+                self.index.set_description(&file, &description);
+            }
+            BroadcastMsg::SignatureReady(file, Some(signature)) => {
+                self.index.set_signature(&file, &signature);
+            }
+            BroadcastMsg::EmbeddingsReady(model, embeddings) => {
+                self.index.set_embeddings(&model, &embeddings);
+            }
+            BroadcastMsg::DescriptionRequestFailed(file, error) => {
+                self.index.set_description_error(&file, &error);
+            }
+            BroadcastMsg::RetryFailedLabels => {
+                self.index.clear_label_errors();
+            }
+            BroadcastMsg::SetFormats(formats) => {
+                self.formats = formats;
+            }
+            BroadcastMsg::SetDirectoryFormats(path, formats) => {
+                self.index
+                    .set_directory_formats(&path.to_string_lossy(), formats.as_deref());
+            }
+            BroadcastMsg::SetThumbnailCacheBudget(mb) => {
+                self.thumbnail_cache_mb = mb;
+            }
+            BroadcastMsg::SetDirectoryWatch(path, watch) => {
+                self.index
+                    .set_directory_watch(&path.to_string_lossy(), watch);
+            }
+            BroadcastMsg::StartLabeling => {
+                self.start_labeling_run();
+            }
+            BroadcastMsg::StopLabeling | BroadcastMsg::FinishLabeling => {
+                self.finish_labeling_run();
             }
             _ => {}
         }
@@ -107,3 +182,52 @@ impl AppState {
         self.action_tx = Some(action_tx);
     }
 }
+
+// -- formats used to be extensions, `jpg` is now `jpeg`, all of them when none is left
+fn migrate_formats(formats: &[String]) -> Vec<String> {
+    let formats = formats
+        .iter()
+        .map(|f| match f.as_str() {
+            "jpg" => "jpeg".to_string(),
+            _ => f.clone(),
+        })
+        .filter(|f| SUPPORTED_IMAGE_FORMATS.contains(&f.as_str()))
+        .fold(vec![], |mut formats, f| {
+            if !formats.contains(&f) {
+                formats.push(f);
+            }
+            formats
+        });
+    match formats.is_empty() {
+        true => SUPPORTED_IMAGE_FORMATS
+            .iter()
+            .map(|f| f.to_string())
+            .collect(),
+        false => formats,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formats(formats: &[&str]) -> Vec<String> {
+        formats.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn jpg_format_becomes_jpeg() {
+        assert_eq!(
+            migrate_formats(&formats(&["jpg", "png", "jpeg"])),
+            formats(&["jpeg", "png"])
+        );
+        assert_eq!(
+            migrate_formats(&formats(&["png", "xcf"])),
+            formats(&["png"])
+        );
+        assert_eq!(
+            migrate_formats(&formats(&["xcf"])).len(),
+            SUPPORTED_IMAGE_FORMATS.len()
+        );
+    }
+}
@@ -4,12 +4,17 @@ use std::{
 };
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::{app_state::AppState, enums::BroadcastMsg};
+use crate::{app_state::AppState, enums::BroadcastMsg, utils::split_ollama_url};
 
+pub mod dir_watcher;
+pub mod duplicate_finder;
+pub mod embedder;
 pub mod file_loader;
+pub mod image_viewer;
 pub mod labels;
 pub mod main_panel;
 pub mod ollama_settings;
+pub mod prompt_presets;
 pub mod top_menu;
 pub mod top_panel;
 
@@ -38,14 +43,11 @@ pub trait Component: Any {
     #[allow(unused_variables)]
     fn update_ctx(&mut self, msg: BroadcastMsg, ctx: &egui::Context) {}
 
+    #[allow(dead_code)]
     fn get_ollama_url(&mut self, app_state: Option<Arc<Mutex<AppState>>>) -> (String, u16) {
         if let Some(state) = app_state.clone() {
             let url = state.lock().unwrap().ollama_state.url.clone();
-            if let Some((base_url, port)) = url.rsplit_once(':') {
-                if let Ok(port_num) = port.parse::<u16>() {
-                    return (base_url.to_string(), port_num);
-                }
-            }
+            return split_ollama_url(&url);
         }
         ("http://localhost/".to_string(), 11343)
     }
@@ -0,0 +1,258 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use notify_debouncer_full::{
+    new_debouncer,
+    notify::{EventKind, RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer, RecommendedCache,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::Component;
+use crate::{
+    app_state::AppState,
+    enums::BroadcastMsg,
+    utils::{dir_device, is_non_empty_dir, is_supported_image, sleep, spawn, thumbnails_dir},
+};
+
+// -- bursts of events (copying a folder, exports) are collapsed into one rescan
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+const AVAILABILITY_CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+struct WatchedRoot {
+    path: PathBuf,
+    device: Option<u64>,
+}
+
+impl WatchedRoot {
+    fn is_available(&self) -> bool {
+        self.path.is_dir() && dir_device(&self.path) == self.device
+    }
+}
+
+fn is_relevant_change(path: &Path, thumbs_dir: &Option<PathBuf>) -> bool {
+    if let Some(thumbs_dir) = thumbs_dir {
+        if path.starts_with(thumbs_dir) {
+            return false;
+        }
+    }
+    // -- no extension is most likely a (re)moved sub directory
+    is_supported_image(path) || path.extension().is_none()
+}
+
+pub struct DirWatcher {
+    action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    app_state: Option<Arc<Mutex<AppState>>>,
+    debouncer: Option<Debouncer<RecommendedWatcher, RecommendedCache>>,
+    // -- shared with the debouncer thread
+    roots: Arc<Mutex<Vec<WatchedRoot>>>,
+    unavailable: Vec<PathBuf>,
+}
+
+impl DirWatcher {
+    pub fn new() -> Self {
+        Self {
+            action_tx: None,
+            app_state: None,
+            debouncer: None,
+            roots: Arc::new(Mutex::new(vec![])),
+            unavailable: vec![],
+        }
+    }
+
+    fn init_watcher(&mut self) {
+        let action_tx = self.action_tx.clone();
+        let roots = self.roots.clone();
+        let thumbs_dir = thumbnails_dir();
+
+        let debouncer = new_debouncer(
+            DEBOUNCE_TIMEOUT,
+            None,
+            move |result: DebounceEventResult| match result {
+                Ok(events) => {
+                    let roots = roots.lock().unwrap().clone();
+                    let mut changed = HashSet::new();
+                    for event in events.iter() {
+                        if matches!(event.kind, EventKind::Access(_)) {
+                            continue;
+                        }
+                        for path in event.paths.iter() {
+                            if !is_relevant_change(path, &thumbs_dir) {
+                                continue;
+                            }
+                            for root in roots.iter() {
+                                if path.starts_with(&root.path) && root.is_available() {
+                                    changed.insert(root.path.clone());
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(tx) = action_tx.clone() {
+                        for dir in changed {
+                            let _ = tx.send(BroadcastMsg::RescanDirectory(dir));
+                        }
+                    }
+                }
+                Err(errors) => {
+                    println!("{:?} - Error watching directories", errors);
+                    if let Some(tx) = action_tx.clone() {
+                        let _ = tx.send(BroadcastMsg::CheckWatchedDirectories);
+                    }
+                }
+            },
+        );
+
+        match debouncer {
+            Ok(d) => self.debouncer = Some(d),
+            Err(e) => println!("{:?} - Error creating directory watcher", e),
+        }
+    }
+
+    fn watch(&mut self, path: PathBuf) -> bool {
+        if self.roots.lock().unwrap().iter().any(|r| r.path == path) {
+            return true;
+        }
+
+        if let Some(debouncer) = self.debouncer.as_mut() {
+            match debouncer.watch(&path, RecursiveMode::Recursive) {
+                Ok(()) => {
+                    println!("WATCHING: {}", path.to_string_lossy());
+                    self.unavailable.retain(|p| *p != path);
+                    self.roots.lock().unwrap().push(WatchedRoot {
+                        device: dir_device(&path),
+                        path,
+                    });
+                    return true;
+                }
+                Err(e) => {
+                    println!("{:?} - Error watching {}", e, path.to_string_lossy());
+                }
+            }
+        }
+
+        if !self.unavailable.contains(&path) {
+            self.unavailable.push(path);
+        }
+        false
+    }
+
+    fn unwatch(&mut self, path: &PathBuf) {
+        self.unavailable.retain(|p| p != path);
+        self.roots.lock().unwrap().retain(|r| r.path != *path);
+        if let Some(debouncer) = self.debouncer.as_mut() {
+            let _ = debouncer.unwatch(path);
+        }
+    }
+
+    fn set_watch(&mut self, path: PathBuf, watch: bool) {
+        if watch {
+            if self.watch(path.clone()) {
+                self.send(BroadcastMsg::RescanDirectory(path));
+            }
+        } else {
+            self.unwatch(&path);
+        }
+    }
+
+    fn check_watched_directories(&mut self) {
+        let gone: Vec<PathBuf> = self
+            .roots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| !r.is_available())
+            .map(|r| r.path.clone())
+            .collect();
+        for path in gone {
+            println!("WATCHED DIRECTORY UNAVAILABLE: {}", path.to_string_lossy());
+            self.unwatch(&path);
+            self.unavailable.push(path.clone());
+            self.send(BroadcastMsg::WatchedDirectoryAvailable(path, false));
+        }
+
+        // -- a remounted directory is watched again and catches up on missed changes
+        for path in self.unavailable.clone() {
+            if is_non_empty_dir(&path) && self.watch(path.clone()) {
+                println!("WATCHED DIRECTORY BACK: {}", path.to_string_lossy());
+                self.send(BroadcastMsg::WatchedDirectoryAvailable(path.clone(), true));
+                self.send(BroadcastMsg::RescanDirectory(path));
+            }
+        }
+    }
+
+    fn send(&self, msg: BroadcastMsg) {
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(msg);
+        }
+    }
+
+    async fn period_availability_check(action_tx: Option<UnboundedSender<BroadcastMsg>>) {
+        loop {
+            sleep(AVAILABILITY_CHECK_PERIOD).await;
+
+            if let Some(tx) = action_tx.clone() {
+                let _ = tx.send(BroadcastMsg::CheckWatchedDirectories);
+            }
+        }
+    }
+}
+
+impl Component for DirWatcher {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn init(&mut self, _cc: &eframe::CreationContext<'_>) {
+        self.init_watcher();
+
+        let mut watched = vec![];
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                watched = app_state.lock().unwrap().index.watched_directories();
+            }
+        }
+        // -- an empty directory may be an unmounted drive, the device is recorded once
+        // -- it has files and the periodic check then watches it
+        for path in watched {
+            if is_non_empty_dir(&path) {
+                self.watch(path);
+            } else {
+                self.unavailable.push(path);
+            }
+        }
+
+        spawn(Self::period_availability_check(self.action_tx.clone()));
+    }
+
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::PickedDirectory(path) => {
+                self.watch(path);
+            }
+            BroadcastMsg::RemovedDirectory(path) => {
+                self.unwatch(&path);
+            }
+            BroadcastMsg::SetDirectoryWatch(path, watch) => {
+                self.set_watch(path, watch);
+            }
+            BroadcastMsg::CheckWatchedDirectories => {
+                self.check_watched_directories();
+            }
+            _ => {}
+        }
+    }
+
+    fn register_app_state(&mut self, app_state: Arc<Mutex<AppState>>) {
+        self.app_state = Some(app_state);
+    }
+
+    fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
+        self.action_tx = Some(action_tx);
+    }
+}
@@ -0,0 +1,532 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use egui::{Color32, RichText, ScrollArea, TextureHandle, TextureOptions, Vec2};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::Component;
+use crate::{
+    app_state::AppState,
+    duplicates::{delete_files, find_duplicates, move_files, wasted_bytes},
+    enums::{BroadcastMsg, DuplicateFile, DuplicateGroup},
+    image_signature::ImageSignature,
+    thumbnail_cache::ThumbnailCache,
+    thumbnail_pipeline::create_thumbnail,
+    utils::{bytes_convert, format_date, spawn},
+};
+
+const GROUPS_PER_PAGE: usize = 20;
+
+#[derive(Clone)]
+enum PendingAction {
+    Delete,
+    Move(PathBuf),
+}
+
+/// Window listing groups of duplicate images, the copies not kept in the ticked
+/// groups are moved to the trash or to a directory after a confirmation.
+pub struct DuplicateFinder {
+    action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    app_state: Option<Arc<Mutex<AppState>>>,
+    open: bool,
+    scanning: bool,
+    // -- hash bits near-duplicates may differ in
+    max_distance: u32,
+    groups: Vec<DuplicateGroup>,
+    // -- files to keep, the suggested copy of every group until changed
+    keep: HashSet<String>,
+    // -- per group, only ticked groups are handled
+    ticked: Vec<bool>,
+    page: usize,
+    thumbnails: HashMap<String, TextureHandle>,
+    requested: HashSet<String>,
+    pending: Option<PendingAction>,
+    busy: bool,
+    result: Option<(Vec<String>, Vec<String>)>,
+}
+
+impl DuplicateFinder {
+    pub fn new() -> Self {
+        Self {
+            action_tx: None,
+            app_state: None,
+            open: false,
+            scanning: false,
+            max_distance: 6,
+            groups: vec![],
+            keep: HashSet::new(),
+            ticked: vec![],
+            page: 0,
+            thumbnails: HashMap::new(),
+            requested: HashSet::new(),
+            pending: None,
+            busy: false,
+            result: None,
+        }
+    }
+
+    fn send(&self, msg: BroadcastMsg) {
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(msg);
+        }
+    }
+
+    fn scan(&mut self) {
+        let mut index = None;
+        let mut cache = None;
+        {
+            if let Some(ref app_state) = self.app_state {
+                let a_state = app_state.lock().unwrap();
+                index = Some(a_state.index.clone());
+                cache = ThumbnailCache::new(a_state.index.clone(), a_state.thumbnail_cache_mb);
+            }
+        }
+        let Some(index) = index else {
+            return;
+        };
+        self.scanning = true;
+        self.result = None;
+        let max_distance = self.max_distance;
+        let action_tx = self.action_tx.clone();
+        spawn(async move {
+            let groups = tokio::task::spawn_blocking(move || {
+                let mut files = index.fingerprints();
+                // -- signatures are otherwise only taken when a thumbnail is shown
+                for file in files.iter_mut().filter(|f| f.signature.is_none()) {
+                    if let Ok(thumb) = create_thumbnail(&cache, &file.path) {
+                        let signature = ImageSignature::from_image(&thumb);
+                        index.set_signature(&file.path, &signature);
+                        file.signature = Some(signature);
+                    }
+                }
+                find_duplicates(&files, max_distance)
+            })
+            .await
+            .unwrap_or_default();
+            if let Some(action_tx) = action_tx {
+                let _ = action_tx.send(BroadcastMsg::DuplicatesFound(groups));
+            }
+        });
+    }
+
+    fn set_groups(&mut self, groups: Vec<DuplicateGroup>) {
+        self.scanning = false;
+        self.keep = groups
+            .iter()
+            .map(|g| g.files[g.keep].path.clone())
+            .collect();
+        self.ticked = vec![false; groups.len()];
+        self.groups = groups;
+        self.page = 0;
+    }
+
+    // -- (file, kept copy) of the ticked groups with a kept copy, a group is never emptied
+    fn to_remove(&self) -> Vec<(DuplicateFile, DuplicateFile)> {
+        self.groups
+            .iter()
+            .zip(self.ticked.iter())
+            .filter(|(_, ticked)| **ticked)
+            .filter_map(|(g, _)| {
+                let suggested = &g.files[g.keep];
+                let kept = match self.keep.contains(&suggested.path) {
+                    true => suggested,
+                    false => g.files.iter().find(|f| self.keep.contains(&f.path))?,
+                };
+                Some(
+                    g.files
+                        .iter()
+                        .filter(|f| !self.keep.contains(&f.path))
+                        .map(|f| (f.clone(), kept.clone()))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .flatten()
+            .collect()
+    }
+
+    fn run(&mut self, action: PendingAction) {
+        let files = self.to_remove();
+        if files.is_empty() {
+            return;
+        }
+        self.busy = true;
+        let action_tx = self.action_tx.clone();
+        spawn(async move {
+            let (done, errors) = tokio::task::spawn_blocking(move || match action {
+                PendingAction::Delete => delete_files(&files),
+                PendingAction::Move(target) => {
+                    let files: Vec<String> = files.into_iter().map(|(f, _)| f.path).collect();
+                    move_files(&files, &target)
+                }
+            })
+            .await
+            .unwrap_or_default();
+            if let Some(action_tx) = action_tx {
+                let _ = action_tx.send(BroadcastMsg::DuplicatesHandled(done, errors));
+            }
+        });
+    }
+
+    fn handled(&mut self, done: Vec<String>, errors: Vec<String>) {
+        self.busy = false;
+        let done_set: HashSet<&String> = done.iter().collect();
+
+        // -- the index learns about the gone files from a rescan
+        let dirs: HashSet<PathBuf> = self
+            .groups
+            .iter()
+            .flat_map(|g| g.files.iter())
+            .filter(|f| done_set.contains(&f.path))
+            .map(|f| f.dir.clone())
+            .collect();
+        for dir in dirs {
+            self.send(BroadcastMsg::RescanDirectory(dir));
+        }
+
+        for group in self.groups.iter_mut() {
+            group.files.retain(|f| !done_set.contains(&f.path));
+            group.keep = group
+                .files
+                .iter()
+                .position(|f| self.keep.contains(&f.path))
+                .unwrap_or(0);
+        }
+        let (groups, ticked) = self
+            .groups
+            .drain(..)
+            .zip(self.ticked.drain(..))
+            .filter(|(g, _)| g.files.len() > 1)
+            .unzip();
+        self.groups = groups;
+        self.ticked = ticked;
+        self.page = self.page.min(self.pages().saturating_sub(1));
+        self.result = Some((done, errors));
+    }
+
+    fn pages(&self) -> usize {
+        self.groups.len().div_ceil(GROUPS_PER_PAGE)
+    }
+
+    fn page_range(&self) -> std::ops::Range<usize> {
+        let start = (self.page * GROUPS_PER_PAGE).min(self.groups.len());
+        let end = (start + GROUPS_PER_PAGE).min(self.groups.len());
+        start..end
+    }
+
+    fn page_groups(&self) -> &[DuplicateGroup] {
+        &self.groups[self.page_range()]
+    }
+
+    // -- thumbnails of the shown page, decoded one after another in the background
+    fn request_thumbnails(&mut self, ctx: &egui::Context) {
+        let files: Vec<String> = self
+            .page_groups()
+            .iter()
+            .flat_map(|g| g.files.iter())
+            .map(|f| f.path.clone())
+            .filter(|f| !self.requested.contains(f))
+            .collect();
+        if files.is_empty() {
+            return;
+        }
+        self.requested.extend(files.iter().cloned());
+
+        let mut cache = None;
+        {
+            if let Some(ref app_state) = self.app_state {
+                let a_state = app_state.lock().unwrap();
+                cache = ThumbnailCache::new(a_state.index.clone(), a_state.thumbnail_cache_mb);
+            }
+        }
+        let ctx = ctx.clone();
+        let action_tx = self.action_tx.clone();
+        spawn(async move {
+            let _ = tokio::task::spawn_blocking(move || {
+                for file in files {
+                    let Ok(thumb) = create_thumbnail(&cache, &file) else {
+                        continue;
+                    };
+                    let rgba = thumb.to_rgba8();
+                    let img = egui::ColorImage::from_rgba_unmultiplied(
+                        [thumb.width() as usize, thumb.height() as usize],
+                        rgba.as_raw(),
+                    );
+                    let texture = ctx.load_texture(
+                        format!("duplicate:{}", file),
+                        img,
+                        TextureOptions::default(),
+                    );
+                    if let Some(action_tx) = action_tx.clone() {
+                        let _ = action_tx.send(BroadcastMsg::DuplicateThumbnail(file, texture));
+                    }
+                    ctx.request_repaint();
+                }
+            })
+            .await;
+        });
+    }
+
+    fn controls_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Near-duplicate distance:");
+            ui.add(egui::Slider::new(&mut self.max_distance, 0..=24))
+                .on_hover_text(
+                    "Differing bits of the image hashes, 0 finds only visually identical images",
+                );
+            if ui
+                .add_enabled(!self.scanning, egui::Button::new("Scan"))
+                .clicked()
+            {
+                self.scan();
+            }
+            if self.scanning {
+                ui.spinner();
+            }
+        });
+
+        if !self.groups.is_empty() {
+            let to_remove = self.to_remove();
+            let bytes: u64 = to_remove.iter().map(|(f, _)| f.size).sum();
+            ui.label(format!(
+                "{} groups, {} ticked with {} copies not kept ({}), {} if only the suggestions are kept",
+                self.groups.len(),
+                self.ticked.iter().filter(|t| **t).count(),
+                to_remove.len(),
+                bytes_convert(bytes as f64),
+                bytes_convert(self.groups.iter().map(wasted_bytes).sum::<u64>() as f64),
+            ));
+        }
+        if let Some((done, errors)) = self.result.as_ref() {
+            ui.small(format!("{} files handled", done.len()));
+            if !errors.is_empty() {
+                ui.small(
+                    RichText::new(format!("{} failed", errors.len()))
+                        .color(Color32::from_rgb(255, 0, 0)),
+                )
+                .on_hover_text(errors.join("\n"));
+            }
+        }
+    }
+
+    fn group_ui(&mut self, index: usize, group: &DuplicateGroup, ui: &mut egui::Ui) {
+        ui.group(|ui| {
+            let kept = group.files.iter().any(|f| self.keep.contains(&f.path));
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.ticked[index], "")
+                    .on_hover_text("Handle the copies not kept of this group");
+                ui.small(match group.exact {
+                    true => "identical files",
+                    false => "near duplicates",
+                });
+                if !kept {
+                    ui.small(
+                        RichText::new("nothing kept, the group is skipped")
+                            .color(Color32::from_rgb(255, 0, 0)),
+                    );
+                }
+            });
+            ui.horizontal(|ui| {
+                for (i, file) in group.files.iter().enumerate() {
+                    ui.vertical(|ui| {
+                        ui.set_width(130.0);
+                        match self.thumbnails.get(&file.path) {
+                            Some(texture) => {
+                                ui.add(
+                                    egui::Image::from_texture(texture)
+                                        .fit_to_exact_size(Vec2::new(120.0, 120.0)),
+                                );
+                            }
+                            None => {
+                                ui.allocate_exact_size(
+                                    Vec2::new(120.0, 120.0),
+                                    egui::Sense::hover(),
+                                );
+                            }
+                        }
+                        let name = Path::new(&file.path)
+                            .file_name()
+                            .map(|n| n.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        ui.small(name).on_hover_text(&file.path);
+                        if file.width > 0 {
+                            ui.small(format!("{} x {}", file.width, file.height));
+                        }
+                        ui.small(bytes_convert(file.size as f64));
+                        ui.small(format_date(file.mtime));
+
+                        let mut keep = self.keep.contains(&file.path);
+                        let label = match i == group.keep {
+                            true => "keep (suggested)",
+                            false => "keep",
+                        };
+                        if ui.checkbox(&mut keep, label).changed() {
+                            match keep {
+                                true => self.keep.insert(file.path.clone()),
+                                false => self.keep.remove(&file.path),
+                            };
+                        }
+                    });
+                }
+            });
+        });
+    }
+
+    fn actions_ui(&mut self, ui: &mut egui::Ui) {
+        let to_remove = self.to_remove();
+        if let Some(pending) = self.pending.clone() {
+            let question = match &pending {
+                PendingAction::Delete => {
+                    format!("Move {} files to the trash?", to_remove.len())
+                }
+                PendingAction::Move(target) => {
+                    format!(
+                        "Move {} files to {}?",
+                        to_remove.len(),
+                        target.to_string_lossy()
+                    )
+                }
+            };
+            ScrollArea::vertical()
+                .id_salt("duplicates_to_remove")
+                .max_height(160.0)
+                .show(ui, |ui| {
+                    for (file, kept) in to_remove.iter() {
+                        ui.small(format!("{}  (keeping {})", file.path, kept.path));
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(question).color(Color32::from_rgb(255, 120, 120)));
+                if ui.button("Yes").clicked() {
+                    self.pending = None;
+                    self.run(pending);
+                }
+                if ui.button("Cancel").clicked() {
+                    self.pending = None;
+                }
+            });
+            return;
+        }
+
+        let enabled = !to_remove.is_empty() && !self.busy;
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(
+                    enabled,
+                    egui::Button::new("Move copies not kept to the trash"),
+                )
+                .clicked()
+            {
+                self.pending = Some(PendingAction::Delete);
+            }
+            if ui
+                .add_enabled(enabled, egui::Button::new("Move copies not kept to..."))
+                .clicked()
+            {
+                if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                    self.pending = Some(PendingAction::Move(dir));
+                }
+            }
+            if self.busy {
+                ui.spinner();
+            }
+        });
+    }
+
+    fn tick_page(&mut self, ticked: bool) {
+        let range = self.page_range();
+        self.ticked[range].fill(ticked);
+    }
+}
+
+impl Component for DuplicateFinder {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::OpenDuplicateFinder => {
+                self.open = true;
+            }
+            BroadcastMsg::DuplicatesFound(groups) => {
+                self.set_groups(groups);
+            }
+            BroadcastMsg::DuplicateThumbnail(file, texture) => {
+                self.thumbnails.insert(file, texture);
+            }
+            BroadcastMsg::DuplicatesHandled(done, errors) => {
+                self.handled(done, errors);
+            }
+            _ => {}
+        }
+    }
+
+    fn render(&mut self, ctx: &egui::Context) {
+        if !self.open {
+            return;
+        }
+        self.request_thumbnails(ctx);
+
+        let mut open = self.open;
+        egui::Window::new("Duplicates")
+            .open(&mut open)
+            .default_size([820.0, 600.0])
+            .show(ctx, |ui| {
+                self.controls_ui(ui);
+                ui.separator();
+
+                if self.groups.is_empty() {
+                    ui.small("No duplicates found");
+                    return;
+                }
+                self.actions_ui(ui);
+                ui.horizontal(|ui| {
+                    if ui
+                        .add_enabled(self.page > 0, egui::Button::new("<"))
+                        .clicked()
+                    {
+                        self.page -= 1;
+                    }
+                    ui.small(format!("page {} of {}", self.page + 1, self.pages()));
+                    if ui
+                        .add_enabled(self.page + 1 < self.pages(), egui::Button::new(">"))
+                        .clicked()
+                    {
+                        self.page += 1;
+                    }
+                    ui.separator();
+                    if ui.small_button("tick this page").clicked() {
+                        self.tick_page(true);
+                    }
+                    if ui.small_button("untick this page").clicked() {
+                        self.tick_page(false);
+                    }
+                });
+                ScrollArea::vertical()
+                    .auto_shrink([false, false])
+                    .show(ui, |ui| {
+                        let start = self.page_range().start;
+                        for (i, group) in self.page_groups().to_vec().iter().enumerate() {
+                            self.group_ui(start + i, group, ui);
+                        }
+                    });
+            });
+        self.open = open;
+        // -- textures are only kept while the window is open
+        if !self.open {
+            self.thumbnails.clear();
+            self.requested.clear();
+        }
+    }
+
+    fn register_app_state(&mut self, app_state: Arc<Mutex<AppState>>) {
+        self.app_state = Some(app_state);
+    }
+
+    fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
+        self.action_tx = Some(action_tx);
+    }
+}
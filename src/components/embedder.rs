@@ -0,0 +1,176 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::Component;
+use crate::{
+    app_state::AppState,
+    enums::BroadcastMsg,
+    index_db::IndexDb,
+    ollama_state::OllamaState,
+    utils::{sleep, spawn},
+};
+
+// -- texts sent in one `/api/embed` request
+const EMBED_BATCH: usize = 32;
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Embeds the labels, caption and description of labeled images for the
+/// semantic search, one batch at a time in the background.
+pub struct Embedder {
+    action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    app_state: Option<Arc<Mutex<AppState>>>,
+    // -- a batch is on its way
+    running: bool,
+    // -- texts may have changed since the index was last looked at
+    dirty: bool,
+    ollama_running: bool,
+    // -- failed batches in a row, the next one waits until `retry_at`
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Embedder {
+    pub fn new() -> Self {
+        Self {
+            action_tx: None,
+            app_state: None,
+            running: false,
+            dirty: true,
+            ollama_running: false,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    fn next_batch(&mut self) {
+        if self.running || !self.dirty || !self.ollama_running {
+            return;
+        }
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        self.dirty = false;
+
+        let mut index = None;
+        let mut url = String::new();
+        let mut model = None;
+        {
+            if let Some(ref app_state) = self.app_state {
+                let a_state = app_state.lock().unwrap();
+                index = Some(a_state.index.clone());
+                url = a_state.ollama_state.url.clone();
+                model = a_state.ollama_state.search.embedding_model.clone();
+            }
+        }
+        let (Some(index), Some(model)) = (index, model) else {
+            return;
+        };
+
+        self.running = true;
+        spawn(Self::embed_batch(index, url, model, self.action_tx.clone()));
+    }
+
+    // -- an empty `EmbeddingsReady` when every text is embedded already
+    async fn embed_batch(
+        index: IndexDb,
+        url: String,
+        model: String,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    ) {
+        let pending_model = model.clone();
+        let mut files = tokio::task::spawn_blocking(move || index.files_to_embed(&pending_model))
+            .await
+            .unwrap_or_default();
+        if let Some(action_tx) = action_tx.as_ref() {
+            let _ = action_tx.send(BroadcastMsg::EmbeddingProgress(files.len()));
+        }
+        files.truncate(EMBED_BATCH);
+        if files.is_empty() {
+            if let Some(action_tx) = action_tx {
+                let _ = action_tx.send(BroadcastMsg::EmbeddingsReady(model, vec![]));
+            }
+            return;
+        }
+
+        let texts = files.iter().map(|(_, text)| text.clone()).collect();
+        let msg = match OllamaState::embed(url, model.clone(), texts).await {
+            Ok(vectors) => {
+                let embeddings = files
+                    .into_iter()
+                    .zip(vectors)
+                    .map(|((file, text), vector)| (file, text, vector))
+                    .collect();
+                BroadcastMsg::EmbeddingsReady(model, embeddings)
+            }
+            Err(e) => BroadcastMsg::EmbeddingFailed(e),
+        };
+        if let Some(action_tx) = action_tx {
+            let _ = action_tx.send(msg);
+        }
+    }
+}
+
+impl Component for Embedder {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::EmbedPending
+            | BroadcastMsg::GetLabelsForImage(_, _, _)
+            | BroadcastMsg::GetDescriptionForImage(_, _)
+            | BroadcastMsg::SetSearchSettings(_) => {
+                self.dirty = true;
+                self.next_batch();
+            }
+            // -- stored by the app state already
+            BroadcastMsg::EmbeddingsReady(_, embeddings) => {
+                self.running = false;
+                self.failures = 0;
+                self.retry_at = None;
+                self.dirty |= !embeddings.is_empty();
+                self.next_batch();
+            }
+            // -- e.g. the model is still loading, tried again after a backoff
+            BroadcastMsg::EmbeddingFailed(e) => {
+                self.running = false;
+                self.dirty = true;
+                let backoff =
+                    (RETRY_BACKOFF * 2u32.pow(self.failures.min(5))).min(MAX_RETRY_BACKOFF);
+                self.failures += 1;
+                self.retry_at = Some(Instant::now() + backoff);
+                println!(
+                    "{} - Error embedding image texts, retrying in {}s",
+                    e,
+                    backoff.as_secs()
+                );
+                let action_tx = self.action_tx.clone();
+                spawn(async move {
+                    sleep(backoff).await;
+                    if let Some(action_tx) = action_tx {
+                        let _ = action_tx.send(BroadcastMsg::EmbedPending);
+                    }
+                });
+            }
+            BroadcastMsg::OllamaRunning(status) => {
+                self.ollama_running = status.is_ok();
+                self.next_batch();
+            }
+            _ => {}
+        }
+    }
+
+    fn register_app_state(&mut self, app_state: Arc<Mutex<AppState>>) {
+        self.app_state = Some(app_state);
+    }
+
+    fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
+        self.action_tx = Some(action_tx);
+    }
+}
@@ -1,23 +1,35 @@
 use std::{
-    fs,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
 };
 
-use egui::TextureOptions;
-use image::ImageReader;
 use tokio::sync::mpsc::UnboundedSender;
 
 use super::Component;
 use crate::{
     app_state::AppState,
-    enums::{BroadcastMsg, DirectoryFiles, DirectoryImage, DirectoryImages, FileWithLabel},
-    utils::search_images_at_path,
+    enums::{
+        BroadcastMsg, DirectoryFiles, DirectoryImage, DirectoryImages, FileWithLabel,
+        RescanSummary, ThumbnailCacheStats,
+    },
+    index_db::IndexDb,
+    thumbnail_cache::ThumbnailCache,
+    thumbnail_pipeline::ThumbnailPipeline,
+    utils::{dir_device, is_non_empty_dir, search_images_at_path, spawn},
 };
 use std::sync::{Arc, Mutex};
 
 pub struct FileLoader {
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
     app_state: Option<Arc<Mutex<AppState>>>,
+    pipeline: Option<ThumbnailPipeline>,
+    // -- directories being scanned, those changed meanwhile and whether to refresh them
+    scanning: HashSet<PathBuf>,
+    rescan_again: HashMap<PathBuf, bool>,
+    refresh: HashSet<PathBuf>,
+    // -- device of the directories once they had files, and removals the user confirmed
+    devices: HashMap<PathBuf, Option<u64>>,
+    confirmed: HashSet<PathBuf>,
 }
 
 impl FileLoader {
@@ -25,128 +37,292 @@ impl FileLoader {
         Self {
             action_tx: None,
             app_state: None,
+            pipeline: None,
+            scanning: HashSet::new(),
+            rescan_again: HashMap::new(),
+            refresh: HashSet::new(),
+            devices: HashMap::new(),
+            confirmed: HashSet::new(),
         }
     }
 
     fn init_thumbnails(&mut self, cc: &eframe::CreationContext<'_>) {
-        let mut dir_files = vec![];
+        let mut index = IndexDb::default();
         {
             if let Some(app_state) = self.app_state.clone() {
-                dir_files = app_state.lock().unwrap().dir_files.clone();
+                index = app_state.lock().unwrap().index.clone();
             }
         }
+        self.pipeline = Some(ThumbnailPipeline::new(
+            cc.egui_ctx.clone(),
+            self.action_tx.clone(),
+            index,
+        ));
 
-        for dir in dir_files.iter() {
-            self.create_thumbnails(dir, cc.egui_ctx.clone());
+        let mut directories = vec![];
+        let mut index = None;
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                let a_state = app_state.lock().unwrap();
+                directories = a_state.directories.clone();
+                index = Some(a_state.index.clone());
+            }
+        }
+
+        if let Some(index) = index {
+            for dir in directories.iter() {
+                if is_non_empty_dir(dir) {
+                    self.devices.insert(dir.clone(), dir_device(dir));
+                }
+                let dir_files = index.dir_files(&dir.to_string_lossy());
+                self.create_thumbnails(&dir_files, None);
+            }
+            for dir in index.unhashed_directories() {
+                self.rescan_directory(dir, false);
+            }
         }
     }
 
-    fn create_thumbnails(&mut self, dir_files: &DirectoryFiles, ctx: egui::Context) {
-        let path = PathBuf::from(dir_files.dir.clone());
-        let files = dir_files.files_with_labels.clone();
+    fn thumbnail_cache(&self) -> Option<ThumbnailCache> {
+        if let Some(app_state) = self.app_state.clone() {
+            let a_state = app_state.lock().unwrap();
+            return ThumbnailCache::new(a_state.index.clone(), a_state.thumbnail_cache_mb);
+        }
+        None
+    }
 
-        let mut thumbs_has_dir = false;
-        let mut thumbnails_dir = "".to_string();
-        if let Some(data_dirs) = directories::UserDirs::new() {
-            thumbnails_dir = format!("{}/deskvision", data_dirs.home_dir().to_string_lossy());
-            if let Ok(thumb_dir_done) = fs::create_dir_all(thumbnails_dir.clone()) {
-                thumbs_has_dir = true;
+    /// Sends the directory with placeholders right away and queues the thumbnails,
+    /// `changed` limits the queue to those files (the others are already displayed).
+    fn create_thumbnails(&mut self, dir_files: &DirectoryFiles, changed: Option<&[String]>) {
+        let path = PathBuf::from(dir_files.dir.clone());
+        let mut formats = vec![];
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                formats = app_state.lock().unwrap().dir_formats(&path);
             }
         }
 
-        let mut dir_imgs = vec![];
-        for file in files.iter() {
-            let mut img_file_path = file.file.clone();
+        // -- files of disabled formats stay indexed (with their labels) but are not shown
+        let files: Vec<FileWithLabel> = dir_files
+            .files_with_labels
+            .iter()
+            .filter(|f| formats.contains(&f.format))
+            .cloned()
+            .collect();
 
-            if thumbs_has_dir {
-                let thumb_file = Path::new(&file.file)
-                    .file_name()
-                    .and_then(|f| f.to_str())
-                    .unwrap_or("unknown");
-                let thumb_path = format!("{}/{}", thumbnails_dir, thumb_file);
-                if Path::new(&thumb_path).exists() {
-                    img_file_path = thumb_path.to_string();
-                }
-            }
+        let images = files
+            .iter()
+            .map(|file| DirectoryImage {
+                file: file.file.to_string(),
+                labels: file.labels.clone(),
+                description: file.description.clone(),
+                texture: None,
+                error: file.error.clone(),
+            })
+            .collect();
 
-            println!(">THUMBING IMG FILE: {}", img_file_path);
-
-            if let Ok(thumb_decode) = ImageReader::open(img_file_path.clone())
-                .unwrap()
-                .with_guessed_format()
-                .unwrap()
-                .decode()
-            {
-                let thumb = thumb_decode.thumbnail(160, 160);
-
-                if thumbs_has_dir && file.file.clone() == img_file_path {
-                    let thumb_file = Path::new(&file.file)
-                        .file_name()
-                        .and_then(|f| f.to_str())
-                        .unwrap_or("unknown");
-                    let thumb_path = format!("{}/{}", thumbnails_dir, thumb_file);
-                    println!("{:?} - THUMBS DIR PATH FILE", thumb_path);
-                    match thumb.save(thumb_path) {
-                        Ok(ok_thumb) => {
-                            println!("{:?}", ok_thumb);
-                        }
-                        Err(err) => {
-                            println!("{:?}", err);
-                        }
-                    }
-                }
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(BroadcastMsg::DirectoryImages(DirectoryImages {
+                dir: path.clone(),
+                images,
+            }));
+        }
+
+        let jobs = match changed {
+            Some(changed) => files
+                .into_iter()
+                .filter(|f| changed.contains(&f.file))
+                .collect(),
+            None => files,
+        };
+        if let Some(pipeline) = self.pipeline.as_ref() {
+            pipeline.enqueue(path, jobs, self.thumbnail_cache());
+        }
+    }
 
-                println!("{} - {} = img w,h", thumb.width(), thumb.height());
+    // -- directories with their own formats are not affected by the global ones
+    fn rescan_global_format_dirs(&mut self) {
+        let mut directories = vec![];
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                let a_state = app_state.lock().unwrap();
+                let overrides = a_state.index.directory_formats();
+                directories = a_state
+                    .directories
+                    .iter()
+                    .filter(|d| !overrides.contains_key(*d))
+                    .cloned()
+                    .collect();
+            }
+        }
+        for dir in directories {
+            self.rescan_directory(dir, true);
+        }
+    }
 
-                let rgba = thumb.to_rgba8();
-                let img = egui::ColorImage::from_rgba_unmultiplied(
-                    [thumb.width() as usize, thumb.height() as usize],
-                    rgba.as_raw(),
-                );
+    fn thumbnail_cache_command(&mut self, msg: BroadcastMsg) {
+        if let Some(cache) = self.thumbnail_cache() {
+            let removed = match msg {
+                BroadcastMsg::ClearThumbnailCache => cache.clear(),
+                BroadcastMsg::CheckThumbnailCache => cache.check() + cache.evict(),
+                BroadcastMsg::SetThumbnailCacheBudget(_) => cache.evict(),
+                _ => 0,
+            };
+            if let Some(action_tx) = self.action_tx.clone() {
+                let _ = action_tx.send(BroadcastMsg::ThumbnailCacheStats(ThumbnailCacheStats {
+                    removed,
+                    ..cache.stats()
+                }));
+            }
+        }
+    }
 
-                let texture =
-                    ctx.load_texture(file.file.to_string(), img, TextureOptions::default());
-                let dir_img = DirectoryImage {
-                    file: file.file.to_string(),
-                    labels: file.labels.clone(),
-                    texture,
-                };
+    // -- mounted on the device it was indexed on, or holding files when not known yet
+    fn is_reachable(&self, dir: &Path) -> bool {
+        dir.is_dir()
+            && match self.devices.get(dir) {
+                Some(device) => dir_device(dir) == *device,
+                None => is_non_empty_dir(dir),
+            }
+    }
 
-                dir_imgs.push(dir_img);
+    /// Lists, hashes and diffs the directory against the index in the background,
+    /// the thumbnails follow on `DirectoryRescanned`. `refresh` re-queues every
+    /// thumbnail, e.g. when files of a newly enabled format were already indexed and
+    /// so are not reported as added.
+    fn rescan_directory(&mut self, path: PathBuf, refresh: bool) {
+        // -- one scan per directory at a time, changes meanwhile scan it once more
+        if self.scanning.contains(&path) {
+            let again = self.rescan_again.entry(path).or_default();
+            *again |= refresh;
+            return;
+        }
+
+        let mut index = None;
+        let mut formats = vec![];
+        let mut others = vec![];
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                let a_state = app_state.lock().unwrap();
+                index = Some(a_state.index.clone());
+                formats = a_state.dir_formats(&path);
+                others = a_state
+                    .directories
+                    .iter()
+                    .filter(|d| **d != path && self.is_reachable(d))
+                    .map(|d| (d.clone(), a_state.dir_formats(d)))
+                    .collect();
             }
         }
 
-        let dir_obj = DirectoryImages {
-            dir: path,
-            images: dir_imgs,
-        };
+        // -- a missing or unmounted directory would otherwise drop all its files
+        let device = self.devices.get(&path).copied();
+        if !path.is_dir() || device.is_some_and(|d| dir_device(&path) != d) {
+            println!("RESCAN SKIPPED, NOT AVAILABLE: {}", path.to_string_lossy());
+            return;
+        }
 
-        if let Some(action_tx) = self.action_tx.clone() {
-            let _ = action_tx.send(BroadcastMsg::DirectoryImages(dir_obj));
+        let Some(index) = index else {
+            return;
+        };
+        self.scanning.insert(path.clone());
+        if refresh {
+            self.refresh.insert(path.clone());
         }
+        let confirmed = self.confirmed.remove(&path);
+        let action_tx = self.action_tx.clone();
+        spawn(async move {
+            let dir = path.clone();
+            let summary = tokio::task::spawn_blocking(move || {
+                let files = search_images_at_path(dir.clone(), &formats);
+                let reachable: Vec<String> = others
+                    .iter()
+                    .map(|(d, _)| d.to_string_lossy().to_string())
+                    .collect();
+                let elsewhere = || {
+                    others
+                        .iter()
+                        .flat_map(|(d, f)| {
+                            let d_string = d.to_string_lossy().to_string();
+                            search_images_at_path(d.clone(), f)
+                                .into_iter()
+                                .map(move |file| (d_string.clone(), file))
+                        })
+                        .collect()
+                };
+                let dir_string = dir.to_string_lossy();
+                index.rescan_directory(
+                    &dir_string,
+                    &files,
+                    &formats,
+                    elsewhere,
+                    &reachable,
+                    confirmed,
+                )
+            })
+            .await
+            .unwrap_or_default();
+            if let Some(action_tx) = action_tx {
+                let _ = action_tx.send(BroadcastMsg::DirectoryRescanned(path, summary));
+            }
+        });
     }
 
-    fn search_images_on_path(&mut self, path: PathBuf, ctx: &egui::Context) {
-        let files = search_images_at_path(path.clone());
-        if let Some(action_tx) = self.action_tx.clone() {
-            let _ = action_tx.send(BroadcastMsg::DirectoryFiles(path.clone(), files.clone()));
+    fn rescanned(&mut self, path: PathBuf, summary: RescanSummary) {
+        self.scanning.remove(&path);
+        let refresh = self.refresh.remove(&path);
+        let dir = path.to_string_lossy().to_string();
+        println!("RESCANNED {}: {}", dir, summary.describe());
+
+        let mut index = None;
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                index = Some(app_state.lock().unwrap().index.clone());
+            }
+        }
+        if let Some(index) = index.filter(|_| summary.unconfirmed == 0) {
+            let dir_files = index.dir_files(&dir);
+            if !dir_files.files_with_labels.is_empty() {
+                self.devices.insert(path.clone(), dir_device(&path));
+            }
+            // -- files that failed before are retried
+            let failed = dir_files
+                .files_with_labels
+                .iter()
+                .filter(|f| f.error.is_some())
+                .map(|f| &f.file);
+            let changed: Vec<String> = summary
+                .added
+                .iter()
+                .chain(summary.modified.iter())
+                .chain(summary.moved.iter().map(|(_, new)| new))
+                .chain(failed)
+                .cloned()
+                .collect();
+            if refresh {
+                if let Some(pipeline) = self.pipeline.as_ref() {
+                    pipeline.cancel_dir(&path);
+                }
+                self.create_thumbnails(&dir_files, None);
+            } else {
+                self.create_thumbnails(&dir_files, Some(&changed));
+            }
         }
 
-        let mut d_files = vec![];
-        for f in files.iter() {
-            d_files.push(FileWithLabel {
-                file: f.to_string(),
-                labels: vec![],
-            });
+        // -- files moved to another directory show up there
+        let moved_to: HashSet<PathBuf> = summary
+            .moved
+            .iter()
+            .filter_map(|(_, new)| Path::new(new).parent().map(Path::to_path_buf))
+            .filter(|d| *d != path)
+            .collect();
+        for other in moved_to {
+            self.rescan_directory(other, false);
         }
 
-        self.create_thumbnails(
-            &DirectoryFiles {
-                dir: path.to_string_lossy().to_string(),
-                files_with_labels: d_files,
-            },
-            ctx.clone(),
-        );
+        if let Some(again) = self.rescan_again.remove(&path) {
+            self.rescan_directory(path, again);
+        }
     }
 }
 
@@ -159,9 +335,44 @@ impl Component for FileLoader {
         self.init_thumbnails(cc);
     }
 
-    fn update_ctx(&mut self, msg: BroadcastMsg, ctx: &egui::Context) {
-        if let BroadcastMsg::PickedDirectory(path) = msg {
-            self.search_images_on_path(path, ctx);
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::PickedDirectory(path) => {
+                self.rescan_directory(path, true);
+            }
+            BroadcastMsg::DirectoryRescanned(path, summary) => {
+                self.rescanned(path, summary);
+            }
+            BroadcastMsg::RescanDirectory(path) => {
+                self.rescan_directory(path, false);
+            }
+            BroadcastMsg::ConfirmRescanRemoval(path) => {
+                self.confirmed.insert(path.clone());
+                self.rescan_directory(path, false);
+            }
+            BroadcastMsg::SetFormats(_) => {
+                self.rescan_global_format_dirs();
+            }
+            BroadcastMsg::SetDirectoryFormats(path, _) => {
+                self.rescan_directory(path, true);
+            }
+            BroadcastMsg::RemovedDirectory(path) => {
+                if let Some(pipeline) = self.pipeline.as_ref() {
+                    pipeline.cancel_dir(&path);
+                }
+            }
+            BroadcastMsg::PrioritizeThumbnails(files) => {
+                if let Some(pipeline) = self.pipeline.as_ref() {
+                    pipeline.prioritize(&files);
+                }
+            }
+            BroadcastMsg::GetThumbnailCacheStats
+            | BroadcastMsg::ClearThumbnailCache
+            | BroadcastMsg::CheckThumbnailCache
+            | BroadcastMsg::SetThumbnailCacheBudget(_) => {
+                self.thumbnail_cache_command(msg);
+            }
+            _ => {}
         }
     }
 
@@ -0,0 +1,309 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use egui::{pos2, Color32, Pos2, Rect, RichText, Sense, Stroke, TextureHandle, TextureOptions};
+use image::{imageops::FilterType, DynamicImage};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::Component;
+use crate::{
+    app_state::AppState, enums::BroadcastMsg, image_loader::open_image,
+    image_signature::ImageSignature, utils::spawn,
+};
+
+// -- longest edge of the texture shown, the crop is taken from the full image
+const MAX_TEXTURE_EDGE: u32 = 2048;
+// -- smaller selections are treated as a click
+const MIN_CROP_PX: u32 = 8;
+
+/// Full size view of one image with a crop tool, the selected region is
+/// searched by its look or by the labels the vision model gives it.
+pub struct ImageViewer {
+    action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    app_state: Option<Arc<Mutex<AppState>>>,
+    open: bool,
+    file: String,
+    image: Option<Arc<DynamicImage>>,
+    texture: Option<TextureHandle>,
+    error: Option<String>,
+    // -- in image coordinates from 0 to 1
+    selection: Option<Rect>,
+    drag_start: Option<Pos2>,
+    labeling: bool,
+    crop_labels: Result<Vec<String>, String>,
+}
+
+impl ImageViewer {
+    pub fn new() -> Self {
+        Self {
+            action_tx: None,
+            app_state: None,
+            open: false,
+            file: String::new(),
+            image: None,
+            texture: None,
+            error: None,
+            selection: None,
+            drag_start: None,
+            labeling: false,
+            crop_labels: Ok(vec![]),
+        }
+    }
+
+    fn send(&self, msg: BroadcastMsg) {
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(msg);
+        }
+    }
+
+    fn open_file(&mut self, file: String) {
+        self.open = true;
+        self.file = file.clone();
+        self.image = None;
+        self.texture = None;
+        self.error = None;
+        self.selection = None;
+        self.drag_start = None;
+        self.crop_labels = Ok(vec![]);
+        // -- the answer for a crop of the previous image is ignored
+        self.labeling = false;
+        spawn(Self::load(file, self.action_tx.clone()));
+    }
+
+    async fn load(file: String, action_tx: Option<UnboundedSender<BroadcastMsg>>) {
+        let path = file.clone();
+        let image = tokio::task::spawn_blocking(move || open_image(&path))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.map(Arc::new).map_err(|e| e.describe()));
+        if let Some(action_tx) = action_tx {
+            let _ = action_tx.send(BroadcastMsg::ViewerImageLoaded(file, image));
+        }
+    }
+
+    fn set_image(&mut self, image: Arc<DynamicImage>, ctx: &egui::Context) {
+        let shown = match image.width().max(image.height()) > MAX_TEXTURE_EDGE {
+            true => image.resize(MAX_TEXTURE_EDGE, MAX_TEXTURE_EDGE, FilterType::Triangle),
+            false => (*image).clone(),
+        };
+        let rgba = shown.to_rgba8();
+        let color_image = egui::ColorImage::from_rgba_unmultiplied(
+            [shown.width() as usize, shown.height() as usize],
+            rgba.as_raw(),
+        );
+        self.texture = Some(ctx.load_texture(
+            format!("viewer:{}", self.file),
+            color_image,
+            TextureOptions::LINEAR,
+        ));
+        self.image = Some(image);
+    }
+
+    // -- (x, y, width, height) of the selection in the full image, `None` without a usable one
+    fn crop_rect(&self) -> Option<(u32, u32, u32, u32)> {
+        let (image, selection) = (self.image.as_ref()?, self.selection?);
+        let (w, h) = (image.width() as f32, image.height() as f32);
+        let x = (selection.min.x * w) as u32;
+        let y = (selection.min.y * h) as u32;
+        let cw = (selection.width() * w) as u32;
+        let ch = (selection.height() * h) as u32;
+        (cw >= MIN_CROP_PX && ch >= MIN_CROP_PX).then_some((x, y, cw, ch))
+    }
+
+    fn crop(&self) -> Option<DynamicImage> {
+        let (x, y, w, h) = self.crop_rect()?;
+        Some(self.image.as_ref()?.crop_imm(x, y, w, h))
+    }
+
+    fn crop_name(&self) -> String {
+        let name = Path::new(&self.file)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        format!("crop of {}", name)
+    }
+
+    fn search_similar(&mut self) {
+        if let Some(crop) = self.crop() {
+            let signature = ImageSignature::from_image(&crop);
+            self.send(BroadcastMsg::FindSimilarSignature(
+                self.crop_name(),
+                signature,
+            ));
+        }
+    }
+
+    // -- the Labeler asks with the active preset on one of its servers
+    fn label_crop(&mut self) {
+        let Some(crop) = self.crop() else {
+            return;
+        };
+        self.labeling = true;
+        self.send(BroadcastMsg::LabelCrop(self.file.clone(), Arc::new(crop)));
+    }
+
+    fn toolbar_ui(&mut self, ui: &mut egui::Ui) {
+        let has_crop = self.crop_rect().is_some();
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(has_crop, egui::Button::new("Search similar"))
+                .on_hover_text("Images that look like the selected region")
+                .clicked()
+            {
+                self.search_similar();
+            }
+            if ui
+                .add_enabled(
+                    has_crop && !self.labeling,
+                    egui::Button::new("Label and search"),
+                )
+                .on_hover_text("Labels the selected region with the vision model and searches them")
+                .clicked()
+            {
+                self.label_crop();
+            }
+            if ui
+                .add_enabled(self.selection.is_some(), egui::Button::new("Clear"))
+                .clicked()
+            {
+                self.selection = None;
+            }
+            if self.labeling {
+                ui.spinner();
+            }
+            match &self.crop_labels {
+                Ok(labels) if !labels.is_empty() => {
+                    ui.small(labels.join(", "));
+                }
+                Err(e) => {
+                    ui.small(RichText::new(e).color(Color32::from_rgb(255, 0, 0)));
+                }
+                _ => {}
+            }
+        });
+        if !has_crop {
+            ui.small("Drag over the image to select a region");
+        }
+    }
+
+    fn image_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(texture) = self.texture.clone() else {
+            match &self.error {
+                Some(e) => ui.label(RichText::new(e).color(Color32::from_rgb(255, 120, 120))),
+                None => ui.spinner(),
+            };
+            return;
+        };
+
+        // -- fit the image into the window, keeping its aspect ratio
+        let available = ui.available_size();
+        let tex_size = texture.size_vec2();
+        let scale = (available.x / tex_size.x)
+            .min(available.y / tex_size.y)
+            .max(0.01);
+        let (rect, resp) = ui.allocate_exact_size(tex_size * scale, Sense::drag());
+        let uv = Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0));
+        ui.painter().image(texture.id(), rect, uv, Color32::WHITE);
+
+        let to_image = |p: Pos2| {
+            let n = (p - rect.min) / rect.size();
+            pos2(n.x.clamp(0.0, 1.0), n.y.clamp(0.0, 1.0))
+        };
+        if resp.drag_started() {
+            self.drag_start = resp.interact_pointer_pos().map(to_image);
+        }
+        if let (Some(start), Some(pos)) = (self.drag_start, resp.interact_pointer_pos()) {
+            if resp.dragged() {
+                self.selection = Some(Rect::from_two_pos(start, to_image(pos)));
+            }
+        }
+        if resp.drag_stopped() {
+            self.drag_start = None;
+        }
+
+        if let Some(selection) = self.selection {
+            let screen = Rect::from_min_max(
+                rect.min + selection.min.to_vec2() * rect.size(),
+                rect.min + selection.max.to_vec2() * rect.size(),
+            );
+            ui.painter().rect_stroke(
+                screen,
+                0.0,
+                Stroke::new(2.0, Color32::from_rgb(0, 255, 255)),
+                egui::StrokeKind::Outside,
+            );
+        }
+    }
+}
+
+impl Component for ImageViewer {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::OpenImageViewer(file) => {
+                self.open_file(file);
+            }
+            BroadcastMsg::CropLabeled(file, labels) if file == self.file => {
+                self.labeling = false;
+                if let Ok(labels) = &labels {
+                    // -- every label as a phrase, any of them may match
+                    let query: Vec<String> = labels
+                        .iter()
+                        .map(|l| format!("\"{}\"", l.replace('"', "")))
+                        .collect();
+                    self.send(BroadcastMsg::SearchByLabels(query.join(", ")));
+                }
+                self.crop_labels = labels;
+            }
+            _ => {}
+        }
+    }
+
+    fn update_ctx(&mut self, msg: BroadcastMsg, ctx: &egui::Context) {
+        if let BroadcastMsg::ViewerImageLoaded(file, image) = msg {
+            if file != self.file {
+                return;
+            }
+            match image {
+                Ok(image) => self.set_image(image, ctx),
+                Err(e) => self.error = Some(e),
+            }
+        }
+    }
+
+    fn render(&mut self, ctx: &egui::Context) {
+        if !self.open {
+            return;
+        }
+        let mut open = self.open;
+        egui::Window::new(self.file.clone())
+            .id(egui::Id::new("image_viewer"))
+            .open(&mut open)
+            .default_size([800.0, 600.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                self.toolbar_ui(ui);
+                ui.separator();
+                self.image_ui(ui);
+            });
+        self.open = open;
+        // -- the full image is only kept while the viewer is open
+        if !self.open {
+            self.image = None;
+            self.texture = None;
+        }
+    }
+
+    fn register_app_state(&mut self, app_state: Arc<Mutex<AppState>>) {
+        self.app_state = Some(app_state);
+    }
+
+    fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
+        self.action_tx = Some(action_tx);
+    }
+}
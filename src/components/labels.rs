@@ -1,19 +1,160 @@
-use crate::{config::IMG_LABEL_PROMPT, enums::ImageBase64Search, utils::img_path_to_base64};
+use crate::{
+    config::{IMG_DESCRIPTION_PROMPT, IMG_LABEL_PROMPT},
+    enums::{
+        AnswerFormat, ImageStructured, LabelSource, LabelingSettings, OllamaModel, PromptPreset,
+        PromptPresets, RescanSummary, VisionUploadStats,
+    },
+    utils::{
+        image_to_base64, img_path_to_base64, is_format_refused, parse_label_list,
+        parse_structured_answer, split_ollama_url, validate_answer,
+    },
+};
+use image::DynamicImage;
 use ollama_rs::{
-    generation::{completion::request::GenerationRequest, options::GenerationOptions},
+    error::OllamaError,
+    generation::{
+        completion::request::GenerationRequest,
+        options::GenerationOptions,
+        parameters::{FormatType, JsonStructure},
+    },
     Ollama,
 };
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::{
+    sync::{mpsc::UnboundedSender, Semaphore},
+    time::Instant,
+};
 
 use super::Component;
 use crate::{app_state::AppState, enums::BroadcastMsg};
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_RETRIES: u32 = 10;
+
+/// Spaces the starts of labeling requests evenly, shared by every request task.
+struct RateLimiter {
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self, per_minute: u32) {
+        if per_minute == 0 {
+            return;
+        }
+        let interval = Duration::from_secs(60) / per_minute;
+        let start = {
+            let mut next = self.next.lock().await;
+            let start = (*next).max(Instant::now());
+            *next = start + interval;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
+
+/// What a request asks the vision model for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum VisionTask {
+    Labels,
+    // -- second pass over labeled images
+    Description,
+    // -- region of an image selected in the viewer, the labels are only shown
+    Crop,
+}
+
+impl VisionTask {
+    fn failed(self, file: String, reason: String) -> BroadcastMsg {
+        match self {
+            VisionTask::Labels => BroadcastMsg::VisionRequestFailed(file, reason),
+            VisionTask::Description => BroadcastMsg::DescriptionRequestFailed(file, reason),
+            VisionTask::Crop => BroadcastMsg::CropLabeled(file, Err(reason)),
+        }
+    }
+
+    // -- asked with the active prompt preset
+    fn is_labels(self) -> bool {
+        matches!(self, VisionTask::Labels | VisionTask::Crop)
+    }
+}
+
+/// Message for a model answer, `None` when the model ignored the answer schema.
+fn read_answer(
+    task: VisionTask,
+    structured: bool,
+    source: &LabelSource,
+    file: &str,
+    response: &str,
+) -> Option<Result<BroadcastMsg, String>> {
+    match task {
+        VisionTask::Labels | VisionTask::Crop => {
+            let answer = if structured {
+                parse_structured_answer(response)?
+            } else {
+                parse_label_list(response)
+            };
+            Some(
+                validate_answer(answer)
+                    .map(|answer| match task {
+                        VisionTask::Crop => {
+                            BroadcastMsg::CropLabeled(file.to_string(), Ok(answer.labels))
+                        }
+                        _ => BroadcastMsg::GetLabelsForImage(
+                            file.to_string(),
+                            answer,
+                            source.clone(),
+                        ),
+                    })
+                    .ok_or_else(|| "answer without labels".to_string()),
+            )
+        }
+        VisionTask::Description => {
+            let description = response.trim();
+            Some(if description.is_empty() {
+                Err("empty description".to_string())
+            } else {
+                Ok(BroadcastMsg::GetDescriptionForImage(
+                    file.to_string(),
+                    description.to_string(),
+                ))
+            })
+        }
+    }
+}
 
 pub struct Labeler {
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
     app_state: Option<Arc<Mutex<AppState>>>,
     files_to_label: Vec<String>,
+    // -- labeled files waiting for the description pass, after every label request
+    files_to_describe: Vec<String>,
+    // -- sent to a vision model and waiting for the answer
+    in_flight: HashSet<String>,
+    // -- crops of the image viewer, they count against the same parallelism
+    crops_in_flight: usize,
     is_labeling: bool,
+    // -- labeling pauses while the Ollama server is away
+    ollama_running: bool,
+    settings: LabelingSettings,
+    preset: PromptPreset,
+    // -- one for the whole session, `permits` is what it hands out in total
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+    rate_limiter: Arc<RateLimiter>,
+    // -- round robin over the labeling servers
+    next_server: usize,
+    // -- models (or their servers) that ignore the answer schema, asked for plain labels
+    plain_answer_models: Arc<Mutex<HashSet<String>>>,
 }
 
 impl Labeler {
@@ -22,42 +163,177 @@ impl Labeler {
             action_tx: None,
             app_state: None,
             files_to_label: vec![],
+            files_to_describe: vec![],
+            in_flight: HashSet::new(),
+            crops_in_flight: 0,
             is_labeling: false,
+            ollama_running: true,
+            settings: LabelingSettings::default(),
+            preset: PromptPresets::default().active(),
+            semaphore: Arc::new(Semaphore::new(1)),
+            permits: 1,
+            rate_limiter: Arc::new(RateLimiter::new()),
+            next_server: 0,
+            plain_answer_models: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
     fn start_labeling(&mut self) {
         println!("START LABELING ---- ");
-        let mut dir_files = vec![];
+        let mut index = None;
+        let mut formats = vec![];
         {
             if let Some(ref app_state) = self.app_state {
-                dir_files = app_state.lock().unwrap().dir_files.clone();
+                let a_state = app_state.lock().unwrap();
+                index = Some(a_state.index.clone());
+                formats = a_state.formats.clone();
+                self.settings = a_state.ollama_state.labeling.clone();
+                self.preset = a_state.ollama_state.prompts.active();
             }
         }
+        self.apply_settings();
 
-        // -- get all files that is not having labels
-        let mut all_files = vec![];
-        for dir in dir_files.iter() {
-            for file in dir.files_with_labels.iter() {
-                if file.labels.is_empty() {
-                    all_files.push(file.file.to_string());
+        // -- get all files that is not having labels or a description
+        if let Some(index) = index {
+            self.files_to_label = index.unlabeled_files(&formats);
+            self.files_to_describe = match self.settings.describe {
+                true => index.undescribed_files(&formats),
+                false => vec![],
+            };
+        }
+        // -- answers of a stopped run may still be on their way
+        self.files_to_label.retain(|f| !self.in_flight.contains(f));
+        self.files_to_describe
+            .retain(|f| !self.in_flight.contains(f));
+
+        self.next_vision_search();
+    }
+
+    fn apply_settings(&mut self) {
+        self.resize_semaphore();
+        if !self.settings.describe {
+            self.files_to_describe.clear();
+        }
+    }
+
+    // -- permits held by requests in flight are only taken away once they are returned
+    fn resize_semaphore(&mut self) {
+        let wanted = self.settings.parallelism.max(1);
+        if wanted > self.permits {
+            self.semaphore.add_permits(wanted - self.permits);
+            self.permits = wanted;
+        }
+        self.permits -= self.semaphore.forget_permits(self.permits - wanted);
+    }
+
+    // -- new and modified files from a rescan join the running labeling
+    fn queue_rescanned_files(&mut self, summary: RescanSummary) {
+        if !self.is_labeling {
+            return;
+        }
+        for queue in [&mut self.files_to_label, &mut self.files_to_describe] {
+            queue.retain(|f| !summary.removed.contains(f) && !summary.modified.contains(f));
+            for (old, new) in summary.moved.iter() {
+                if let Some(f) = queue.iter_mut().find(|f| *f == old) {
+                    *f = new.clone();
                 }
             }
         }
+        for file in summary.added.into_iter().chain(summary.modified) {
+            if !self.files_to_label.contains(&file) && !self.in_flight.contains(&file) {
+                self.files_to_label.push(file);
+            }
+        }
+    }
 
-        self.files_to_label = all_files;
+    /// Sends files to the vision model until the parallelism limit is reached,
+    /// labels go before descriptions.
+    fn next_vision_search(&mut self) {
+        if !self.is_labeling || !self.ollama_running {
+            return;
+        }
+        while self.ollama_running
+            && self.in_flight.len() + self.crops_in_flight < self.settings.parallelism.max(1)
+        {
+            let (img, task) = if let Some(img) = self.files_to_label.pop() {
+                (img, VisionTask::Labels)
+            } else if let Some(img) = self.files_to_describe.pop() {
+                (img, VisionTask::Description)
+            } else {
+                break;
+            };
+            if !self.send_to_vision(img.clone(), task) {
+                // -- nothing can be labeled without a vision model
+                self.requeue(&img, task);
+                if let Some(action_tx) = self.action_tx.clone() {
+                    let _ = action_tx.send(BroadcastMsg::StopLabeling);
+                }
+                return;
+            }
+        }
+        self.send_progress();
 
-        self.next_vision_search();
+        if self.files_to_label.is_empty()
+            && self.files_to_describe.is_empty()
+            && self.in_flight.is_empty()
+        {
+            self.finished_image_search();
+        }
     }
 
-    fn next_vision_search(&mut self) {
+    fn send_progress(&self) {
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(BroadcastMsg::LabelingProgress(
+                self.in_flight.len(),
+                self.files_to_label.len() + self.files_to_describe.len(),
+            ));
+        }
+    }
+
+    fn set_ollama_running(&mut self, running: bool) {
+        if running == self.ollama_running {
+            return;
+        }
+        self.ollama_running = running;
+        if self.is_labeling {
+            match running {
+                true => println!("OLLAMA IS BACK, LABELING RESUMED"),
+                false => println!("OLLAMA IS AWAY, LABELING PAUSED"),
+            }
+            self.next_vision_search();
+        }
+    }
+
+    // -- labeling goes on while any labeling server is up
+    fn servers_checked(&mut self) {
+        let mut running = false;
+        {
+            if let Some(ref app_state) = self.app_state {
+                running = app_state.lock().unwrap().ollama_state.any_server_up();
+            }
+        }
+        self.set_ollama_running(running);
+    }
+
+    // -- requests lost with their server are tried again on another one or once it is back
+    fn requeue(&mut self, file: &str, task: VisionTask) {
         if !self.is_labeling {
             return;
         }
-        if let Some(img) = self.files_to_label.pop() {
-            self.label_image(img);
+        match task {
+            VisionTask::Labels => self.files_to_label.push(file.to_string()),
+            VisionTask::Description => self.files_to_describe.push(file.to_string()),
+            VisionTask::Crop => {}
+        }
+    }
+
+    fn request_done(&mut self, file: &str) {
+        self.in_flight.remove(file);
+        self.resize_semaphore();
+        if self.is_labeling {
+            self.next_vision_search();
         } else {
-            self.finished_image_search();
+            self.send_progress();
         }
     }
 
@@ -69,53 +345,259 @@ impl Labeler {
         }
     }
 
-    fn get_vision_model(&mut self) -> Option<String> {
+    fn get_vision_model(&mut self) -> Option<OllamaModel> {
         if let Some(app_state) = self.app_state.clone() {
             let a_state = app_state.lock().unwrap();
-            let v_models = a_state.ollama_state.get_vision_models();
-            if !v_models.is_empty() {
-                let model_name = v_models[0].name.clone();
-                println!("SELECTED FIRST VISION MODEL: {}", model_name.clone());
-                return Some(model_name);
+            let model = a_state.ollama_state.labeling_model();
+            if model.is_none() {
+                println!(
+                    "LABELING MODEL {:?} IS NOT AVAILABLE",
+                    a_state.ollama_state.vision_model
+                );
             }
+            return model;
         }
         None
     }
 
-    fn label_image(&mut self, file: String) {
-        println!("> start labeling img: {}", file);
-        if let Some(img) = img_path_to_base64(file.clone()) {
-            if let Some(vision_model) = self.get_vision_model() {
-                self.msg_to_vision(file, vision_model, IMG_LABEL_PROMPT.to_string(), img);
-            } else {
-                println!("NO VISION MODEL FOUND");
+    // -- round robin over the servers that are up and have the vision model
+    fn next_server_url(&mut self) -> Option<String> {
+        let mut urls = vec![];
+        {
+            if let Some(ref app_state) = self.app_state {
+                urls = app_state.lock().unwrap().ollama_state.labeling_servers();
+            }
+        }
+        if urls.is_empty() {
+            return None;
+        }
+        let url = urls[self.next_server % urls.len()].clone();
+        self.next_server = self.next_server.wrapping_add(1);
+        Some(url)
+    }
+
+    fn send_to_vision(&mut self, file: String, task: VisionTask) -> bool {
+        println!("> start {:?} img: {}", task, file);
+        let Some(vision_model) = self.get_vision_model() else {
+            println!("NO VISION MODEL FOUND");
+            return false;
+        };
+        match self.next_server_url() {
+            Some(server_url) => {
+                self.in_flight.insert(file.clone());
+                self.msg_to_vision(file, None, server_url, vision_model, task);
+            }
+            None => {
+                // -- the servers with the model are away, the next health check resumes
+                println!("NO LABELING SERVER WITH {} IS UP", vision_model.name);
+                self.requeue(&file, task);
+                self.ollama_running = false;
+            }
+        }
+        true
+    }
+
+    // -- the crop of the image viewer, not a file of the labeling run
+    fn label_crop(&mut self, file: String, crop: Arc<DynamicImage>) {
+        if !self.is_labeling {
+            if let Some(ref app_state) = self.app_state {
+                let a_state = app_state.lock().unwrap();
+                self.settings = a_state.ollama_state.labeling.clone();
+                self.preset = a_state.ollama_state.prompts.active();
             }
+            self.apply_settings();
+        }
+        // -- every path ends with a `CropLabeled`
+        self.crops_in_flight += 1;
+        let failed = |reason: &str| BroadcastMsg::CropLabeled(file.clone(), Err(reason.into()));
+        let msg = match (self.get_vision_model(), self.next_server_url()) {
+            (None, _) => failed("No labeling model available"),
+            (Some(_), None) => failed("No labeling server with the model is up"),
+            (Some(model), Some(server_url)) => {
+                self.msg_to_vision(file, Some(crop), server_url, model, VisionTask::Crop);
+                return;
+            }
+        };
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(msg);
         }
     }
 
     fn msg_to_vision(
         &mut self,
         file: String,
-        model_name: String,
-        prompt: String,
-        img: ImageBase64Search,
+        crop: Option<Arc<DynamicImage>>,
+        server_url: String,
+        model: OllamaModel,
+        task: VisionTask,
     ) {
-        println!("> send img to vision: {}", file);
-        let (url, port) = self.get_ollama_url(self.app_state.clone());
+        let (url, port) = split_ollama_url(&server_url);
         let ollama = Ollama::new(url, port);
+        let mut settings = Default::default();
+        {
+            if let Some(ref app_state) = self.app_state {
+                settings = app_state
+                    .lock()
+                    .unwrap()
+                    .ollama_state
+                    .preprocess(&model.name);
+            }
+        }
+        let semaphore = self.semaphore.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let rate_limit = self.settings.rate_limit;
+        let timeout = Duration::from_secs(self.settings.timeout_secs.max(1));
+        let retries = self.settings.retries.min(MAX_RETRIES);
+        let plain_answer_models = self.plain_answer_models.clone();
+        let preset = self.preset.clone();
+        let model_name = model.name.clone();
+        let source = LabelSource {
+            preset: preset.version(),
+            model: model.name,
+            digest: model.digest,
+        };
+
         if let Some(action_tx) = self.action_tx.clone() {
             tokio::spawn(async move {
-                let res = ollama
-                    .generate(
-                        GenerationRequest::new(model_name, prompt)
-                            .add_image(img.clone().base64)
-                            .options(GenerationOptions::default().temperature(0.0)),
-                    )
-                    .await;
-                if let Ok(resp) = res {
-                    println!("{:?} desc vision search", &resp.response);
-                    let _ = action_tx
-                        .send(BroadcastMsg::GetLabelsForImage(file, resp.response.clone()));
+                let _permit = semaphore.acquire_owned().await;
+
+                // -- decoding and downscaling big images would block the runtime
+                let path = file.clone();
+                let prepared = tokio::task::spawn_blocking(move || match crop {
+                    Some(crop) => image_to_base64(path, &crop, &settings),
+                    None => img_path_to_base64(path, &settings),
+                })
+                .await;
+                let Ok(Some(img)) = prepared else {
+                    let _ =
+                        action_tx.send(task.failed(file, "image could not be read".to_string()));
+                    return;
+                };
+
+                println!(
+                    "> send img to vision: {} ({} -> {} bytes)",
+                    img.path, img.original_bytes, img.sent_bytes
+                );
+                let mut attempt = 0;
+                // -- the schema was refused, kept once a plain request goes through
+                let mut schema_refused = false;
+                let res = loop {
+                    let structured = task.is_labels()
+                        && preset.format == AnswerFormat::Structured
+                        && !schema_refused
+                        && !plain_answer_models.lock().unwrap().contains(&model_name);
+                    let prompt = match task {
+                        VisionTask::Description => IMG_DESCRIPTION_PROMPT.to_string(),
+                        _ if structured || preset.format == AnswerFormat::LabelList => {
+                            preset.prompt.clone()
+                        }
+                        _ => IMG_LABEL_PROMPT.to_string(),
+                    };
+                    let mut request = GenerationRequest::new(model_name.clone(), prompt)
+                        .add_image(img.base64.clone())
+                        .options(GenerationOptions::default().temperature(0.0));
+                    // -- the description pass keeps its own prompt and options
+                    if task.is_labels() {
+                        let mut options =
+                            GenerationOptions::default().temperature(preset.temperature);
+                        if preset.num_predict > 0 {
+                            options = options.num_predict(preset.num_predict);
+                        }
+                        request = request.options(options);
+                        if !preset.system.trim().is_empty() {
+                            request = request.system(preset.system.clone());
+                        }
+                    }
+                    if structured {
+                        request = request
+                            .format(FormatType::StructuredJson(JsonStructure::new::<
+                                ImageStructured,
+                            >()));
+                    }
+
+                    rate_limiter.wait(rate_limit).await;
+                    let started = Instant::now();
+                    let error = match tokio::time::timeout(timeout, ollama.generate(request)).await
+                    {
+                        Ok(Ok(resp)) => {
+                            println!("{:?} desc vision search", resp.response);
+                            if schema_refused {
+                                plain_answer_models
+                                    .lock()
+                                    .unwrap()
+                                    .insert(model_name.clone());
+                            }
+                            match read_answer(task, structured, &source, &img.path, &resp.response)
+                            {
+                                Some(Ok(msg)) => break Ok((msg, started)),
+                                Some(Err(e)) => e,
+                                None => {
+                                    // -- Ollama before 0.5 ignores the schema, ask again for plain labels
+                                    println!(
+                                        "{} - no structured answer, using plain labels",
+                                        model_name
+                                    );
+                                    plain_answer_models
+                                        .lock()
+                                        .unwrap()
+                                        .insert(model_name.clone());
+                                    continue;
+                                }
+                            }
+                        }
+                        // -- the server refused the schema, the request is sent again without it,
+                        // -- any other error status is retried like a failed request
+                        Ok(Err(OllamaError::Other(e))) if structured && is_format_refused(&e) => {
+                            println!("{} - {}, trying plain labels", model_name, e);
+                            schema_refused = true;
+                            continue;
+                        }
+                        // -- a crop is not queued, it fails like any other request
+                        Ok(Err(OllamaError::ReqwestError(e)))
+                            if e.is_connect() && task != VisionTask::Crop =>
+                        {
+                            println!("{} - {} is away, requeued {}", e, server_url, img.path);
+                            let _ = action_tx.send(BroadcastMsg::VisionServerAway(
+                                server_url,
+                                img.path,
+                                task == VisionTask::Description,
+                            ));
+                            return;
+                        }
+                        Ok(Err(e)) => e.to_string(),
+                        Err(_) => format!("timed out after {}s", timeout.as_secs()),
+                    };
+                    if attempt >= retries {
+                        break Err(format!("{} ({} attempts)", error, attempt + 1));
+                    }
+                    let backoff = (RETRY_BACKOFF * 2u32.pow(attempt)).min(MAX_RETRY_BACKOFF);
+                    println!(
+                        "{} - Error labeling {}, retrying in {}s",
+                        error,
+                        img.path,
+                        backoff.as_secs()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                };
+                match res {
+                    Ok((msg, started)) => {
+                        let _ = action_tx.send(BroadcastMsg::VisionUploaded(
+                            model_name,
+                            VisionUploadStats {
+                                images: 1,
+                                original_bytes: img.original_bytes,
+                                sent_bytes: img.sent_bytes,
+                                prepare_ms: img.prepare_ms,
+                                request_ms: started.elapsed().as_millis() as u64,
+                            },
+                        ));
+                        let _ = action_tx.send(msg);
+                    }
+                    Err(e) => {
+                        println!("{} - Labeling failed {}", e, img.path);
+                        let _ = action_tx.send(task.failed(img.path, e));
+                    }
                 }
             });
         }
@@ -136,11 +618,59 @@ impl Component for Labeler {
 
             BroadcastMsg::StopLabeling => {
                 self.is_labeling = false;
+                self.files_to_label.clear();
+                self.files_to_describe.clear();
+                self.send_progress();
             }
 
-            BroadcastMsg::GetLabelsForImage(_file, _labels) => {
+            BroadcastMsg::SetLabelingSettings(settings) => {
+                self.settings = settings;
+                self.apply_settings();
                 self.next_vision_search();
             }
+
+            BroadcastMsg::DirectoryRescanned(_path, summary) => {
+                self.queue_rescanned_files(summary);
+            }
+
+            BroadcastMsg::SetPromptPresets(prompts) => {
+                self.preset = prompts.active();
+            }
+
+            BroadcastMsg::GetLabelsForImage(file, _labels, _) => {
+                if self.is_labeling && self.settings.describe {
+                    self.files_to_describe.push(file.clone());
+                }
+                self.request_done(&file);
+            }
+
+            BroadcastMsg::VisionRequestFailed(file, _)
+            | BroadcastMsg::DescriptionRequestFailed(file, _) => {
+                self.request_done(&file);
+            }
+            BroadcastMsg::VisionServerAway(_, file, description) => {
+                let task = match description {
+                    true => VisionTask::Description,
+                    false => VisionTask::Labels,
+                };
+                self.requeue(&file, task);
+                self.servers_checked();
+                self.request_done(&file);
+            }
+            BroadcastMsg::GetDescriptionForImage(file, _) => {
+                self.request_done(&file);
+            }
+            BroadcastMsg::LabelCrop(file, crop) => {
+                self.label_crop(file, crop);
+            }
+            BroadcastMsg::CropLabeled(_, _) => {
+                self.crops_in_flight = self.crops_in_flight.saturating_sub(1);
+                self.resize_semaphore();
+                self.next_vision_search();
+            }
+            BroadcastMsg::ServerRunning(_, _) | BroadcastMsg::SetServerModels(_, _) => {
+                self.servers_checked();
+            }
             _ => {}
         }
     }
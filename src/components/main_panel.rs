@@ -1,22 +1,55 @@
 use super::Component;
 use crate::{
     app_state::AppState,
-    enums::{BroadcastMsg, DirectoryImage, DirectoryImages},
+    enums::{BroadcastMsg, DirectoryImage, DirectoryImages, ImageStructured},
+    image_signature::ImageSignature,
+    ollama_state::OllamaState,
+    query::Query,
+    thumbnail_pipeline::create_thumbnail,
+    utils::{cosine_similarity, spawn},
+};
+use egui::{
+    text::{LayoutJob, TextFormat},
+    CollapsingHeader, Color32, RichText, ScrollArea, Sense, Vec2,
 };
-use egui::{CollapsingHeader, Color32, ScrollArea, Sense, Vec2};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    ops::Range,
     path::PathBuf,
     sync::{Arc, Mutex},
 };
 use tokio::sync::mpsc::UnboundedSender;
 
+/// Reverse image search, the found images look like `file`.
+struct SimilarSearch {
+    file: String,
+    // -- only images of this directory
+    dir: Option<PathBuf>,
+    // -- `None` while it is computed or when the file can not be read
+    signature: Option<ImageSignature>,
+    unreadable: bool,
+}
+
 pub struct MainPanel {
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
     app_state: Option<Arc<Mutex<AppState>>>,
     dir_images: Vec<DirectoryImages>,
     found_images: Vec<DirectoryImage>,
     search_inputs: HashMap<String, String>,
+    // -- lowercased terms of the last search, highlighted in the tile hover
+    search_terms: Vec<String>,
+    // -- words of the last query and the images matching it with their share of
+    // -- the words, ranked again once the words are embedded
+    search_query: String,
+    keyword_hits: HashMap<String, f32>,
+    // -- images passing the filters of the query, which the semantic search may add
+    semantic_candidates: HashSet<String>,
+    similar: Option<SimilarSearch>,
+    min_similarity: f32,
+    thumbnail_progress: HashMap<PathBuf, (usize, usize)>,
+    // -- on-screen tiles still waiting for their thumbnail
+    visible_pending: Vec<String>,
+    prioritized: Vec<String>,
 }
 
 impl MainPanel {
@@ -27,102 +60,476 @@ impl MainPanel {
             dir_images: vec![],
             found_images: vec![],
             search_inputs: HashMap::new(),
+            search_terms: vec![],
+            search_query: String::new(),
+            keyword_hits: HashMap::new(),
+            semantic_candidates: HashSet::new(),
+            similar: None,
+            min_similarity: 0.75,
+            thumbnail_progress: HashMap::new(),
+            visible_pending: vec![],
+            prioritized: vec![],
         }
     }
 
-    fn save_thumbnails(&mut self, dir_images: DirectoryImages) {
-        if self
+    fn save_thumbnails(&mut self, mut dir_images: DirectoryImages) {
+        // -- rescanned directory replaces its previous images, keeping loaded thumbnails
+        if let Some(existing) = self
             .dir_images
-            .iter()
-            .all(|item| item.dir != dir_images.dir)
+            .iter_mut()
+            .find(|item| item.dir == dir_images.dir)
         {
-            self.dir_images.push(dir_images.clone());
-
-            // -- save to search hashmap
-            self.search_inputs
-                .insert(dir_images.dir.to_string_lossy().to_string(), "".to_string());
+            let textures: HashMap<&String, &egui::TextureHandle> = existing
+                .images
+                .iter()
+                .filter_map(|img| img.texture.as_ref().map(|t| (&img.file, t)))
+                .collect();
+            for img in dir_images.images.iter_mut() {
+                if img.texture.is_none() {
+                    img.texture = textures.get(&img.file).map(|t| (*t).clone());
+                }
+            }
+            *existing = dir_images;
+            return;
         }
+
+        // -- save to search hashmap
+        self.search_inputs
+            .insert(dir_images.dir.to_string_lossy().to_string(), "".to_string());
+        self.dir_images.push(dir_images);
     }
 
     fn remove_thumbnails(&mut self, path: PathBuf) {
+        self.thumbnail_progress.remove(&path);
         self.dir_images.retain(|p| p.dir != path);
     }
 
-    fn add_labels_to_file(&mut self, file: String, labels: String) {
-        let l_labels: Vec<String> = labels
-            .split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(String::from)
+    fn set_thumbnail(&mut self, dir: PathBuf, image: DirectoryImage) {
+        if let Some(d) = self.dir_images.iter_mut().find(|d| d.dir == dir) {
+            if let Some(img) = d.images.iter_mut().find(|i| i.file == image.file) {
+                img.texture = image.texture.clone();
+                img.error = image.error.clone();
+            }
+        }
+        if let Some(img) = self.found_images.iter_mut().find(|i| i.file == image.file) {
+            img.texture = image.texture;
+            img.error = image.error;
+        }
+    }
+
+    fn set_thumbnail_progress(&mut self, dir: PathBuf, done: usize, total: usize) {
+        if done >= total {
+            self.thumbnail_progress.remove(&dir);
+        } else {
+            self.thumbnail_progress.insert(dir, (done, total));
+        }
+    }
+
+    // -- asks the thumbnail workers to decode what is on screen first
+    fn prioritize_visible(&mut self) {
+        let visible = std::mem::take(&mut self.visible_pending);
+        if !visible.is_empty() && visible != self.prioritized {
+            if let Some(action_tx) = self.action_tx.clone() {
+                let _ = action_tx.send(BroadcastMsg::PrioritizeThumbnails(visible.clone()));
+            }
+        }
+        self.prioritized = visible;
+    }
+
+    fn render_image_tile(&mut self, image: &DirectoryImage, ui: &mut egui::Ui) {
+        let resp = match image.texture.as_ref() {
+            Some(texture) => {
+                let s_text = egui::load::SizedTexture::new(texture.id(), egui::vec2(160.0, 160.0));
+                ui.add(
+                    egui::Image::from_texture(s_text)
+                        .fit_to_exact_size(Vec2::new(120.0, 120.0))
+                        .bg_fill(Color32::from_rgb(33, 33, 33))
+                        .sense(Sense::click())
+                        .corner_radius(6.0),
+                )
+            }
+            None => {
+                let (rect, resp) = ui.allocate_exact_size(Vec2::new(120.0, 120.0), Sense::click());
+                if ui.is_rect_visible(rect) {
+                    ui.painter()
+                        .rect_filled(rect, 6.0, Color32::from_rgb(33, 33, 33));
+                    match image.error.as_ref() {
+                        Some(error) => {
+                            ui.painter().text(
+                                rect.center(),
+                                egui::Align2::CENTER_CENTER,
+                                format!("⚠\n{}", error.title()),
+                                egui::FontId::proportional(11.0),
+                                Color32::from_rgb(255, 120, 120),
+                            );
+                        }
+                        None => {
+                            egui::Spinner::new().paint_at(ui, rect.shrink(44.0));
+                            self.visible_pending.push(image.file.clone());
+                        }
+                    }
+                }
+                match image.error.as_ref() {
+                    Some(error) => {
+                        resp.on_hover_text(format!("{}\n{}", image.file, error.describe()))
+                    }
+                    None => resp,
+                }
+            }
+        };
+
+        let resp = resp.on_hover_ui(|ui| {
+            ui.set_max_width(320.0);
+            ui.label(highlighted(
+                &image.labels.join(", "),
+                &self.search_terms,
+                ui.style(),
+            ));
+            if !image.description.is_empty() {
+                ui.separator();
+                ui.label(highlighted(
+                    &image.description,
+                    &self.search_terms,
+                    ui.style(),
+                ));
+            }
+        });
+        if resp.clicked() {
+            println!("open files {}", image.file);
+            let _ = open::that(image.file.clone());
+        }
+
+        let mut similar = None;
+        resp.context_menu(|ui| {
+            if ui.button("Find similar images").clicked() {
+                similar = Some(None);
+                ui.close_menu();
+            }
+            if ui.button("Find similar in this folder").clicked() {
+                similar = Some(self.image_dir(&image.file));
+                ui.close_menu();
+            }
+            if ui.button("Crop and search").clicked() {
+                if let Some(action_tx) = self.action_tx.clone() {
+                    let _ = action_tx.send(BroadcastMsg::OpenImageViewer(image.file.clone()));
+                }
+                ui.close_menu();
+            }
+        });
+        if let Some(dir) = similar {
+            self.find_similar(image.file.clone(), dir);
+        }
+    }
+
+    fn image_dir(&self, file: &str) -> Option<PathBuf> {
+        self.dir_images
+            .iter()
+            .find(|d| d.images.iter().any(|i| i.file == file))
+            .map(|d| d.dir.clone())
+    }
+
+    fn find_similar(&mut self, file: String, dir: Option<PathBuf>) {
+        let mut signature = None;
+        {
+            if let Some(ref app_state) = self.app_state {
+                signature = app_state.lock().unwrap().index.signature(&file);
+            }
+        }
+        // -- not thumbnailed yet or a file from outside the index
+        if signature.is_none() {
+            spawn(Self::compute_signature(
+                file.clone(),
+                self.action_tx.clone(),
+            ));
+        }
+
+        self.search_terms.clear();
+        self.search_query.clear();
+        self.keyword_hits.clear();
+        self.semantic_candidates.clear();
+        self.similar = Some(SimilarSearch {
+            file,
+            dir,
+            signature,
+            unreadable: false,
+        });
+        self.rank_similar();
+    }
+
+    async fn compute_signature(file: String, action_tx: Option<UnboundedSender<BroadcastMsg>>) {
+        let path = file.clone();
+        let signature = tokio::task::spawn_blocking(move || {
+            let thumb = create_thumbnail(&None, &path).ok()?;
+            Some(ImageSignature::from_image(&thumb))
+        })
+        .await
+        .ok()
+        .flatten();
+        if let Some(action_tx) = action_tx {
+            let _ = action_tx.send(BroadcastMsg::SignatureReady(file, signature));
+        }
+    }
+
+    fn set_similar_signature(&mut self, file: String, signature: Option<ImageSignature>) {
+        let Some(similar) = self.similar.as_mut().filter(|s| s.file == file) else {
+            return;
+        };
+        similar.unreadable = signature.is_none();
+        similar.signature = signature;
+        self.rank_similar();
+    }
+
+    fn rank_similar(&mut self) {
+        let Some(similar) = self.similar.as_ref() else {
+            return;
+        };
+        let Some(signature) = similar.signature.as_ref() else {
+            self.found_images.clear();
+            return;
+        };
+        let mut signatures = vec![];
+        {
+            if let Some(ref app_state) = self.app_state {
+                let dir = similar
+                    .dir
+                    .as_ref()
+                    .map(|d| d.to_string_lossy().to_string());
+                signatures = app_state.lock().unwrap().index.signatures(dir.as_deref());
+            }
+        }
+        let scores = signatures
+            .into_iter()
+            .filter(|(file, _)| *file != similar.file)
+            .map(|(file, other)| (file, signature.similarity(&other)))
+            .filter(|(_, score)| *score >= self.min_similarity)
             .collect();
+        self.show_ranked(scores);
+    }
 
-        for dir in self.dir_images.iter_mut() {
-            if let Some(f_file) = dir.images.iter_mut().find(|f| f.file == file) {
-                f_file.labels = l_labels.clone();
+    fn add_labels_to_file(&mut self, file: String, answer: ImageStructured) {
+        let images = self.dir_images.iter_mut().flat_map(|d| d.images.iter_mut());
+        for f_file in images
+            .chain(self.found_images.iter_mut())
+            .filter(|f| f.file == file)
+        {
+            f_file.labels = answer.labels.clone();
+            if f_file.description.is_empty() {
+                f_file.description = answer.caption.clone();
             }
         }
     }
 
+    fn add_description_to_file(&mut self, file: String, description: String) {
+        let images = self.dir_images.iter_mut().flat_map(|d| d.images.iter_mut());
+        for f_file in images
+            .chain(self.found_images.iter_mut())
+            .filter(|f| f.file == file)
+        {
+            f_file.description = description.clone();
+        }
+    }
+
     fn search_by_labels(&mut self, labels: String) {
         println!("SERACH BY LABELS: {:?}", labels);
 
-        let l_labels: Vec<String> = labels
-            .split(',')
-            .map(|s| s.trim())
-            .map(|s| s.to_lowercase())
-            .filter(|s| !s.is_empty())
-            .map(String::from)
+        self.similar = None;
+        let query = match Query::parse(&labels) {
+            Ok(query) => query,
+            Err(e) => {
+                println!("{} - Invalid search query", e);
+                self.search_terms.clear();
+                self.search_query.clear();
+                self.keyword_hits.clear();
+                self.semantic_candidates.clear();
+                self.found_images.clear();
+                return;
+            }
+        };
+
+        let mut docs = Arc::default();
+        let mut url = String::new();
+        let mut model = None;
+        {
+            if let Some(ref app_state) = self.app_state {
+                let a_state = app_state.lock().unwrap();
+                docs = a_state.index.search_docs();
+                url = a_state.ollama_state.url.clone();
+                model = a_state.ollama_state.search.embedding_model.clone();
+            }
+        }
+        // -- the plain words are embedded, filters and negations still apply
+        let text = query.text_terms().join(" ");
+        self.keyword_hits = docs
+            .iter()
+            .filter(|d| query.matches(d))
+            .map(|d| (d.path.clone(), query.text_score(d)))
             .collect();
+        self.semantic_candidates = match model.is_some() && !text.is_empty() {
+            true => docs
+                .iter()
+                .filter(|d| query.matches_filters(d))
+                .map(|d| d.path.clone())
+                .collect(),
+            false => HashSet::new(),
+        };
+        self.search_terms = query.highlight_terms();
+        self.search_query = text.clone();
+        self.rank_found_images(None);
 
-        let mut imgs = vec![];
+        // -- keyword results show up at once, the embedded query ranks them again
+        if let Some(model) = model {
+            if !text.is_empty() {
+                spawn(Self::embed_query(url, model, text, self.action_tx.clone()));
+            }
+        }
+    }
 
-        for dir in self.dir_images.iter() {
-            for img in dir.images.iter() {
-                let labels = img.clone().labels;
+    async fn embed_query(
+        url: String,
+        model: String,
+        query: String,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    ) {
+        let vector = match OllamaState::embed(url, model, vec![query.clone()]).await {
+            Ok(mut vectors) => vectors.pop(),
+            Err(e) => {
+                println!("{} - Error embedding search query", e);
+                None
+            }
+        };
+        if let Some(action_tx) = action_tx {
+            let _ = action_tx.send(BroadcastMsg::QueryEmbedded(query, vector));
+        }
+    }
+
+    /// Found images by score, the share of matched words mixed with the
+    /// similarity to the query when it is embedded.
+    fn rank_found_images(&mut self, query: Option<&[f32]>) {
+        let mut scores = self.keyword_hits.clone();
 
-                let common: Vec<_> = labels
-                    .iter()
-                    .map(|s| s.to_lowercase())
-                    .filter(|f| l_labels.iter().any(|s| f.contains(s)))
+        if let Some(query) = query {
+            let mut index = None;
+            let mut settings = None;
+            {
+                if let Some(ref app_state) = self.app_state {
+                    let a_state = app_state.lock().unwrap();
+                    index = Some(a_state.index.clone());
+                    settings = Some(a_state.ollama_state.search.clone());
+                }
+            }
+            if let (Some(index), Some(settings)) = (index, settings) {
+                let model = settings.embedding_model.clone().unwrap_or_default();
+                let similarities: HashMap<String, f32> = index
+                    .embeddings(&model)
+                    .into_iter()
+                    .filter(|(file, _)| self.semantic_candidates.contains(file))
+                    .map(|(file, vector)| (file, cosine_similarity(query, &vector)))
+                    .collect();
+                let files: HashSet<String> =
+                    scores.keys().chain(similarities.keys()).cloned().collect();
+                let weight = settings.keyword_weight.clamp(0.0, 1.0);
+                scores = files
+                    .into_iter()
+                    .filter_map(|file| {
+                        let keyword = scores.get(&file).copied();
+                        let similarity = similarities.get(&file).copied().unwrap_or(0.0);
+                        if keyword.is_none() && similarity < settings.threshold {
+                            return None;
+                        }
+                        let keyword = keyword.unwrap_or(0.0);
+                        Some((file, weight * keyword + (1.0 - weight) * similarity))
+                    })
                     .collect();
+            }
+        }
+
+        self.show_ranked(scores);
+    }
 
-                if !common.is_empty() {
-                    imgs.push(img.clone());
+    // -- found images by descending score, in directory order when equal
+    fn show_ranked(&mut self, scores: HashMap<String, f32>) {
+        let mut imgs = vec![];
+        for dir in self.dir_images.iter() {
+            for img in dir.images.iter() {
+                if let Some(score) = scores.get(&img.file) {
+                    imgs.push((*score, img.clone()));
                 }
             }
         }
+        imgs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        self.found_images = imgs.into_iter().map(|(_, img)| img).collect();
+    }
+
+    fn render_similar_controls(&mut self, ui: &mut egui::Ui) {
+        let Some(similar) = self.similar.as_ref() else {
+            return;
+        };
+        if similar.unreadable {
+            ui.small(RichText::new("Could not read the image").color(Color32::from_rgb(255, 0, 0)));
+        } else if similar.signature.is_none() {
+            ui.spinner();
+        }
+
+        let mut dir = similar.dir.clone();
+        let mut changed = false;
+        let mut clear = false;
+        ui.horizontal(|ui| {
+            let resp = ui
+                .add(egui::Slider::new(&mut self.min_similarity, 0.5..=1.0).text("min similarity"));
+            changed |= resp.drag_stopped() || resp.lost_focus();
+
+            let selected = match dir.as_ref() {
+                Some(d) => d.to_string_lossy().to_string(),
+                None => "All folders".to_string(),
+            };
+            egui::ComboBox::from_id_salt("similar_dir")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    changed |= ui.selectable_value(&mut dir, None, "All folders").changed();
+                    for d in self.dir_images.iter() {
+                        changed |= ui
+                            .selectable_value(
+                                &mut dir,
+                                Some(d.dir.clone()),
+                                d.dir.to_string_lossy(),
+                            )
+                            .changed();
+                    }
+                });
+            clear = ui.button("clear").clicked();
+        });
 
-        self.found_images = imgs;
+        if clear {
+            self.similar = None;
+            self.found_images.clear();
+        } else if changed {
+            if let Some(similar) = self.similar.as_mut() {
+                similar.dir = dir;
+            }
+            self.rank_similar();
+        }
     }
 
     fn render_found_images(&mut self, ui: &mut egui::Ui) {
-        let title = format!("Found Images: ({})", self.found_images.len());
+        let title = match self.similar.as_ref() {
+            Some(similar) => {
+                let name = PathBuf::from(&similar.file)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                format!("Similar to {}: ({})", name, self.found_images.len())
+            }
+            None => format!("Found Images: ({})", self.found_images.len()),
+        };
         CollapsingHeader::new(title)
+            .id_salt("found_images")
             .default_open(true)
             .show(ui, |ui| {
+                self.render_similar_controls(ui);
                 ui.horizontal_wrapped(|ui| {
                     for image in self.found_images.clone() {
-                        let s_text = egui::load::SizedTexture::new(
-                            image.texture.id(),
-                            egui::vec2(160.0, 160.0),
-                        );
-
-                        let resp = ui
-                            .add(
-                                egui::Image::from_texture(s_text)
-                                    .fit_to_exact_size(Vec2::new(120.0, 120.0))
-                                    .bg_fill(Color32::from_rgb(33, 33, 33))
-                                    // .max_width(440.0)
-                                    .sense(Sense::click())
-                                    // .sense(Sense::hover())
-                                    .rounding(6.0),
-                            )
-                            .on_hover_text(image.labels.join(","));
-                        // resp.on_hover_text(image.labels.join(","));
-                        if resp.clicked() {
-                            println!("open files {}", image.file);
-                            let _ = open::that(image.file);
-                        }
+                        self.render_image_tile(&image, ui);
                     }
                 });
             });
@@ -131,40 +538,34 @@ impl MainPanel {
     }
 
     fn render_dir_images(&mut self, dir: DirectoryImages, ui: &mut egui::Ui) {
-        let path_title = format!("{} ({})", dir.dir.to_string_lossy(), dir.images.len());
-        CollapsingHeader::new(path_title).show(ui, |ui| {
-            let dir_string = dir.dir.to_string_lossy().to_string();
-            let resp = ui.add(
-                egui::TextEdit::singleline(self.search_inputs.get_mut(&dir_string).unwrap())
-                    .hint_text("Search here.."),
-            );
-            if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                if let Some(action_tx) = self.action_tx.clone() {
-                    // let _ = action_tx.send(BroadcastMsg::SearchByLabels(self.input_text.clone()));
+        let progress = self.thumbnail_progress.get(&dir.dir).copied();
+        let mut path_title = format!("{} ({})", dir.dir.to_string_lossy(), dir.images.len());
+        if let Some((done, total)) = progress {
+            path_title = format!("{} - thumbnails {}/{}", path_title, done, total);
+        }
+        CollapsingHeader::new(path_title)
+            .id_salt(&dir.dir)
+            .show(ui, |ui| {
+                if let Some((done, total)) = progress {
+                    ui.add(egui::ProgressBar::new(done as f32 / total as f32).desired_height(4.0));
                 }
-            }
-            ui.horizontal_wrapped(|ui| {
-                for image in dir.images {
-                    let s_text =
-                        egui::load::SizedTexture::new(image.texture.id(), egui::vec2(160.0, 160.0));
-
-                    let resp = ui
-                        .add(
-                            egui::Image::from_texture(s_text)
-                                .fit_to_exact_size(Vec2::new(120.0, 120.0))
-                                .bg_fill(Color32::from_rgb(33, 33, 33))
-                                .sense(Sense::click())
-                                // .sense(Sense::hover())
-                                .rounding(6.0),
-                        )
-                        .on_hover_text(image.labels.join(","));
-                    if resp.clicked() {
-                        println!("open files {}", image.file);
-                        let _ = open::that(image.file);
+
+                let dir_string = dir.dir.to_string_lossy().to_string();
+                let resp = ui.add(
+                    egui::TextEdit::singleline(self.search_inputs.get_mut(&dir_string).unwrap())
+                        .hint_text("Search here.."),
+                );
+                if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    if let Some(_action_tx) = self.action_tx.clone() {
+                        // let _ = action_tx.send(BroadcastMsg::SearchByLabels(self.input_text.clone()));
                     }
                 }
+                ui.horizontal_wrapped(|ui| {
+                    for image in dir.images.iter() {
+                        self.render_image_tile(image, ui);
+                    }
+                });
             });
-        });
     }
 }
 
@@ -185,11 +586,42 @@ impl Component for MainPanel {
             BroadcastMsg::DirectoryImages(dir_file) => {
                 self.save_thumbnails(dir_file);
             }
+            BroadcastMsg::ThumbnailReady(dir, image) => {
+                self.set_thumbnail(dir, image);
+            }
+            BroadcastMsg::ThumbnailProgress(dir, done, total) => {
+                self.set_thumbnail_progress(dir, done, total);
+            }
             BroadcastMsg::SearchByLabels(labels) => {
                 self.search_by_labels(labels);
             }
-            BroadcastMsg::GetLabelsForImage(file, labels) => {
-                self.add_labels_to_file(file, labels);
+            BroadcastMsg::QueryEmbedded(query, Some(vector)) if query == self.search_query => {
+                self.rank_found_images(Some(&vector));
+            }
+            BroadcastMsg::FindSimilar(file, dir) => {
+                self.find_similar(file, dir);
+            }
+            BroadcastMsg::FindSimilarSignature(name, signature) => {
+                self.search_terms.clear();
+                self.search_query.clear();
+                self.keyword_hits.clear();
+                self.semantic_candidates.clear();
+                self.similar = Some(SimilarSearch {
+                    file: name,
+                    dir: None,
+                    signature: Some(signature),
+                    unreadable: false,
+                });
+                self.rank_similar();
+            }
+            BroadcastMsg::SignatureReady(file, signature) => {
+                self.set_similar_signature(file, signature);
+            }
+            BroadcastMsg::GetLabelsForImage(file, answer, _) => {
+                self.add_labels_to_file(file, answer);
+            }
+            BroadcastMsg::GetDescriptionForImage(file, description) => {
+                self.add_description_to_file(file, description);
             }
             _ => {}
         }
@@ -220,5 +652,153 @@ impl Component for MainPanel {
                 });
             });
         });
+
+        // -- an image dropped on the window is searched for
+        let dropped = ctx.input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .filter_map(|f| f.path.clone())
+                .find(|p| p.is_file())
+        });
+        if let Some(path) = dropped {
+            self.find_similar(path.to_string_lossy().to_string(), None);
+        }
+
+        self.prioritize_visible();
+    }
+}
+
+// -- byte ranges of `text` matching any of the lowercased terms, merged and sorted
+fn match_ranges(text: &str, terms: &[String]) -> Vec<Range<usize>> {
+    // -- lowercasing may change byte lengths, so every lowercased byte keeps its origin
+    let mut lower = String::new();
+    let mut origin = vec![];
+    for (i, c) in text.char_indices() {
+        let start = lower.len();
+        lower.extend(c.to_lowercase());
+        origin.resize(origin.len() + lower.len() - start, i);
+    }
+    origin.push(text.len());
+
+    let mut ranges: Vec<Range<usize>> = terms
+        .iter()
+        .filter(|t| !t.is_empty())
+        .flat_map(|t| lower.match_indices(t.as_str()))
+        .map(|(start, m)| origin[start]..origin[start + m.len()])
+        .filter(|r| !r.is_empty())
+        .collect();
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<Range<usize>> = vec![];
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+fn highlighted(text: &str, terms: &[String], style: &egui::Style) -> LayoutJob {
+    let normal = TextFormat {
+        font_id: egui::TextStyle::Body.resolve(style),
+        color: style.visuals.text_color(),
+        ..Default::default()
+    };
+    let matched = TextFormat {
+        color: Color32::BLACK,
+        background: Color32::from_rgb(0, 255, 255),
+        ..normal.clone()
+    };
+
+    let mut job = LayoutJob::default();
+    let mut pos = 0;
+    for r in match_ranges(text, terms) {
+        job.append(&text[pos..r.start], 0.0, normal.clone());
+        job.append(&text[r.clone()], 0.0, matched.clone());
+        pos = r.end;
+    }
+    job.append(&text[pos..], 0.0, normal);
+    job
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|t| t.to_string()).collect()
+    }
+
+    // -- the highlighted parts of the text
+    fn matches(text: &str, t: &[&str]) -> Vec<String> {
+        match_ranges(text, &terms(t))
+            .into_iter()
+            .map(|r| text[r].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(matches("Dog on a DOG bed", &["dog"]), ["Dog", "DOG"]);
+    }
+
+    #[test]
+    fn overlapping_terms_are_merged() {
+        assert_eq!(
+            matches("sunset beach", &["sun", "sunset", "set be"]),
+            ["sunset be"]
+        );
+        assert_eq!(matches("redcar", &["red", "car"]), ["redcar"]);
+    }
+
+    #[test]
+    fn sorted_by_position() {
+        assert_eq!(matches("cat and dog", &["dog", "cat"]), ["cat", "dog"]);
+    }
+
+    #[test]
+    fn no_terms() {
+        assert!(match_ranges("dog", &[]).is_empty());
+        assert!(match_ranges("dog", &terms(&[""])).is_empty());
+        assert!(match_ranges("dog", &terms(&["cat"])).is_empty());
+    }
+
+    #[test]
+    fn lowercasing_changes_byte_lengths() {
+        // -- "İ" lowercases to two chars, "ß" stays
+        assert_eq!(
+            matches("İstanbul Straße", &["stanbul", "straße"]),
+            ["stanbul", "Straße"]
+        );
+        assert_eq!(matches("ÄPFEL", &["äpfel"]), ["ÄPFEL"]);
+    }
+
+    #[test]
+    fn highlighted_keeps_the_text() {
+        let style = egui::Style::default();
+        let job = highlighted("Dog on the beach", &terms(&["dog", "beach"]), &style);
+        assert_eq!(job.text, "Dog on the beach");
+        let parts: Vec<(&str, bool)> = job
+            .sections
+            .iter()
+            .map(|s| {
+                (
+                    &job.text[s.byte_range.clone()],
+                    s.format.background != Color32::TRANSPARENT,
+                )
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("", false),
+                ("Dog", true),
+                (" on the ", false),
+                ("beach", true),
+                ("", false)
+            ]
+        );
     }
 }
@@ -1,6 +1,9 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, time::Duration};
 
-use egui::{popup_below_widget, CollapsingHeader, Id, PopupCloseBehavior, TextEdit, Vec2};
+use egui::{
+    popup_below_widget, Button, CollapsingHeader, Color32, Id, PopupCloseBehavior, ProgressBar,
+    RichText, TextEdit, Vec2,
+};
 use egui_flex::{Flex, FlexAlignContent, FlexItem};
 use egui_form::{
     garde::{field_path, GardeReport},
@@ -10,7 +13,8 @@ use garde::Validate;
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
-    enums::{BroadcastMsg, OllamaModel},
+    config::RECOMMENDED_MODELS,
+    enums::{BroadcastMsg, LabelingSettings, ModelPullProgress, OllamaModel, SearchSettings},
     utils::{bytes_convert, sleep, spawn},
 };
 
@@ -25,6 +29,17 @@ struct OllamaURL {
 pub struct OllamaSettings {
     url: OllamaURL,
     models: Vec<OllamaModel>,
+    labeling: LabelingSettings,
+    vision_model: Option<String>,
+    search: SearchSettings,
+    // -- labeled images still waiting for an embedding
+    to_embed: usize,
+    // -- one URL per line
+    extra_urls: String,
+    // -- model management
+    pull_name: String,
+    pulls: BTreeMap<String, ModelPullProgress>,
+    confirm_delete: Option<String>,
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
 }
 
@@ -33,10 +48,274 @@ impl OllamaSettings {
         Self {
             url: OllamaURL { url: String::new() },
             models: vec![],
+            labeling: LabelingSettings::default(),
+            vision_model: None,
+            search: SearchSettings::default(),
+            to_embed: 0,
+            extra_urls: String::new(),
+            pull_name: String::new(),
+            pulls: BTreeMap::new(),
+            confirm_delete: None,
             action_tx: None,
         }
     }
 
+    fn vision_model_ui(&mut self, ui: &mut egui::Ui) {
+        let vision_models: Vec<String> = self
+            .models
+            .iter()
+            .filter(|m| m.is_vision())
+            .map(|m| m.name.clone())
+            .collect();
+        ui.horizontal(|ui| {
+            ui.label("Labeling model:");
+            let selected = self.vision_model.clone().unwrap_or("none".to_string());
+            egui::ComboBox::from_id_salt("labeling_model")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    for name in vision_models.iter() {
+                        let checked = self.vision_model.as_ref() == Some(name);
+                        if ui.selectable_label(checked, name).clicked() {
+                            self.vision_model = Some(name.clone());
+                            if let Some(tx) = self.action_tx.clone() {
+                                let _ = tx.send(BroadcastMsg::SetVisionModel(name.clone()));
+                            }
+                        }
+                    }
+                });
+        });
+
+        if self.models.is_empty() {
+            return;
+        }
+        if vision_models.is_empty() {
+            ui.small(
+                RichText::new("No vision model on the server").color(Color32::from_rgb(255, 0, 0)),
+            );
+        } else if let Some(name) = &self.vision_model {
+            if !self.models.iter().any(|m| m.name == *name) {
+                ui.small(
+                    RichText::new(format!("{} is not on the server anymore", name))
+                        .color(Color32::from_rgb(255, 0, 0)),
+                );
+            }
+        }
+    }
+
+    // -- a name without tag is the `latest` one
+    fn is_installed(&self, name: &str) -> bool {
+        let name = match name.contains(':') {
+            true => name.to_string(),
+            false => format!("{}:latest", name),
+        };
+        self.models.iter().any(|m| m.name == name)
+    }
+
+    fn pull(&mut self, name: String) {
+        if self.pulls.get(&name).is_some_and(|p| !p.done) {
+            return;
+        }
+        let progress = ModelPullProgress {
+            status: "starting".to_string(),
+            ..Default::default()
+        };
+        self.pulls.insert(name.clone(), progress);
+        if let Some(tx) = self.action_tx.clone() {
+            let _ = tx.send(BroadcastMsg::PullOllamaModel(name));
+        }
+    }
+
+    fn get_models_ui(&mut self, ui: &mut egui::Ui) {
+        let mut pull = None;
+        ui.horizontal(|ui| {
+            ui.add(
+                TextEdit::singleline(&mut self.pull_name)
+                    .hint_text("model:tag")
+                    .desired_width(200.0),
+            );
+            let name = self.pull_name.trim().to_string();
+            if ui
+                .add_enabled(!name.is_empty(), Button::new("pull"))
+                .clicked()
+            {
+                pull = Some(name);
+            }
+        });
+
+        ui.small("Recommended:");
+        egui::Grid::new("recommended_models")
+            .num_columns(3)
+            .show(ui, |ui| {
+                for (name, kind) in RECOMMENDED_MODELS {
+                    ui.small(*name);
+                    ui.small(*kind);
+                    if self.is_installed(name) {
+                        ui.small("installed");
+                    } else if ui.small_button("pull").clicked() {
+                        pull = Some(name.to_string());
+                    }
+                    ui.end_row();
+                }
+            });
+        if let Some(name) = pull {
+            self.pull(name);
+        }
+
+        for (name, progress) in self.pulls.iter() {
+            ui.small(name);
+            if let Some(e) = &progress.error {
+                ui.small(RichText::new(e).color(Color32::from_rgb(255, 0, 0)));
+            } else if progress.done {
+                ui.small("done");
+            } else if progress.total > 0 {
+                let text = format!(
+                    "{} {} / {}",
+                    progress.status,
+                    bytes_convert(progress.completed as f64),
+                    bytes_convert(progress.total as f64)
+                );
+                let fraction = progress.completed as f32 / progress.total as f32;
+                ui.add(ProgressBar::new(fraction).text(text));
+            } else {
+                ui.small(progress.status.clone());
+            }
+        }
+    }
+
+    fn search_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+        egui::Grid::new("search_grid")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Embedding model:");
+                let selected = self
+                    .search
+                    .embedding_model
+                    .clone()
+                    .unwrap_or("none".to_string());
+                egui::ComboBox::from_id_salt("embedding_model")
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        changed |= ui
+                            .selectable_value(&mut self.search.embedding_model, None, "none")
+                            .changed();
+                        for model in self.models.iter().filter(|m| m.is_embedding()) {
+                            changed |= ui
+                                .selectable_value(
+                                    &mut self.search.embedding_model,
+                                    Some(model.name.clone()),
+                                    &model.name,
+                                )
+                                .changed();
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Threshold:");
+                let resp = ui
+                    .add(egui::Slider::new(&mut self.search.threshold, 0.0..=1.0))
+                    .on_hover_text("Lowest similarity of an image without matching keywords");
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+
+                ui.label("Keyword weight:");
+                let resp = ui
+                    .add(egui::Slider::new(
+                        &mut self.search.keyword_weight,
+                        0.0..=1.0,
+                    ))
+                    .on_hover_text(
+                        "Share of matching keywords in the ranking, the rest is similarity",
+                    );
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+            });
+
+        if self.search.embedding_model.is_none() {
+            ui.small("Keyword search only");
+        } else if self.to_embed > 0 {
+            ui.small(format!("{} images to embed", self.to_embed));
+        }
+
+        if changed {
+            if let Some(tx) = self.action_tx.clone() {
+                let _ = tx.send(BroadcastMsg::SetSearchSettings(self.search.clone()));
+            }
+        }
+    }
+
+    fn labeling_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+        egui::Grid::new("labeling_grid")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Parallel requests:");
+                let resp =
+                    ui.add(egui::DragValue::new(&mut self.labeling.parallelism).range(1..=32));
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+
+                ui.label("Rate limit:");
+                let resp = ui
+                    .add(
+                        egui::DragValue::new(&mut self.labeling.rate_limit)
+                            .range(0..=6000)
+                            .suffix(" / min"),
+                    )
+                    .on_hover_text("0 is unlimited");
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+
+                ui.label("Timeout:");
+                let resp = ui
+                    .add(
+                        egui::DragValue::new(&mut self.labeling.timeout_secs)
+                            .range(10..=3600)
+                            .suffix(" s"),
+                    )
+                    .on_hover_text("Per request, a model still loading counts too");
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+
+                ui.label("Retries:");
+                let resp = ui
+                    .add(egui::DragValue::new(&mut self.labeling.retries).range(0..=10))
+                    .on_hover_text("Failed requests are retried with a growing pause");
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+
+                ui.label("Descriptions:");
+                changed |= ui
+                    .checkbox(&mut self.labeling.describe, "second pass")
+                    .on_hover_text("Ask for a paragraph describing every labeled image")
+                    .changed();
+                ui.end_row();
+            });
+
+        ui.label("More Ollama URLs:")
+            .on_hover_text("Labeling is spread over these and the main URL, every server needs the same vision model");
+        let resp = ui.add(
+            TextEdit::multiline(&mut self.extra_urls)
+                .desired_rows(2)
+                .hint_text("http://gpu-box:11434"),
+        );
+        if resp.lost_focus() {
+            self.labeling.extra_urls = self
+                .extra_urls
+                .lines()
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty())
+                .collect();
+            changed = true;
+        }
+
+        if changed {
+            if let Some(tx) = self.action_tx.clone() {
+                let _ = tx.send(BroadcastMsg::SetLabelingSettings(self.labeling.clone()));
+            }
+        }
+    }
+
     async fn period_ollama_status(action_tx: Option<UnboundedSender<BroadcastMsg>>) {
         loop {
             sleep(Duration::from_secs(1)).await;
@@ -65,6 +344,22 @@ impl Component for OllamaSettings {
             BroadcastMsg::OllamaModels(models) => {
                 self.models = models;
             }
+            BroadcastMsg::VisionModel(model) => {
+                self.vision_model = model;
+            }
+            BroadcastMsg::SearchSettings(settings) => {
+                self.search = settings;
+            }
+            BroadcastMsg::EmbeddingProgress(to_embed) => {
+                self.to_embed = to_embed;
+            }
+            BroadcastMsg::ModelPullProgress(name, progress) => {
+                self.pulls.insert(name, progress);
+            }
+            BroadcastMsg::LabelingSettings(settings) => {
+                self.extra_urls = settings.extra_urls.join("\n");
+                self.labeling = settings;
+            }
             _ => {}
         }
     }
@@ -82,7 +377,13 @@ impl Component for OllamaSettings {
                 if let Some(tx) = action_tx {
                     let _ = tx.send(BroadcastMsg::GetOllamaURL);
                     let _ = tx.send(BroadcastMsg::GetOllamaModels);
+                    let _ = tx.send(BroadcastMsg::GetLabelingSettings);
+                    let _ = tx.send(BroadcastMsg::GetVisionModel);
+                    let _ = tx.send(BroadcastMsg::GetSearchSettings);
+                    let _ = tx.send(BroadcastMsg::EmbedPending);
                 }
+                self.pulls.retain(|_, p| !p.done);
+                self.confirm_delete = None;
                 mem.toggle_popup(button_id);
             });
         }
@@ -119,6 +420,22 @@ impl Component for OllamaSettings {
                         });
                     });
 
+                self.vision_model_ui(ui);
+
+                CollapsingHeader::new("Labeling:").show(ui, |ui| {
+                    self.labeling_ui(ui);
+                });
+
+                CollapsingHeader::new("Search:").show(ui, |ui| {
+                    self.search_ui(ui);
+                });
+
+                CollapsingHeader::new("Get models:").show(ui, |ui| {
+                    self.get_models_ui(ui);
+                });
+
+                let mut pull = None;
+                let mut delete = None;
                 CollapsingHeader::new("Models:").show(ui, |ui| {
                     // ui.label("Models:");
                     for model in &self.models {
@@ -145,10 +462,42 @@ impl Component for OllamaSettings {
                                         ui.small("quantization:");
                                         ui.small(model.details.quantization_level.clone());
                                         ui.end_row();
+
+                                        ui.small("capabilities:");
+                                        let mut caps = model.capabilities.names().join(", ");
+                                        if !model.capabilities.reported {
+                                            caps = format!("{} (guessed)", caps);
+                                        }
+                                        ui.small(caps);
+                                        ui.end_row();
                                     });
+
+                                ui.horizontal(|ui| {
+                                    if ui.small_button("update").clicked() {
+                                        pull = Some(model.name.clone());
+                                    }
+                                    if self.confirm_delete.as_ref() == Some(&model.name) {
+                                        let text = RichText::new("really delete")
+                                            .color(Color32::from_rgb(255, 0, 0));
+                                        if ui.small_button(text).clicked() {
+                                            delete = Some(model.name.clone());
+                                        }
+                                    } else if ui.small_button("delete").clicked() {
+                                        self.confirm_delete = Some(model.name.clone());
+                                    }
+                                });
                             });
                     }
                 });
+                if let Some(name) = pull {
+                    self.pull(name);
+                }
+                if let Some(name) = delete {
+                    self.confirm_delete = None;
+                    if let Some(tx) = self.action_tx.clone() {
+                        let _ = tx.send(BroadcastMsg::DeleteOllamaModel(name));
+                    }
+                }
             },
         );
     }
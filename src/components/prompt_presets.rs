@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use egui::{Button, Grid, RichText, TextEdit};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::Component;
+use crate::{
+    app_state::AppState,
+    enums::{AnswerFormat, BroadcastMsg, PromptPreset, PromptPresets},
+};
+
+/// Window to create, edit and pick the prompt presets used for labeling.
+pub struct PromptPresetsWindow {
+    action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    app_state: Option<Arc<Mutex<AppState>>>,
+    open: bool,
+    presets: PromptPresets,
+    // -- preset being edited, changes are kept until saved
+    draft: Option<PromptPreset>,
+    // -- labeled images per (preset, version) and all labeled images
+    labeled_by: HashMap<(u32, u32), usize>,
+    labeled: usize,
+}
+
+impl PromptPresetsWindow {
+    pub fn new() -> Self {
+        Self {
+            action_tx: None,
+            app_state: None,
+            open: false,
+            presets: PromptPresets::default(),
+            draft: None,
+            labeled_by: HashMap::new(),
+            labeled: 0,
+        }
+    }
+
+    fn send(&self, msg: BroadcastMsg) {
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(msg);
+        }
+    }
+
+    fn send_presets(&self) {
+        self.send(BroadcastMsg::SetPromptPresets(self.presets.clone()));
+    }
+
+    fn load(&mut self) {
+        {
+            if let Some(ref app_state) = self.app_state {
+                self.presets = app_state.lock().unwrap().ollama_state.prompts.clone();
+            }
+        }
+        self.draft = Some(self.presets.active());
+        self.refresh_counts();
+    }
+
+    fn refresh_counts(&mut self) {
+        let mut index = None;
+        let mut formats = vec![];
+        {
+            if let Some(ref app_state) = self.app_state {
+                let a_state = app_state.lock().unwrap();
+                index = Some(a_state.index.clone());
+                formats = a_state.formats.clone();
+            }
+        }
+        if let Some(index) = index {
+            self.labeled_by = index.count_labeled_by_preset(&formats);
+            self.labeled = index.count_files(&formats) - index.count_unlabeled(&formats);
+        }
+    }
+
+    fn select(&mut self, id: u32) {
+        self.draft = self.presets.presets.iter().find(|p| p.id == id).cloned();
+    }
+
+    fn list_ui(&mut self, ui: &mut egui::Ui) {
+        let selected = self.draft.as_ref().map(|d| d.id);
+        for preset in self.presets.presets.clone().iter() {
+            let current = self
+                .labeled_by
+                .get(&(preset.id, preset.version))
+                .copied()
+                .unwrap_or(0);
+            let older: usize = self
+                .labeled_by
+                .iter()
+                .filter(|((id, version), _)| *id == preset.id && *version != preset.version)
+                .map(|(_, n)| n)
+                .sum();
+
+            ui.horizontal(|ui| {
+                let mut name = RichText::new(preset.name.clone());
+                if preset.id == self.presets.active {
+                    name = name.strong();
+                }
+                if ui
+                    .selectable_label(selected == Some(preset.id), name)
+                    .clicked()
+                {
+                    self.select(preset.id);
+                }
+                ui.small(format!("{} images", current));
+                if older > 0 {
+                    ui.small(format!("+{} by older versions", older));
+                }
+            });
+        }
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            if ui.button("New").clicked() {
+                let id = self.presets.add(None, "New preset".to_string());
+                self.send_presets();
+                self.select(id);
+            }
+            if let Some(draft) = self.draft.clone() {
+                if ui.button("Duplicate").clicked() {
+                    let id = self
+                        .presets
+                        .add(Some(draft.id), format!("{} copy", draft.name));
+                    self.send_presets();
+                    self.select(id);
+                }
+                let deletable = self.presets.presets.len() > 1;
+                if ui.add_enabled(deletable, Button::new("Delete")).clicked() {
+                    self.presets.remove(draft.id);
+                    self.send_presets();
+                    self.draft = Some(self.presets.active());
+                }
+            }
+        });
+    }
+
+    fn editor_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(draft) = self.draft.as_mut() else {
+            return;
+        };
+        Grid::new("prompt_preset_grid")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Name:");
+                ui.text_edit_singleline(&mut draft.name);
+                ui.end_row();
+
+                ui.label("System message:");
+                ui.add(
+                    TextEdit::multiline(&mut draft.system)
+                        .desired_rows(2)
+                        .hint_text("optional"),
+                );
+                ui.end_row();
+
+                ui.label("Prompt:");
+                ui.add(TextEdit::multiline(&mut draft.prompt).desired_rows(5));
+                ui.end_row();
+
+                ui.label("Temperature:");
+                ui.add(egui::Slider::new(&mut draft.temperature, 0.0..=2.0));
+                ui.end_row();
+
+                ui.label("Max tokens:");
+                ui.add(egui::DragValue::new(&mut draft.num_predict).range(0..=4096))
+                    .on_hover_text("0 leaves it to the model");
+                ui.end_row();
+
+                ui.label("Answer format:");
+                egui::ComboBox::from_id_salt("prompt_preset_format")
+                    .selected_text(format_name(draft.format))
+                    .show_ui(ui, |ui| {
+                        for format in [AnswerFormat::Structured, AnswerFormat::LabelList] {
+                            ui.selectable_value(&mut draft.format, format, format_name(format));
+                        }
+                    });
+                ui.end_row();
+            });
+
+        let draft = draft.clone();
+        let saved = self.presets.presets.iter().find(|p| p.id == draft.id);
+        let changed = saved != Some(&draft);
+        ui.horizontal(|ui| {
+            if ui.add_enabled(changed, Button::new("Save")).clicked() {
+                self.presets.save(draft.clone());
+                self.send_presets();
+                self.select(draft.id);
+            }
+            if ui.add_enabled(changed, Button::new("Revert")).clicked() {
+                self.select(draft.id);
+            }
+            if draft.id == self.presets.active {
+                ui.small("used for labeling");
+            } else if ui.button("Use for labeling").clicked() {
+                self.presets.active = draft.id;
+                self.send_presets();
+            }
+        });
+    }
+
+    fn relabel_ui(&mut self, ui: &mut egui::Ui) {
+        let active = self.presets.active();
+        let current = self
+            .labeled_by
+            .get(&(active.id, active.version))
+            .copied()
+            .unwrap_or(0);
+        let outdated = self.labeled.saturating_sub(current);
+        ui.label(format!(
+            "{} labeled images are from other presets or older versions of \"{}\"",
+            outdated, active.name
+        ));
+        if ui
+            .add_enabled(outdated > 0, Button::new("Relabel them"))
+            .on_hover_text("Clears their labels and starts labeling with the active preset")
+            .clicked()
+        {
+            self.send(BroadcastMsg::RelabelOutdated);
+            self.send(BroadcastMsg::StartLabeling);
+        }
+    }
+}
+
+fn format_name(format: AnswerFormat) -> &'static str {
+    match format {
+        AnswerFormat::Structured => "structured JSON",
+        AnswerFormat::LabelList => "comma separated labels",
+    }
+}
+
+impl Component for PromptPresetsWindow {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::OpenPromptPresets => {
+                self.load();
+                self.open = true;
+            }
+            BroadcastMsg::GetLabelsForImage(_, _, _) | BroadcastMsg::RelabelOutdated
+                if self.open =>
+            {
+                self.refresh_counts();
+            }
+            _ => {}
+        }
+    }
+
+    fn render(&mut self, ctx: &egui::Context) {
+        let mut open = self.open;
+        egui::Window::new("Prompt presets")
+            .open(&mut open)
+            .default_width(640.0)
+            .show(ctx, |ui| {
+                ui.horizontal_top(|ui| {
+                    ui.vertical(|ui| {
+                        ui.set_width(220.0);
+                        self.list_ui(ui);
+                    });
+                    ui.separator();
+                    ui.vertical(|ui| {
+                        self.editor_ui(ui);
+                    });
+                });
+                ui.separator();
+                self.relabel_ui(ui);
+            });
+        self.open = open;
+    }
+
+    fn register_app_state(&mut self, app_state: Arc<Mutex<AppState>>) {
+        self.app_state = Some(app_state);
+    }
+
+    fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
+        self.action_tx = Some(action_tx);
+    }
+}
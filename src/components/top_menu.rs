@@ -1,18 +1,177 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
 use tokio::sync::mpsc::UnboundedSender;
 
 use super::Component;
-use crate::{config::SUPPORTED_IMAGE_FORMATS, enums::BroadcastMsg};
+use crate::{
+    app_state::AppState,
+    config::SUPPORTED_IMAGE_FORMATS,
+    enums::{BroadcastMsg, ThumbnailCacheStats, VisionPreprocess, VisionUploadStats},
+    utils::{bytes_convert, format_label},
+};
 
 pub struct TopMenu {
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    app_state: Option<Arc<Mutex<AppState>>>,
     formats_checks: Vec<bool>,
+    cache_stats: ThumbnailCacheStats,
+    cache_budget_mb: u64,
+    // -- per vision model, the one edited is the labeling model until another is picked
+    vision_preprocess: BTreeMap<String, VisionPreprocess>,
+    vision_models: Vec<String>,
+    preprocess_model: Option<String>,
+    // -- per model, since the last reset
+    vision_stats: BTreeMap<String, VisionUploadStats>,
 }
 
 impl TopMenu {
     pub fn new() -> Self {
         Self {
             action_tx: None,
+            app_state: None,
             formats_checks: vec![false; SUPPORTED_IMAGE_FORMATS.len()],
+            cache_stats: ThumbnailCacheStats::default(),
+            cache_budget_mb: 0,
+            vision_preprocess: BTreeMap::new(),
+            vision_models: vec![],
+            preprocess_model: None,
+            vision_stats: BTreeMap::new(),
+        }
+    }
+
+    fn send(&self, msg: BroadcastMsg) {
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(msg);
+        }
+    }
+
+    fn formats_changed(&mut self) {
+        let formats = SUPPORTED_IMAGE_FORMATS
+            .iter()
+            .zip(self.formats_checks.iter())
+            .filter(|(_, checked)| **checked)
+            .map(|(f, _)| f.to_string())
+            .collect();
+        self.send(BroadcastMsg::SetFormats(formats));
+    }
+
+    fn vision_upload_menu(&mut self, ui: &mut egui::Ui) {
+        let Some(mut model) = self.preprocess_model.clone() else {
+            ui.small("No vision model yet");
+            return;
+        };
+        egui::ComboBox::from_label("model")
+            .selected_text(&model)
+            .show_ui(ui, |ui| {
+                for name in self.vision_models.iter() {
+                    ui.selectable_value(&mut model, name.clone(), name);
+                }
+            });
+        self.preprocess_model = Some(model.clone());
+
+        let settings = self.vision_preprocess.entry(model.clone()).or_default();
+        let mut changed = false;
+        egui::Grid::new("vision_upload_grid")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Max long edge:");
+                let resp = ui
+                    .add(
+                        egui::DragValue::new(&mut settings.max_edge)
+                            .range(0..=8192)
+                            .suffix(" px"),
+                    )
+                    .on_hover_text("0 keeps the original size");
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+
+                ui.label("JPEG quality:");
+                let resp = ui.add(egui::Slider::new(&mut settings.jpeg_quality, 10..=100));
+                changed |= resp.drag_stopped() || resp.lost_focus();
+                ui.end_row();
+            });
+        changed |= ui
+            .checkbox(&mut settings.strip_metadata, "Strip metadata")
+            .on_hover_text("Always re-encode, so EXIF, GPS and comments are not uploaded")
+            .changed();
+        changed |= ui
+            .checkbox(
+                &mut settings.flatten_alpha,
+                "Put transparent images on white",
+            )
+            .on_hover_text("Send them as JPEG instead of PNG")
+            .changed();
+        if changed {
+            let settings = settings.clone();
+            self.send(BroadcastMsg::SetVisionPreprocess(model, settings));
+        }
+        ui.separator();
+
+        if self.vision_stats.is_empty() {
+            ui.small("No images sent yet");
+            return;
+        }
+        egui::Grid::new("vision_stats_grid")
+            .num_columns(5)
+            .striped(true)
+            .show(ui, |ui| {
+                ui.small("model");
+                ui.small("images");
+                ui.small("avg size");
+                ui.small("avg prepare");
+                ui.small("avg request");
+                ui.end_row();
+                for (model, stats) in self.vision_stats.iter() {
+                    let n = stats.images.max(1) as u64;
+                    ui.small(model);
+                    ui.small(stats.images.to_string());
+                    ui.small(format!(
+                        "{} -> {}",
+                        bytes_convert((stats.original_bytes / n) as f64),
+                        bytes_convert((stats.sent_bytes / n) as f64)
+                    ));
+                    ui.small(format!("{} ms", stats.prepare_ms / n));
+                    ui.small(format!("{} ms", stats.request_ms / n));
+                    ui.end_row();
+                }
+            });
+        if ui.button("Reset statistics").clicked() {
+            self.vision_stats.clear();
+            self.send(BroadcastMsg::ResetVisionUploadStats);
+        }
+    }
+
+    fn thumbnail_cache_menu(&mut self, ui: &mut egui::Ui) {
+        ui.label(format!(
+            "{} thumbnails, {} of {}",
+            self.cache_stats.files,
+            bytes_convert(self.cache_stats.bytes as f64),
+            bytes_convert(self.cache_stats.budget_bytes as f64)
+        ));
+        if self.cache_stats.removed > 0 {
+            ui.small(format!("removed {} thumbnails", self.cache_stats.removed));
+        }
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Size limit:");
+            let resp = ui.add(
+                egui::DragValue::new(&mut self.cache_budget_mb)
+                    .range(16..=100_000)
+                    .suffix(" MB"),
+            );
+            if resp.drag_stopped() || resp.lost_focus() {
+                self.send(BroadcastMsg::SetThumbnailCacheBudget(self.cache_budget_mb));
+            }
+        });
+        if ui.button("Check cache").clicked() {
+            self.send(BroadcastMsg::CheckThumbnailCache);
+        }
+        if ui.button("Clear cache").clicked() {
+            self.send(BroadcastMsg::ClearThumbnailCache);
         }
     }
 }
@@ -22,11 +181,53 @@ impl Component for TopMenu {
         self
     }
 
+    fn init(&mut self, _cc: &eframe::CreationContext<'_>) {
+        self.send(BroadcastMsg::GetThumbnailCacheStats);
+    }
+
+    fn update(&mut self, msg: BroadcastMsg) {
+        match msg {
+            BroadcastMsg::ThumbnailCacheStats(stats) => {
+                self.cache_budget_mb = stats.budget_bytes / (1000 * 1000);
+                self.cache_stats = stats;
+            }
+            BroadcastMsg::VisionUploaded(model, stats) => {
+                self.vision_stats.entry(model).or_default().add(&stats);
+            }
+            BroadcastMsg::OllamaModels(models) => {
+                self.vision_models = models
+                    .iter()
+                    .filter(|m| m.is_vision())
+                    .map(|m| m.name.clone())
+                    .collect();
+            }
+            BroadcastMsg::VisionModel(Some(model)) | BroadcastMsg::SetVisionModel(model) => {
+                self.preprocess_model = Some(model);
+            }
+            _ => {}
+        }
+    }
+
     fn render(&mut self, ctx: &egui::Context) {
         egui::TopBottomPanel::top("top_menu").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 // -- main button
                 ui.menu_button("Imager", |ui| {
+                    ui.menu_button("Thumbnail cache", |ui| {
+                        self.thumbnail_cache_menu(ui);
+                    });
+                    ui.menu_button("Vision upload", |ui| {
+                        self.vision_upload_menu(ui);
+                    });
+                    if ui.button("Prompt presets").clicked() {
+                        self.send(BroadcastMsg::OpenPromptPresets);
+                        ui.close_menu();
+                    }
+                    if ui.button("Find duplicates").clicked() {
+                        self.send(BroadcastMsg::OpenDuplicateFinder);
+                        ui.close_menu();
+                    }
+                    ui.separator();
                     if ui.button("Quit").clicked() {
                         ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                     }
@@ -36,14 +237,39 @@ impl Component for TopMenu {
                 ui.menu_button("Formats", |ui| {
                     ui.label("Filter searched formats");
                     ui.separator();
+                    let mut changed = false;
                     for (i, checked) in self.formats_checks.iter_mut().enumerate() {
-                        ui.checkbox(checked, SUPPORTED_IMAGE_FORMATS[i]);
+                        changed |= ui
+                            .checkbox(checked, format_label(SUPPORTED_IMAGE_FORMATS[i]))
+                            .changed();
+                    }
+                    if changed {
+                        self.formats_changed();
                     }
                 });
             });
         });
     }
 
+    fn register_app_state(&mut self, app_state: Arc<Mutex<AppState>>) {
+        self.app_state = Some(app_state);
+
+        // -- formats
+        {
+            if let Some(app_state) = self.app_state.clone() {
+                let a_state = app_state.lock().unwrap();
+                let formats = a_state.formats.clone();
+                self.vision_preprocess = a_state.ollama_state.vision_preprocess.clone();
+                self.vision_stats = a_state.ollama_state.upload_stats.clone();
+                self.preprocess_model = a_state.ollama_state.vision_model.clone();
+                self.formats_checks = SUPPORTED_IMAGE_FORMATS
+                    .iter()
+                    .map(|f| formats.iter().any(|s| s == f))
+                    .collect();
+            }
+        }
+    }
+
     fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
         self.action_tx = Some(action_tx);
     }
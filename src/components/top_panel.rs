@@ -1,22 +1,57 @@
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use super::ollama_settings::OllamaSettings;
 use super::Component;
-use crate::{app_state::AppState, enums::BroadcastMsg};
+use crate::{
+    app_state::AppState,
+    config::SUPPORTED_IMAGE_FORMATS,
+    enums::{BroadcastMsg, OllamaServerStatus},
+    query::Query,
+    utils::{bytes_convert, format_label},
+};
 use egui::{Align, CollapsingHeader, Color32, Grid, RichText, ScrollArea};
 use tokio::sync::mpsc::UnboundedSender;
 
+const QUERY_HELP: &str = "Words must all be found, OR or a comma for either
+-word or NOT word leaves images out, \"red car\" is a phrase, ( ) groups
+label:tree  caption:wedding  description:sunset
+ext:png  dir:~/Pictures  width:>3000  height:1000..2000
+modified:2024-..  modified:2024-06  modified:<2023-01-15";
+
 pub struct TopPanel {
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
     input_text: String,
+    // -- why the search input does not parse
+    query_error: Option<String>,
     picked_directories: Vec<PathBuf>,
+    rescan_summaries: HashMap<PathBuf, String>,
+    // -- directories whose files are all gone, waiting for the user to confirm
+    unconfirmed_removals: HashSet<PathBuf>,
+    watched_directories: HashSet<PathBuf>,
+    offline_directories: HashSet<PathBuf>,
+    global_formats: Vec<String>,
+    // -- directories with their own formats
+    dir_formats: HashMap<PathBuf, Vec<String>>,
     app_state: Option<Arc<Mutex<AppState>>>,
     ollama_button: OllamaSettings,
     ollama_connected: bool,
+    ollama_status: OllamaServerStatus,
+    // -- chosen labeling model and the models on the server
+    vision_model: Option<String>,
+    model_names: Vec<String>,
     non_labeled_imgs: usize,
     all_imgs_num: usize,
+    // -- unlabeled files whose labeling failed, and the latest (file, error)
+    failed_imgs: usize,
+    failed_descriptions: usize,
+    // -- labeled files waiting for the description pass
+    undescribed_imgs: usize,
+    recent_failures: Vec<(String, String)>,
     is_labeling: bool,
+    // -- requests in flight, files still queued
+    labeling_progress: (usize, usize),
 }
 
 impl TopPanel {
@@ -24,37 +59,82 @@ impl TopPanel {
         Self {
             action_tx: None,
             input_text: "".to_string(),
+            query_error: None,
             picked_directories: vec![],
+            rescan_summaries: HashMap::new(),
+            unconfirmed_removals: HashSet::new(),
+            watched_directories: HashSet::new(),
+            offline_directories: HashSet::new(),
+            global_formats: vec![],
+            dir_formats: HashMap::new(),
             app_state: None,
             ollama_connected: false,
+            ollama_status: OllamaServerStatus::default(),
+            vision_model: None,
+            model_names: vec![],
             ollama_button: OllamaSettings::new(),
             non_labeled_imgs: 0,
             all_imgs_num: 0,
+            failed_imgs: 0,
+            failed_descriptions: 0,
+            undescribed_imgs: 0,
+            recent_failures: vec![],
             is_labeling: false,
+            labeling_progress: (0, 0),
         }
     }
 
     fn get_labeled_images(&mut self) {
-        let mut dir_files = vec![];
+        let mut index = None;
+        let mut formats = vec![];
+        let mut describe = false;
         {
             if let Some(ref app_state) = self.app_state {
-                dir_files = app_state.lock().unwrap().dir_files.clone();
+                let a_state = app_state.lock().unwrap();
+                index = Some(a_state.index.clone());
+                formats = a_state.formats.clone();
+                describe = a_state.ollama_state.labeling.describe;
             }
         }
 
         // -- get all files that is not having labels
-        let mut non_labeled_imgs = 0;
-        let mut all_imgs_num = 0;
-        for dir in dir_files.iter() {
-            all_imgs_num += dir.files_with_labels.len();
-            for file in dir.files_with_labels.iter() {
-                if file.labels.is_empty() {
-                    non_labeled_imgs += 1;
-                }
-            }
+        if let Some(index) = index {
+            self.failed_descriptions = index.count_description_failures(&formats);
+            self.undescribed_imgs = match describe {
+                true => index.count_undescribed(&formats),
+                false => 0,
+            };
+            self.non_labeled_imgs = index.count_unlabeled(&formats);
+            self.all_imgs_num = index.count_files(&formats);
+            self.failed_imgs = index.count_label_failures(&formats);
+            self.recent_failures = index.label_failures(&formats, 10);
         }
-        self.non_labeled_imgs = non_labeled_imgs;
-        self.all_imgs_num = all_imgs_num;
+    }
+
+    fn ollama_status_text(&self) -> String {
+        let status = &self.ollama_status;
+        let mut lines = vec![
+            format!("Ollama {}", status.version),
+            format!("round trip {} ms", status.latency_ms),
+        ];
+        if status.loaded.is_empty() {
+            lines.push("no model loaded".to_string());
+        }
+        for model in status.loaded.iter() {
+            lines.push(format!(
+                "{}: {} of {} in VRAM",
+                model.name,
+                bytes_convert(model.size_vram as f64),
+                bytes_convert(model.size as f64)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    // -- the chosen model is gone from a connected server
+    fn missing_model(&self) -> Option<&String> {
+        let model = self.vision_model.as_ref()?;
+        (self.ollama_connected && !self.model_names.contains(model)).then_some(model)
     }
 
     fn pick_dir(&mut self, path: PathBuf) {
@@ -62,10 +142,12 @@ impl TopPanel {
             self.picked_directories.push(path.clone());
             {
                 if let Some(ref app_state) = self.app_state {
-                    app_state.lock().unwrap().directories = self.picked_directories.clone();
+                    app_state.lock().unwrap().add_directory(path.clone());
                 }
             }
 
+            self.watched_directories.insert(path.clone());
+
             if let Some(action_tx) = self.action_tx.clone() {
                 let _ = action_tx.send(BroadcastMsg::PickedDirectory(path));
             }
@@ -87,21 +169,127 @@ impl TopPanel {
         }
     }
 
+    fn rescan_directory(&mut self, path: PathBuf) {
+        self.rescan_summaries
+            .insert(path.clone(), "rescanning..".to_string());
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(BroadcastMsg::RescanDirectory(path));
+        }
+    }
+
+    fn confirm_removal(&mut self, path: PathBuf) {
+        self.unconfirmed_removals.remove(&path);
+        self.rescan_summaries
+            .insert(path.clone(), "rescanning..".to_string());
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(BroadcastMsg::ConfirmRescanRemoval(path));
+        }
+    }
+
+    fn toggle_watch(&mut self, path: PathBuf, watch: bool) {
+        if watch {
+            self.watched_directories.insert(path.clone());
+        } else {
+            self.watched_directories.remove(&path);
+            self.offline_directories.remove(&path);
+        }
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(BroadcastMsg::SetDirectoryWatch(path, watch));
+        }
+    }
+
+    fn set_dir_formats(&mut self, path: PathBuf, formats: Option<Vec<String>>) {
+        match formats.clone() {
+            Some(f) => self.dir_formats.insert(path.clone(), f),
+            None => self.dir_formats.remove(&path),
+        };
+        if let Some(action_tx) = self.action_tx.clone() {
+            let _ = action_tx.send(BroadcastMsg::SetDirectoryFormats(path, formats));
+        }
+    }
+
+    fn dir_formats_menu(&mut self, path: &PathBuf, ui: &mut egui::Ui) {
+        let mut same_as_global = !self.dir_formats.contains_key(path);
+        if ui.checkbox(&mut same_as_global, "same as global").changed() {
+            let formats = (!same_as_global).then(|| self.global_formats.clone());
+            self.set_dir_formats(path.clone(), formats);
+        }
+        ui.separator();
+
+        let current = self
+            .dir_formats
+            .get(path)
+            .unwrap_or(&self.global_formats)
+            .clone();
+        ui.add_enabled_ui(!same_as_global, |ui| {
+            for format in SUPPORTED_IMAGE_FORMATS.iter() {
+                let mut checked = current.iter().any(|f| f == format);
+                if ui.checkbox(&mut checked, format_label(format)).changed() {
+                    let formats = SUPPORTED_IMAGE_FORMATS
+                        .iter()
+                        .filter(|f| {
+                            if *f == format {
+                                checked
+                            } else {
+                                current.iter().any(|c| c == *f)
+                            }
+                        })
+                        .map(|f| f.to_string())
+                        .collect();
+                    self.set_dir_formats(path.clone(), Some(formats));
+                }
+            }
+        });
+    }
+
     fn draw_left_side(&mut self, ui: &mut egui::Ui) {
         ui.vertical(|ui| {
             egui::Grid::new("left_grid").num_columns(2).show(ui, |ui| {
                 ui.label("Search images:");
                 // -- search input
-                let resp = ui.add(
-                    egui::TextEdit::singleline(&mut self.input_text).hint_text("Search here.."),
-                );
-                if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                let resp = ui
+                    .add(
+                        egui::TextEdit::singleline(&mut self.input_text)
+                            .hint_text("dog beach -indoor ext:png"),
+                    )
+                    .on_hover_text(QUERY_HELP);
+                if resp.changed() {
+                    self.query_error = match self.input_text.trim().is_empty() {
+                        true => None,
+                        false => Query::parse(&self.input_text).err(),
+                    };
+                }
+                if resp.lost_focus()
+                    && ui.input(|i| i.key_pressed(egui::Key::Enter))
+                    && self.query_error.is_none()
+                {
                     if let Some(action_tx) = self.action_tx.clone() {
                         let _ =
                             action_tx.send(BroadcastMsg::SearchByLabels(self.input_text.clone()));
                     }
                 }
+                ui.end_row();
+
+                if let Some(e) = self.query_error.as_ref() {
+                    ui.label("");
+                    ui.small(RichText::new(e).color(Color32::from_rgb(255, 0, 0)));
+                    ui.end_row();
+                }
 
+                // -- reverse image search, dropping an image on the window works too
+                ui.label("Search by image:");
+                if ui
+                    .button("Pick an image")
+                    .on_hover_text("Or drop an image on the window")
+                    .clicked()
+                {
+                    if let Some(file) = rfd::FileDialog::new().pick_file() {
+                        if let Some(action_tx) = self.action_tx.clone() {
+                            let file = file.to_string_lossy().to_string();
+                            let _ = action_tx.send(BroadcastMsg::FindSimilar(file, None));
+                        }
+                    }
+                }
                 ui.end_row();
 
                 // -- directory picker
@@ -117,12 +305,53 @@ impl TopPanel {
                 ScrollArea::vertical().max_width(420.0).show(ui, |ui| {
                     Grid::new("dir_grid")
                         .striped(true)
-                        .num_columns(2)
+                        .num_columns(5)
                         .min_col_width(50.0)
                         .max_col_width(340.0)
                         .show(ui, |ui| {
                             for dir in self.picked_directories.clone().iter() {
-                                ui.small(dir.to_string_lossy());
+                                ui.vertical(|ui| {
+                                    ui.small(dir.to_string_lossy());
+                                    if self.offline_directories.contains(dir) {
+                                        ui.small(
+                                            RichText::new("unavailable")
+                                                .color(Color32::from_rgb(255, 0, 0)),
+                                        );
+                                    } else if let Some(summary) = self.rescan_summaries.get(dir) {
+                                        ui.small(
+                                            RichText::new(summary)
+                                                .color(Color32::from_rgb(0, 255, 255)),
+                                        );
+                                    }
+                                    if self.unconfirmed_removals.contains(dir) {
+                                        ui.horizontal(|ui| {
+                                            if ui
+                                                .small_button("remove them")
+                                                .on_hover_text("Their labels are lost")
+                                                .clicked()
+                                            {
+                                                self.confirm_removal(dir.clone());
+                                            }
+                                            if ui.small_button("keep").clicked() {
+                                                self.unconfirmed_removals.remove(dir);
+                                            }
+                                        });
+                                    }
+                                });
+                                let mut watch = self.watched_directories.contains(dir);
+                                if ui
+                                    .checkbox(&mut watch, "watch")
+                                    .on_hover_text("Rescan automatically when files change")
+                                    .changed()
+                                {
+                                    self.toggle_watch(dir.clone(), watch);
+                                }
+                                ui.menu_button("formats", |ui| {
+                                    self.dir_formats_menu(dir, ui);
+                                });
+                                if ui.button("rescan").clicked() {
+                                    self.rescan_directory(dir.clone());
+                                }
                                 if ui.button("delete").clicked() {
                                     self.remove_directory(dir.clone());
                                     println!("delete: {}", dir.to_string_lossy());
@@ -162,18 +391,58 @@ impl TopPanel {
                 if !self.ollama_connected {
                     ui.small(RichText::new("not connected").color(Color32::from_rgb(255, 0, 0)));
                 } else {
-                    ui.small(RichText::new("connected").color(Color32::from_rgb(0, 255, 0)));
+                    ui.small(format!("{} ms", self.ollama_status.latency_ms));
+                    ui.small(RichText::new("connected").color(Color32::from_rgb(0, 255, 0)))
+                        .on_hover_text(self.ollama_status_text());
                 }
             });
         });
 
         let labeled_imgs = self.all_imgs_num - self.non_labeled_imgs;
+        let failed = self.failed_imgs + self.failed_descriptions;
         ui.horizontal(|ui| {
-            if self.all_imgs_num == labeled_imgs {
+            if let Some(model) = self.missing_model() {
+                ui.small(
+                    RichText::new(format!("{} not found", model))
+                        .color(Color32::from_rgb(255, 0, 0)),
+                )
+                .on_hover_text("Choose another labeling model in the Ollama settings");
+            }
+            if failed > 0 {
+                if !self.is_labeling && ui.button("retry failed").clicked() {
+                    if let Some(action_tx) = self.action_tx.clone() {
+                        let _ = action_tx.send(BroadcastMsg::RetryFailedLabels);
+                        let _ = action_tx.send(BroadcastMsg::StartLabeling);
+                    }
+                }
+                let failures = self
+                    .recent_failures
+                    .iter()
+                    .map(|(file, error)| format!("{}: {}", file, error))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                ui.small(
+                    RichText::new(format!("{} failed", failed)).color(Color32::from_rgb(255, 0, 0)),
+                )
+                .on_hover_text(failures);
+            }
+            if self.all_imgs_num == labeled_imgs + self.failed_imgs && self.undescribed_imgs == 0 {
                 ui.label(RichText::new("all done").color(Color32::from_rgb(0, 255, 255)));
             } else {
-                if self.is_labeling {
+                if self.is_labeling && !self.ollama_connected {
+                    ui.small(
+                        RichText::new("paused until Ollama is back")
+                            .color(Color32::from_rgb(255, 0, 0)),
+                    );
+                    if ui.button("stop").clicked() {
+                        if let Some(action_tx) = self.action_tx.clone() {
+                            let _ = action_tx.send(BroadcastMsg::StopLabeling);
+                        }
+                    }
+                } else if self.is_labeling {
                     ui.spinner();
+                    let (in_flight, queued) = self.labeling_progress;
+                    ui.small(format!("{} running, {} queued", in_flight, queued));
                     if ui.button("stop").clicked() {
                         if let Some(action_tx) = self.action_tx.clone() {
                             let _ = action_tx.send(BroadcastMsg::StopLabeling);
@@ -187,6 +456,9 @@ impl TopPanel {
                     }
                 }
                 ui.label(format!("{}/{}", labeled_imgs, self.all_imgs_num));
+                if self.undescribed_imgs > 0 {
+                    ui.small(format!("{} to describe", self.undescribed_imgs));
+                }
             }
             ui.label("labels:");
         });
@@ -207,22 +479,70 @@ impl Component for TopPanel {
         self.ollama_button.update(msg.clone());
 
         match msg {
-            BroadcastMsg::OllamaRunning(r) => self.ollama_connected = r.is_ok(),
+            BroadcastMsg::OllamaRunning(r) => {
+                self.ollama_connected = r.is_ok();
+                if let Ok(status) = r {
+                    self.ollama_status = status;
+                }
+            }
+            BroadcastMsg::OllamaModels(models) => {
+                self.model_names = models.into_iter().map(|m| m.name).collect();
+            }
+            BroadcastMsg::VisionModel(model) => {
+                self.vision_model = model;
+            }
+            BroadcastMsg::SetVisionModel(model) => {
+                self.vision_model = Some(model);
+            }
             BroadcastMsg::StartLabeling => {
                 self.is_labeling = true;
             }
-            BroadcastMsg::StopLabeling => {
+            BroadcastMsg::StopLabeling | BroadcastMsg::FinishLabeling => {
                 self.is_labeling = false;
             }
+            BroadcastMsg::LabelingProgress(in_flight, queued) => {
+                self.labeling_progress = (in_flight, queued);
+            }
+            BroadcastMsg::VisionRequestFailed(_, _)
+            | BroadcastMsg::DescriptionRequestFailed(_, _)
+            | BroadcastMsg::GetDescriptionForImage(_, _)
+            | BroadcastMsg::SetLabelingSettings(_)
+            | BroadcastMsg::RetryFailedLabels => {
+                self.get_labeled_images();
+            }
             BroadcastMsg::DirectoryImages(_) => {
                 self.get_labeled_images();
             }
-            BroadcastMsg::RemovedDirectory(_) => {
+            BroadcastMsg::RemovedDirectory(path) => {
+                self.rescan_summaries.remove(&path);
+                self.unconfirmed_removals.remove(&path);
+                self.dir_formats.remove(&path);
+                self.watched_directories.remove(&path);
+                self.offline_directories.remove(&path);
                 self.get_labeled_images();
             }
-            BroadcastMsg::GetLabelsForImage(_, _) => {
+            BroadcastMsg::WatchedDirectoryAvailable(path, available) => {
+                if available {
+                    self.offline_directories.remove(&path);
+                } else {
+                    self.offline_directories.insert(path);
+                }
+            }
+            BroadcastMsg::DirectoryRescanned(path, summary) => {
+                if summary.unconfirmed > 0 {
+                    self.unconfirmed_removals.insert(path.clone());
+                } else {
+                    self.unconfirmed_removals.remove(&path);
+                }
+                self.rescan_summaries.insert(path, summary.describe());
+                self.get_labeled_images();
+            }
+            BroadcastMsg::GetLabelsForImage(_, _, _) | BroadcastMsg::RelabelOutdated => {
                 self.get_labeled_images();
             }
+            BroadcastMsg::SetFormats(formats) => {
+                self.global_formats = formats;
+            }
             _ => {}
         }
     }
@@ -255,8 +575,13 @@ impl Component for TopPanel {
         // -- directories
         {
             if let Some(app_state) = self.app_state.clone() {
-                let directories = app_state.lock().unwrap().directories.clone();
-                self.picked_directories = directories;
+                let a_state = app_state.lock().unwrap();
+                self.picked_directories = a_state.directories.clone();
+                self.watched_directories =
+                    a_state.index.watched_directories().into_iter().collect();
+                self.global_formats = a_state.formats.clone();
+                self.vision_model = a_state.ollama_state.vision_model.clone();
+                self.dir_formats = a_state.index.directory_formats();
             }
         }
     }
@@ -1,8 +1,32 @@
-pub const SUPPORTED_IMAGE_FORMATS: [&str; 3] = ["png", "jpg", "jpeg"];
+// -- formats decoded by the `image` crate, detected from the file content
+pub const SUPPORTED_IMAGE_FORMATS: &[&str] = &[
+    "png", "jpeg", "webp", "gif", "bmp", "tiff", "ico", "pnm", "qoi", "tga", "dds", "exr", "hdr",
+    "farbfeld", "raw", "avif",
+];
+// -- AVIF decoding needs the native dav1d library, without it AVIF files are still
+// -- listed but show an error
+pub const AVIF_SUPPORTED: bool = cfg!(feature = "avif");
+pub const AVIF_UNSUPPORTED_NOTE: &str = "AVIF not supported in this build";
 
-// pub const IMG_LABEL_PROMPT: &str = "List the main objects or elements in this image as very simple labels (use maximum 2.words for a label) separated by commas. Never return more then five labels.";
-// pub const IMG_LABEL_PROMPT: &str = "List up to 5 main objects or elements in this image as simple labels, separated by commas. Do not list more than 5.";
+pub const THUMBNAIL_CACHE_BUDGET_MB: u64 = 512;
+pub const MAX_IMAGE_FILE_BYTES: u64 = 256 * 1000 * 1000;
+
+// -- offered in the Ollama settings, (name, what it is for)
+pub const RECOMMENDED_MODELS: &[(&str, &str)] = &[
+    ("llava:7b", "vision"),
+    ("llama3.2-vision:11b", "vision"),
+    ("gemma3:4b", "vision"),
+    ("qwen2.5vl:7b", "vision"),
+    ("minicpm-v:8b", "vision"),
+    ("nomic-embed-text", "embedding"),
+    ("mxbai-embed-large", "embedding"),
+];
+
+// -- prompts of the built-in presets, the label list one is also the fallback
+// -- for models without structured output
 pub const IMG_LABEL_PROMPT: &str = "List up to 5 main objects or elements in this image as simple labels, each 2-3 words max, separated by commas. Do not include 'and', '...', or extra text.";
+pub const IMG_DESCRIPTION_PROMPT: &str = "Describe this image in one paragraph of 3-5 sentences: the main subjects, what is happening, the setting and notable details. Answer with the paragraph only.";
+pub const IMG_DESCRIBE_PROMPT: &str = "Describe this image. Give up to 5 main objects or elements as simple labels (each 2-3 words max), a one sentence caption, any text readable in the image, up to 3 dominant colors and your confidence in the labels from 0 to 1.";
 
 // const COLORS_SIGNAL: [Color; 7] = [
 //     Color::Red,
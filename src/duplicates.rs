@@ -0,0 +1,427 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    enums::{DuplicateFile, DuplicateGroup, FileFingerprint},
+    image_signature::ImageSignature,
+    utils::file_content_hash,
+};
+
+/// Groups byte-identical files by content hash and near-duplicates by the
+/// distance of their perceptual hashes, largest waste first.
+///
+/// A near-duplicate group is every file within `max_distance` of its first
+/// file, so a chain of slightly different images is not merged into one group.
+pub fn find_duplicates(files: &[FileFingerprint], max_distance: u32) -> Vec<DuplicateGroup> {
+    let mut groups = vec![];
+
+    // -- identical files, one of each content takes part in the near-duplicate search
+    let mut by_hash: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut representatives = vec![];
+    for (i, file) in files.iter().enumerate() {
+        match file.hash.as_deref() {
+            Some(hash) => by_hash.entry(hash).or_default().push(i),
+            None => representatives.push(i),
+        }
+    }
+    for members in by_hash.into_values() {
+        let group = new_group(files, &members, true);
+        let keep = &group.files[group.keep].path;
+        // -- the kept copy stands for the content, its signature may still be missing
+        let representative = members
+            .iter()
+            .copied()
+            .filter(|i| files[*i].signature.is_some())
+            .max_by_key(|i| &files[*i].path == keep)
+            .unwrap_or(members[0]);
+        representatives.push(representative);
+        if members.len() > 1 {
+            groups.push(group);
+        }
+    }
+
+    // -- the image with the most pixels becomes the first file of its group
+    let mut signed: Vec<usize> = representatives
+        .into_iter()
+        .filter(|i| files[*i].signature.is_some())
+        .collect();
+    signed.sort_by_key(|i| {
+        let f = &files[*i];
+        (
+            Reverse(f.width as u64 * f.height as u64),
+            Reverse(f.size),
+            &f.path,
+        )
+    });
+
+    let mut firsts = BkTree::default();
+    let mut members: Vec<Vec<usize>> = vec![];
+    for i in signed {
+        let signature = files[i].signature.as_ref().unwrap();
+        let nearest = firsts.nearest(signature, max_distance, |g| {
+            files[members[g][0]].signature.as_ref().unwrap()
+        });
+        match nearest {
+            Some(g) => members[g].push(i),
+            None => {
+                firsts.insert(members.len(), signature, |g| {
+                    files[members[g][0]].signature.as_ref().unwrap()
+                });
+                members.push(vec![i]);
+            }
+        }
+    }
+    groups.extend(
+        members
+            .iter()
+            .filter(|m| m.len() > 1)
+            .map(|m| new_group(files, m, false)),
+    );
+
+    groups.sort_by_key(|g| Reverse(wasted_bytes(g)));
+    groups
+}
+
+fn new_group(files: &[FileFingerprint], members: &[usize], exact: bool) -> DuplicateGroup {
+    let files: Vec<DuplicateFile> = members.iter().map(|i| duplicate_file(&files[*i])).collect();
+    DuplicateGroup {
+        exact,
+        keep: suggest_keep(&files),
+        files,
+    }
+}
+
+// -- BK-tree over the hash distance, a node holds a group and its children by distance
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<(usize, HashMap<u32, usize>)>,
+}
+
+impl BkTree {
+    fn insert<'a>(
+        &mut self,
+        group: usize,
+        signature: &ImageSignature,
+        signature_of: impl Fn(usize) -> &'a ImageSignature,
+    ) {
+        let new = self.nodes.len();
+        self.nodes.push((group, HashMap::new()));
+        if new == 0 {
+            return;
+        }
+        let mut node = 0;
+        loop {
+            let distance = signature_of(self.nodes[node].0).hash_distance(signature);
+            match self.nodes[node].1.get(&distance) {
+                Some(&child) => node = child,
+                None => {
+                    self.nodes[node].1.insert(distance, new);
+                    return;
+                }
+            }
+        }
+    }
+
+    // -- the closest group within `max_distance`, the first one found on a tie
+    fn nearest<'a>(
+        &self,
+        signature: &ImageSignature,
+        max_distance: u32,
+        signature_of: impl Fn(usize) -> &'a ImageSignature,
+    ) -> Option<usize> {
+        let mut best: Option<(u32, usize)> = None;
+        let mut stack = match self.nodes.is_empty() {
+            true => vec![],
+            false => vec![0],
+        };
+        while let Some(node) = stack.pop() {
+            let (group, children) = &self.nodes[node];
+            let distance = signature_of(*group).hash_distance(signature);
+            if distance <= max_distance && best.map_or(true, |b| (distance, *group) < b) {
+                best = Some((distance, *group));
+            }
+            let low = distance.saturating_sub(max_distance);
+            let high = distance + max_distance;
+            stack.extend(
+                children
+                    .iter()
+                    .filter(|(d, _)| (low..=high).contains(*d))
+                    .map(|(_, child)| *child),
+            );
+        }
+        best.map(|(_, group)| group)
+    }
+}
+
+fn duplicate_file(f: &FileFingerprint) -> DuplicateFile {
+    DuplicateFile {
+        path: f.path.clone(),
+        dir: f.dir.clone(),
+        size: f.size,
+        mtime: f.mtime,
+        hash: f.hash.clone(),
+        width: f.width,
+        height: f.height,
+    }
+}
+
+// -- most pixels, then the biggest file, the oldest one and the shortest path
+fn suggest_keep(files: &[DuplicateFile]) -> usize {
+    files
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, f)| {
+            (
+                f.width as u64 * f.height as u64,
+                f.size,
+                Reverse(f.mtime),
+                Reverse(f.path.len()),
+            )
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// Bytes freed when only the suggested copy is kept.
+pub fn wasted_bytes(group: &DuplicateGroup) -> u64 {
+    group
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != group.keep)
+        .map(|(_, f)| f.size)
+        .sum()
+}
+
+/// Moves `(file, kept copy)` pairs to the trash. A file is skipped when it or the
+/// kept copy changed since the scan, or when it is a link to the kept copy.
+pub fn delete_files(files: &[(DuplicateFile, DuplicateFile)]) -> (Vec<String>, Vec<String>) {
+    let mut done = vec![];
+    let mut errors = vec![];
+    for (file, kept) in files.iter() {
+        let removed = check_removal(file, kept)
+            .and_then(|_| trash::delete(&file.path).map_err(|e| e.to_string()));
+        match removed {
+            Ok(()) => done.push(file.path.clone()),
+            Err(e) => errors.push(format!("{}: {}", file.path, e)),
+        }
+    }
+    (done, errors)
+}
+
+// -- the file and its kept copy still hold what was compared, and are two files
+fn check_removal(file: &DuplicateFile, kept: &DuplicateFile) -> Result<(), String> {
+    let meta = fs::symlink_metadata(&file.path).map_err(|e| e.to_string())?;
+    if meta.file_type().is_symlink() {
+        return Err("is a link, skipped".to_string());
+    }
+    let kept_meta =
+        fs::metadata(&kept.path).map_err(|e| format!("kept copy {} is gone: {}", kept.path, e))?;
+    if same_file(&meta, &kept_meta) {
+        return Err(format!("is a hard link to the kept copy {}", kept.path));
+    }
+    if file.hash.is_none() || file_content_hash(&file.path) != file.hash {
+        return Err("changed since the scan, skipped".to_string());
+    }
+    if kept.hash.is_none() || file_content_hash(&kept.path) != kept.hash {
+        return Err(format!("kept copy {} changed since the scan", kept.path));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    false
+}
+
+/// Moves the files into `target`, renaming them when the name is taken there.
+pub fn move_files(files: &[String], target: &Path) -> (Vec<String>, Vec<String>) {
+    let mut done = vec![];
+    let mut errors = vec![];
+    for file in files.iter() {
+        let to = free_path(target, Path::new(file));
+        // -- rename does not work across file systems
+        let moved = fs::rename(file, &to).or_else(|_| {
+            fs::copy(file, &to)?;
+            fs::remove_file(file)
+        });
+        match moved {
+            Ok(()) => done.push(file.clone()),
+            Err(e) => errors.push(format!("{}: {}", file, e)),
+        }
+    }
+    (done, errors)
+}
+
+fn free_path(dir: &Path, file: &Path) -> PathBuf {
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = file
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut path = dir.join(format!("{}{}", stem, ext));
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, n, ext));
+        n += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, hash: Option<&str>, dhash: Option<u64>) -> FileFingerprint {
+        FileFingerprint {
+            path: path.to_string(),
+            dir: PathBuf::from("/photos"),
+            size: 1000,
+            mtime: 0,
+            hash: hash.map(|h| h.to_string()),
+            signature: dhash.map(|dhash| ImageSignature {
+                dhash,
+                phash: 0,
+                histogram: [0.0; 64],
+            }),
+            width: 100,
+            height: 100,
+        }
+    }
+
+    fn paths(group: &DuplicateGroup) -> Vec<&str> {
+        let mut paths: Vec<&str> = group.files.iter().map(|f| f.path.as_str()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn bk_tree_finds_the_nearest_within_the_radius() {
+        // -- clusters of hashes a few bits apart, from a fixed pseudo-random sequence
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let bases: Vec<u64> = (0..8).map(|_| next()).collect();
+        let signatures: Vec<ImageSignature> = (0..200)
+            .map(|i| {
+                let flips = (0..i % 5).fold(0u64, |f, _| f | 1 << (next() % 64));
+                ImageSignature {
+                    dhash: bases[i % bases.len()] ^ flips,
+                    phash: bases[(i + 3) % bases.len()],
+                    histogram: [0.0; 64],
+                }
+            })
+            .collect();
+
+        let mut tree = BkTree::default();
+        for (i, signature) in signatures[..150].iter().enumerate() {
+            tree.insert(i, signature, |g| &signatures[g]);
+        }
+        for radius in [0, 3, 6, 12] {
+            for query in signatures[150..].iter() {
+                let brute = (0..150)
+                    .map(|g| (signatures[g].hash_distance(query), g))
+                    .filter(|(d, _)| *d <= radius)
+                    .min()
+                    .map(|(_, g)| g);
+                assert_eq!(tree.nearest(query, radius, |g| &signatures[g]), brute);
+            }
+        }
+    }
+
+    #[test]
+    fn chain_is_not_one_group() {
+        // -- a~b and b~c within 4 bits, but a and c 8 bits apart
+        let files = vec![
+            file("a", Some("1"), Some(0)),
+            file("b", Some("2"), Some(0b1111)),
+            file("c", Some("3"), Some(0b1111_1111)),
+        ];
+        let groups = find_duplicates(&files, 4);
+        assert_eq!(groups.len(), 1);
+        assert!(!groups[0].exact);
+        assert_eq!(paths(&groups[0]), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn exact_and_near_groups_are_separate() {
+        let files = vec![
+            file("a", Some("1"), Some(0)),
+            file("a copy", Some("1"), Some(0)),
+            file("a resized", Some("2"), Some(0b11)),
+            file("other", Some("3"), Some(u64::MAX)),
+        ];
+        let groups = find_duplicates(&files, 4);
+        assert_eq!(groups.len(), 2);
+        let exact = groups.iter().find(|g| g.exact).unwrap();
+        assert_eq!(paths(exact), vec!["a", "a copy"]);
+        // -- one file of the identical pair stands for both
+        let near = groups.iter().find(|g| !g.exact).unwrap();
+        assert_eq!(near.files.len(), 2);
+        assert!(paths(near).contains(&"a resized"));
+    }
+
+    #[test]
+    fn files_join_the_nearest_group() {
+        let mut big = file("big", Some("1"), Some(0));
+        big.width = 200;
+        let mut other = file("other", Some("2"), Some(0b1111_1111));
+        other.width = 150;
+        let files = vec![
+            big,
+            other,
+            // -- 3 bits from big, 5 from other
+            file("near big", Some("3"), Some(0b111)),
+            // -- 6 bits from big, 2 from other
+            file("near other", Some("4"), Some(0b11_1111)),
+        ];
+        let groups = find_duplicates(&files, 6);
+        let with = |name: &str| {
+            groups
+                .iter()
+                .find(|g| g.files.iter().any(|f| f.path == name))
+                .map(paths)
+        };
+        assert_eq!(with("big"), Some(vec!["big", "near big"]));
+        assert_eq!(with("other"), Some(vec!["near other", "other"]));
+    }
+
+    #[test]
+    fn files_without_signature_only_group_by_hash() {
+        let files = vec![
+            file("a", Some("1"), None),
+            file("b", Some("1"), None),
+            file("c", None, None),
+        ];
+        let groups = find_duplicates(&files, 24);
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].exact);
+        assert_eq!(paths(&groups[0]), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn suggests_the_biggest_image() {
+        let mut small = file("small", Some("1"), Some(0));
+        small.width = 50;
+        let files = vec![small, file("large", Some("2"), Some(1))];
+        let groups = find_duplicates(&files, 4);
+        assert_eq!(groups[0].files[groups[0].keep].path, "large");
+    }
+}
@@ -1,15 +1,81 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use egui::TextureHandle;
+use image::DynamicImage;
 use ollama_rs::generation::images::Image;
 use schemars::JsonSchema;
 use serde::Deserialize;
 
+use crate::{
+    config::{IMG_DESCRIBE_PROMPT, IMG_LABEL_PROMPT},
+    image_signature::ImageSignature,
+};
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct OllamaTagsResult {
     pub models: Vec<OllamaModel>,
 }
 
+/// Part of the `/api/show` answer, older servers have no `capabilities`.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct OllamaShowResult {
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub projector_info: Option<serde_json::Value>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ModelCapabilities {
+    pub vision: bool,
+    pub embedding: bool,
+    pub tools: bool,
+    // -- reported by /api/show, otherwise guessed from the families
+    pub reported: bool,
+}
+
+impl ModelCapabilities {
+    /// Guess from the model families, used when `/api/show` gives nothing.
+    pub fn from_families(details: &OllamaModelDetail) -> Self {
+        let has = |f: &str| details.families.iter().any(|s| s == f);
+        Self {
+            vision: has("clip") || has("mllama"),
+            embedding: has("bert") || has("nomic-bert"),
+            tools: false,
+            reported: false,
+        }
+    }
+
+    pub fn from_show(show: &OllamaShowResult, details: &OllamaModelDetail) -> Self {
+        if show.capabilities.is_empty() {
+            let mut caps = Self::from_families(details);
+            caps.vision |= show.projector_info.is_some();
+            return caps;
+        }
+        let has = |c: &str| show.capabilities.iter().any(|s| s == c);
+        Self {
+            vision: has("vision"),
+            embedding: has("embedding"),
+            tools: has("tools"),
+            reported: true,
+        }
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = vec![];
+        if self.vision {
+            names.push("vision");
+        }
+        if self.embedding {
+            names.push("embedding");
+        }
+        if self.tools {
+            names.push("tools");
+        }
+        names
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
 pub struct OllamaModelDetail {
     pub parent_model: String,
@@ -25,20 +91,327 @@ pub struct OllamaModel {
     pub name: String,
     pub model: String,
     pub size: u64,
+    #[serde(default)]
+    pub digest: String,
     pub details: OllamaModelDetail,
+    // -- filled from /api/show after the tags are loaded
+    #[serde(default)]
+    pub capabilities: ModelCapabilities,
+}
+
+impl OllamaModel {
+    pub fn is_vision(&self) -> bool {
+        self.capabilities.vision
+    }
+
+    pub fn is_embedding(&self) -> bool {
+        self.capabilities.embedding
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct OllamaVersionResult {
+    pub version: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct OllamaPsResult {
+    #[serde(default)]
+    pub models: Vec<LoadedOllamaModel>,
+}
+
+/// Model loaded in memory, from `/api/ps`.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LoadedOllamaModel {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub size_vram: u64,
+}
+
+/// Answer of a health check of the main Ollama server.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OllamaServerStatus {
+    pub version: String,
+    pub latency_ms: u64,
+    pub loaded: Vec<LoadedOllamaModel>,
+}
+
+/// One line of the `/api/pull` stream.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ModelPullProgress {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub total: u64,
+    #[serde(default)]
+    pub completed: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub done: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct ImageBase64Search {
     pub base64: Image,
     pub path: String,
+    pub original_bytes: u64,
+    pub sent_bytes: u64,
+    pub prepare_ms: u64,
 }
 
-#[derive(JsonSchema, PartialEq, Deserialize, Debug, Clone)]
-pub struct ImageStructured {
-    pub path: String,
+/// How images are prepared before they are sent to the vision model.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct VisionPreprocess {
+    // -- longest side in pixels, 0 keeps the original size
+    pub max_edge: u32,
+    pub jpeg_quality: u8,
+    pub strip_metadata: bool,
+    // -- transparent images are put on white and sent as JPEG, otherwise as PNG
+    pub flatten_alpha: bool,
+}
+
+impl Default for VisionPreprocess {
+    fn default() -> Self {
+        Self {
+            max_edge: 1024,
+            jpeg_quality: 85,
+            strip_metadata: true,
+            flatten_alpha: true,
+        }
+    }
+}
+
+/// How many images are labeled at once and on which Ollama servers.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LabelingSettings {
+    // -- requests in flight at the same time, over all servers
+    pub parallelism: usize,
+    // -- requests started per minute, 0 is unlimited
+    pub rate_limit: u32,
+    // -- servers used next to the main Ollama URL, they need the same vision model
+    pub extra_urls: Vec<String>,
+    pub timeout_secs: u64,
+    // -- attempts after the first one, with exponential backoff
+    pub retries: u32,
+    // -- second pass asking for a paragraph describing every labeled image
+    pub describe: bool,
+}
+
+impl Default for LabelingSettings {
+    fn default() -> Self {
+        Self {
+            parallelism: 1,
+            rate_limit: 0,
+            extra_urls: vec![],
+            timeout_secs: 180,
+            retries: 2,
+            describe: true,
+        }
+    }
+}
+
+/// Semantic search over the labels, caption and description of every image.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SearchSettings {
+    // -- keyword search only while unset
+    pub embedding_model: Option<String>,
+    // -- lowest cosine similarity of a result without keyword match
+    pub threshold: f32,
+    // -- share of the keyword score in the ranking, the rest is similarity
+    pub keyword_weight: f32,
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        Self {
+            embedding_model: None,
+            threshold: 0.5,
+            keyword_weight: 0.3,
+        }
+    }
+}
+
+/// Upload sizes and timings, summed over every image sent to one model.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct VisionUploadStats {
+    pub images: usize,
+    pub original_bytes: u64,
+    pub sent_bytes: u64,
+    pub prepare_ms: u64,
+    pub request_ms: u64,
+}
+
+impl VisionUploadStats {
+    pub fn add(&mut self, other: &VisionUploadStats) {
+        self.images += other.images;
+        self.original_bytes += other.original_bytes;
+        self.sent_bytes += other.sent_bytes;
+        self.prepare_ms += other.prepare_ms;
+        self.request_ms += other.request_ms;
+    }
+}
+
+/// How the answer to a labeling prompt is requested and read.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum AnswerFormat {
+    // -- JSON in the `ImageStructured` schema
+    Structured,
+    // -- free text with comma separated labels
+    LabelList,
+}
+
+/// Preset and its version that labeled a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PresetVersion {
+    pub id: u32,
+    pub version: u32,
+}
+
+/// Where the labels of a file come from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelSource {
+    pub preset: PresetVersion,
+    pub model: String,
+    pub digest: String,
+}
+
+/// Named labeling prompt with its generation options.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct PromptPreset {
+    pub id: u32,
+    // -- raised on every edit of the prompt, so older labels can be redone
+    pub version: u32,
     pub name: String,
-    pub extension: String,
+    pub system: String,
+    pub prompt: String,
+    pub temperature: f32,
+    // -- tokens to generate at most, 0 leaves it to the model
+    pub num_predict: i32,
+    pub format: AnswerFormat,
+}
+
+impl PromptPreset {
+    pub fn version(&self) -> PresetVersion {
+        PresetVersion {
+            id: self.id,
+            version: self.version,
+        }
+    }
+
+    /// Same prompt and options, whatever the name.
+    pub fn same_prompt(&self, other: &PromptPreset) -> bool {
+        self.system == other.system
+            && self.prompt == other.prompt
+            && self.temperature == other.temperature
+            && self.num_predict == other.num_predict
+            && self.format == other.format
+    }
+}
+
+/// Every prompt preset and the one used for labeling.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PromptPresets {
+    pub presets: Vec<PromptPreset>,
+    pub active: u32,
+    pub next_id: u32,
+}
+
+impl Default for PromptPresets {
+    fn default() -> Self {
+        Self {
+            presets: vec![
+                PromptPreset {
+                    id: 0,
+                    version: 1,
+                    name: "Describe (JSON)".to_string(),
+                    system: "".to_string(),
+                    prompt: IMG_DESCRIBE_PROMPT.to_string(),
+                    temperature: 0.0,
+                    num_predict: 0,
+                    format: AnswerFormat::Structured,
+                },
+                PromptPreset {
+                    id: 1,
+                    version: 1,
+                    name: "Simple labels".to_string(),
+                    system: "".to_string(),
+                    prompt: IMG_LABEL_PROMPT.to_string(),
+                    temperature: 0.0,
+                    num_predict: 0,
+                    format: AnswerFormat::LabelList,
+                },
+            ],
+            active: 0,
+            next_id: 2,
+        }
+    }
+}
+
+impl PromptPresets {
+    /// Preset used for labeling, the first one when the active was deleted.
+    pub fn active(&self) -> PromptPreset {
+        self.presets
+            .iter()
+            .find(|p| p.id == self.active)
+            .or(self.presets.first())
+            .cloned()
+            .unwrap_or_else(|| Self::default().presets[0].clone())
+    }
+
+    /// Copy of a preset under a new id, or a new preset from the active one.
+    pub fn add(&mut self, from: Option<u32>, name: String) -> u32 {
+        let mut preset = from
+            .and_then(|id| self.presets.iter().find(|p| p.id == id).cloned())
+            .unwrap_or_else(|| self.active());
+        preset.id = self.next_id;
+        preset.version = 1;
+        preset.name = name;
+        self.next_id += 1;
+        self.presets.push(preset);
+        self.next_id - 1
+    }
+
+    /// Replaces a preset, a changed prompt gets a new version.
+    pub fn save(&mut self, mut preset: PromptPreset) {
+        if let Some(old) = self.presets.iter_mut().find(|p| p.id == preset.id) {
+            if !old.same_prompt(&preset) {
+                preset.version = old.version + 1;
+            }
+            *old = preset;
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if self.presets.len() > 1 {
+            self.presets.retain(|p| p.id != id);
+        }
+        if !self.presets.iter().any(|p| p.id == self.active) {
+            self.active = self.active().id;
+        }
+    }
+}
+
+/// Answer of a vision model, its JSON schema is sent along as the requested format.
+#[derive(JsonSchema, PartialEq, Deserialize, serde::Serialize, Debug, Clone, Default)]
+pub struct ImageStructured {
+    /// Up to 5 main objects or elements in the image, each 1-3 words.
+    pub labels: Vec<String>,
+    /// One sentence describing the image.
+    pub caption: String,
+    /// Text readable in the image, empty when there is none.
+    pub text: String,
+    /// Up to 3 dominant colors as simple color names.
+    pub colors: Vec<String>,
+    /// How sure the model is about the labels, from 0 to 1.
+    pub confidence: f32,
 }
 
 #[derive(serde::Deserialize, Default, serde::Serialize, Debug, Clone)]
@@ -52,13 +425,182 @@ pub struct DirectoryFiles {
 pub struct FileWithLabel {
     pub file: String,
     pub labels: Vec<String>,
+    #[serde(skip)]
+    pub error: Option<FileError>,
+    #[serde(skip)]
+    pub format: String,
+    // -- paragraph from the description pass, else the caption of the labels answer
+    #[serde(skip)]
+    pub description: String,
+}
+
+/// Why a single file could not be read or decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileError {
+    NotFound,
+    PermissionDenied,
+    UnsupportedFormat(String),
+    Corrupt(String),
+    TooLarge,
+    Io(String),
+}
+
+impl FileError {
+    pub fn title(&self) -> &'static str {
+        match self {
+            FileError::NotFound => "not found",
+            FileError::PermissionDenied => "permission denied",
+            FileError::UnsupportedFormat(_) => "unsupported format",
+            FileError::Corrupt(_) => "corrupt data",
+            FileError::TooLarge => "too large",
+            FileError::Io(_) => "read error",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            FileError::UnsupportedFormat(d) | FileError::Corrupt(d) | FileError::Io(d) => {
+                format!("{}: {}", self.title(), d)
+            }
+            _ => self.title().to_string(),
+        }
+    }
+
+    pub fn to_db(&self) -> String {
+        let (code, detail) = match self {
+            FileError::NotFound => ("not_found", ""),
+            FileError::PermissionDenied => ("permission", ""),
+            FileError::UnsupportedFormat(d) => ("unsupported", d.as_str()),
+            FileError::Corrupt(d) => ("corrupt", d.as_str()),
+            FileError::TooLarge => ("too_large", ""),
+            FileError::Io(d) => ("io", d.as_str()),
+        };
+        format!("{}:{}", code, detail)
+    }
+
+    pub fn from_db(value: &str) -> Self {
+        let (code, detail) = value.split_once(':').unwrap_or((value, ""));
+        let detail = detail.to_string();
+        match code {
+            "not_found" => FileError::NotFound,
+            "permission" => FileError::PermissionDenied,
+            "unsupported" => FileError::UnsupportedFormat(detail),
+            "corrupt" => FileError::Corrupt(detail),
+            "too_large" => FileError::TooLarge,
+            _ => FileError::Io(detail),
+        }
+    }
+}
+
+/// Indexed file with what the search query looks at.
+#[derive(Debug, Clone, Default)]
+pub struct SearchDoc {
+    pub path: String,
+    // -- found in the file, see `file_format`
+    pub format: String,
+    pub mtime: i64,
+    // -- `None` until read from the image header
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub labels: Vec<String>,
+    pub caption: String,
+    pub description: String,
+}
+
+/// Indexed file with what tells duplicates apart.
+#[derive(Debug, Clone)]
+pub struct FileFingerprint {
+    pub path: String,
+    pub dir: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    pub hash: Option<String>,
+    pub signature: Option<ImageSignature>,
+    // -- 0 until read when a thumbnail is made
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateFile {
+    pub path: String,
+    pub dir: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    // -- content hash when the group was found, checked again before a deletion
+    pub hash: Option<String>,
+    // -- 0 when not known yet
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Copies of one image, `exact` when they are byte-identical. Near-duplicate
+/// groups hold one file per content, its identical copies are a group of their own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub exact: bool,
+    pub files: Vec<DuplicateFile>,
+    // -- index of the suggested copy to keep
+    pub keep: usize,
+}
+
+/// Difference between a directory on disk and its indexed files after a rescan.
+#[derive(Debug, Clone, Default)]
+pub struct RescanSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // -- (old path, new path), labels are kept
+    pub moved: Vec<(String, String)>,
+    // -- content changed, labels were dropped
+    pub modified: Vec<String>,
+    pub unchanged: usize,
+    // -- every file would be removed, nothing was applied
+    pub unconfirmed: usize,
+}
+
+impl RescanSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+            && self.modified.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        if self.unconfirmed > 0 {
+            return format!("all {} files are gone, not removed yet", self.unconfirmed);
+        }
+        if self.is_empty() {
+            return format!("no changes ({} files)", self.unchanged);
+        }
+        format!(
+            "+{} added, -{} removed, {} moved, {} modified, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.moved.len(),
+            self.modified.len(),
+            self.unchanged
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ThumbnailCacheStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub budget_bytes: u64,
+    // -- thumbnails removed by the last clear/check/eviction
+    pub removed: usize,
 }
 
 #[derive(Clone)]
 pub struct DirectoryImage {
     pub file: String,
     pub labels: Vec<String>,
-    pub texture: TextureHandle,
+    pub description: String,
+    // -- `None` until the thumbnail is decoded
+    pub texture: Option<TextureHandle>,
+    pub error: Option<FileError>,
 }
 
 #[derive(Clone)]
@@ -71,10 +613,21 @@ pub struct DirectoryImages {
 #[derive(Clone)]
 pub enum BroadcastMsg {
     // START -- Ollama settings & state
-    OllamaRunning(Result<(), String>),
+    OllamaRunning(Result<OllamaServerStatus, String>),
+    // -- health check tick, checks run less often while the server is away
     GetOllamaRunning,
+    // -- health of one labeling server, the main one is also sent as `OllamaRunning`
+    ServerRunning(String, Result<OllamaServerStatus, String>),
+    // -- models of an extra labeling server
+    SetServerModels(String, Vec<OllamaModel>),
+    // -- a vision request could not reach its server (URL, file, is a description),
+    // -- the file goes back to the queue without an error
+    VisionServerAway(String, String, bool),
 
     SetOllamaURL(String),
+    GetLabelingSettings,
+    LabelingSettings(LabelingSettings),
+    SetLabelingSettings(LabelingSettings),
     SetOllamaModels(Vec<OllamaModel>),
 
     GetOllamaURL,
@@ -82,19 +635,104 @@ pub enum BroadcastMsg {
 
     GetOllamaModels,
     OllamaModels(Vec<OllamaModel>),
+    RefreshOllamaModels,
+
+    // -- model management, pulling again updates a model
+    PullOllamaModel(String),
+    ModelPullProgress(String, ModelPullProgress),
+    DeleteOllamaModel(String),
 
     // END -- Ollama settings & state
     PickedDirectory(PathBuf),
     RemovedDirectory(PathBuf),
-    DirectoryFiles(PathBuf, Vec<String>),
     DirectoryImages(DirectoryImages),
+    ThumbnailReady(PathBuf, DirectoryImage),
+    ThumbnailProgress(PathBuf, usize, usize),
+    PrioritizeThumbnails(Vec<String>),
+    RescanDirectory(PathBuf),
+    // -- rescan that removes the files even if none is left
+    ConfirmRescanRemoval(PathBuf),
+    DirectoryRescanned(PathBuf, RescanSummary),
+
+    // -- thumbnail cache
+    GetThumbnailCacheStats,
+    ClearThumbnailCache,
+    CheckThumbnailCache,
+    SetThumbnailCacheBudget(u64),
+    ThumbnailCacheStats(ThumbnailCacheStats),
+
+    // -- formats, `None` makes the directory follow the global formats again
+    SetFormats(Vec<String>),
+    SetDirectoryFormats(PathBuf, Option<Vec<String>>),
+
+    // -- watching
+    SetDirectoryWatch(PathBuf, bool),
+    CheckWatchedDirectories,
+    WatchedDirectoryAvailable(PathBuf, bool),
 
     // -- labeling
     StartLabeling,
     StopLabeling,
-    GetLabelsForImage(String, String),
+    GetLabelsForImage(String, ImageStructured, LabelSource),
+    // -- file and the reason once every retry failed, the file stays unlabeled
+    VisionRequestFailed(String, String),
+    RetryFailedLabels,
+    SetVisionModel(String),
+    GetVisionModel,
+    VisionModel(Option<String>),
+    OpenPromptPresets,
+    SetPromptPresets(PromptPresets),
+    // -- clears the labels made by other presets or older versions of the active one
+    RelabelOutdated,
+    GetDescriptionForImage(String, String),
+    // -- file and the reason, the labels of the file are kept
+    DescriptionRequestFailed(String, String),
+    // -- requests in flight and files still queued
+    LabelingProgress(usize, usize),
     FinishLabeling,
 
+    // -- vision uploads
+    // -- model name and its settings
+    SetVisionPreprocess(String, VisionPreprocess),
+    ResetVisionUploadStats,
+    // -- model name and the stats of a single image
+    VisionUploaded(String, VisionUploadStats),
+
     // -- searching
     SearchByLabels(String),
+    // -- images looking like a file, in every directory or in one
+    FindSimilar(String, Option<PathBuf>),
+    // -- signature of a file that had none yet, `None` when it can not be read
+    SignatureReady(String, Option<ImageSignature>),
+    // -- images looking like a region of an image, with a name for it
+    FindSimilarSignature(String, ImageSignature),
+
+    // -- duplicate finder
+    OpenDuplicateFinder,
+    DuplicatesFound(Vec<DuplicateGroup>),
+    DuplicateThumbnail(String, TextureHandle),
+    // -- files deleted or moved and the errors of the others
+    DuplicatesHandled(Vec<String>, Vec<String>),
+
+    // -- image viewer and its crop tool
+    OpenImageViewer(String),
+    ViewerImageLoaded(String, Result<Arc<DynamicImage>, String>),
+    // -- file and the region cropped from it, labeled like the files of a labeling run
+    LabelCrop(String, Arc<DynamicImage>),
+    // -- file and the labels the vision model gave the cropped region
+    CropLabeled(String, Result<Vec<String>, String>),
+    SetSearchSettings(SearchSettings),
+    GetSearchSettings,
+    SearchSettings(SearchSettings),
+    // -- query and its embedding, `None` when it could not be embedded
+    QueryEmbedded(String, Option<Vec<f32>>),
+
+    // -- embeddings of the image texts, made in the background
+    // -- looks for images without an up to date embedding
+    EmbedPending,
+    // -- model and (file, text, vector) of a finished batch
+    EmbeddingsReady(String, Vec<(String, String, Vec<f32>)>),
+    EmbeddingFailed(String),
+    // -- images still waiting for an embedding
+    EmbeddingProgress(usize),
 }
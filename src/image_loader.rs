@@ -0,0 +1,249 @@
+use std::{
+    fs,
+    io::{self, Cursor},
+    panic,
+    path::Path,
+};
+
+use image::{
+    codecs::jpeg::JpegDecoder, metadata::Orientation, DynamicImage, ImageDecoder, ImageError,
+    ImageFormat, ImageReader,
+};
+use qcms::{DataType, Intent, Profile, Transform};
+
+use crate::{
+    config::{AVIF_SUPPORTED, AVIF_UNSUPPORTED_NOTE, MAX_IMAGE_FILE_BYTES},
+    enums::FileError,
+    raw_preview::{extract_preview, is_raw},
+};
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => FileError::NotFound,
+            io::ErrorKind::PermissionDenied => FileError::PermissionDenied,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                FileError::Corrupt(e.to_string())
+            }
+            _ => FileError::Io(e.to_string()),
+        }
+    }
+}
+
+impl From<ImageError> for FileError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::IoError(e) => e.into(),
+            ImageError::Unsupported(e) => FileError::UnsupportedFormat(e.to_string()),
+            ImageError::Limits(_) => FileError::TooLarge,
+            e => FileError::Corrupt(e.to_string()),
+        }
+    }
+}
+
+/// Opens and decodes an image, every failure ends up as a `FileError`.
+///
+/// The image comes out upright (EXIF orientation applied) and in sRGB when it carries
+/// an ICC profile of another colour space.
+pub fn open_image(path: &str) -> Result<DynamicImage, FileError> {
+    let meta = fs::metadata(Path::new(path))?;
+    if meta.len() > MAX_IMAGE_FILE_BYTES {
+        return Err(FileError::TooLarge);
+    }
+
+    // -- some decoders still panic on malformed data
+    let decoded = panic::catch_unwind(|| {
+        // -- RAW files are shown through their embedded JPEG preview
+        if is_raw(Path::new(path)) {
+            let preview = extract_preview(path)?;
+            let camera = preview.orientation.and_then(Orientation::from_exif);
+            let decoder = JpegDecoder::new(Cursor::new(preview.jpeg))?;
+            return decode_upright(decoder, camera);
+        }
+
+        let reader = ImageReader::open(path)?.with_guessed_format()?;
+        if !AVIF_SUPPORTED && reader.format() == Some(ImageFormat::Avif) {
+            return Err(FileError::UnsupportedFormat(
+                AVIF_UNSUPPORTED_NOTE.to_string(),
+            ));
+        }
+        decode_upright(reader.into_decoder()?, None)
+    });
+    decoded.unwrap_or_else(|_| Err(FileError::Corrupt("decoder panicked".to_string())))
+}
+
+/// Width and height of the image as `open_image` returns it, upright and of the RAW
+/// preview, read from the headers only.
+pub fn upright_dimensions(path: &str) -> Result<(u32, u32), FileError> {
+    let read = panic::catch_unwind(|| {
+        if is_raw(Path::new(path)) {
+            let preview = extract_preview(path)?;
+            let camera = preview.orientation.and_then(Orientation::from_exif);
+            let decoder = JpegDecoder::new(Cursor::new(preview.jpeg))?;
+            return Ok(header_dimensions(decoder, camera));
+        }
+        let decoder = ImageReader::open(path)?
+            .with_guessed_format()?
+            .into_decoder()?;
+        Ok(header_dimensions(decoder, None))
+    });
+    read.unwrap_or_else(|_| Err(FileError::Corrupt("decoder panicked".to_string())))
+}
+
+fn header_dimensions(mut decoder: impl ImageDecoder, fallback: Option<Orientation>) -> (u32, u32) {
+    let (width, height) = decoder.dimensions();
+    let orientation = match decoder.orientation() {
+        Ok(Orientation::NoTransforms) | Err(_) => fallback.unwrap_or(Orientation::NoTransforms),
+        Ok(o) => o,
+    };
+    match orientation {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    }
+}
+
+/// Whether an image has to be rotated or colour converted to be shown correctly,
+/// read from its headers only.
+pub fn needs_correction(path: &str) -> bool {
+    let decoder = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(ImageError::from)
+        .and_then(|r| r.into_decoder());
+    match decoder {
+        Ok(mut decoder) => {
+            let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+            let icc = decoder.icc_profile().ok().flatten();
+            orientation != Orientation::NoTransforms
+                || icc.is_some_and(|icc| srgb_transform(&icc, DataType::RGB8).is_some())
+        }
+        Err(_) => false,
+    }
+}
+
+fn decode_upright(
+    mut decoder: impl ImageDecoder,
+    fallback: Option<Orientation>,
+) -> Result<DynamicImage, FileError> {
+    let orientation = match decoder.orientation() {
+        Ok(Orientation::NoTransforms) | Err(_) => fallback.unwrap_or(Orientation::NoTransforms),
+        Ok(o) => o,
+    };
+    let icc = decoder.icc_profile().ok().flatten();
+
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    if let Some(icc) = icc {
+        convert_to_srgb(&mut img, &icc);
+    }
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+// -- `None` for profiles that are sRGB already or cannot be used
+fn srgb_transform(icc: &[u8], data_type: DataType) -> Option<Transform> {
+    let profile = Profile::new_from_slice(icc, false)?;
+    if profile.is_sRGB() {
+        return None;
+    }
+    Transform::new(
+        &profile,
+        &Profile::new_sRGB(),
+        data_type,
+        Intent::Perceptual,
+    )
+}
+
+// -- wide gamut photos (e.g. Display P3) look washed out when shown as sRGB,
+// -- 16 bit, float and gray images are left as they are
+fn convert_to_srgb(img: &mut DynamicImage, icc: &[u8]) {
+    match img {
+        DynamicImage::ImageRgb8(buf) => {
+            if let Some(transform) = srgb_transform(icc, DataType::RGB8) {
+                transform.apply(buf);
+            }
+        }
+        DynamicImage::ImageRgba8(buf) => {
+            if let Some(transform) = srgb_transform(icc, DataType::RGBA8) {
+                transform.apply(buf);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::path::PathBuf;
+
+    // -- left half red, right half blue, so a rotation shows where the halves went
+    fn landscape_jpeg() -> Vec<u8> {
+        let img = RgbImage::from_fn(32, 16, |x, _| match x < 16 {
+            true => Rgb([255, 0, 0]),
+            false => Rgb([0, 0, 255]),
+        });
+        let mut bytes = Cursor::new(vec![]);
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut bytes, ImageFormat::Jpeg)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    // -- an APP1 Exif segment holding only the orientation tag, right after SOI
+    fn with_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(&exif);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    fn fixture(name: &str, bytes: &[u8]) -> String {
+        let dir: PathBuf = std::env::temp_dir().join("desk_imager_image_loader");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn is_red(img: &DynamicImage, x: u32, y: u32) -> bool {
+        let [r, _, b] = img.to_rgb8().get_pixel(x, y).0;
+        r > 200 && b < 60
+    }
+
+    #[test]
+    fn exif_orientation_is_applied() {
+        // -- 6 is "rotate 90 clockwise to show upright"
+        let rotated = fixture("rotated.jpg", &with_orientation(&landscape_jpeg(), 6));
+        assert!(needs_correction(&rotated));
+        assert_eq!(upright_dimensions(&rotated).unwrap(), (16, 32));
+
+        let img = open_image(&rotated).unwrap();
+        assert_eq!((img.width(), img.height()), (16, 32));
+        // -- the left half ends up on top
+        assert!(is_red(&img, 8, 4));
+        assert!(!is_red(&img, 8, 28));
+    }
+
+    #[test]
+    fn images_without_orientation_stay_as_they_are() {
+        let plain = fixture("plain.jpg", &landscape_jpeg());
+        let upright = fixture("upright.jpg", &with_orientation(&landscape_jpeg(), 1));
+        for path in [plain, upright] {
+            assert!(!needs_correction(&path));
+            assert_eq!(upright_dimensions(&path).unwrap(), (32, 16));
+            let img = open_image(&path).unwrap();
+            assert_eq!((img.width(), img.height()), (32, 16));
+            assert!(is_red(&img, 4, 8));
+            assert!(!is_red(&img, 28, 8));
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use image::{imageops::FilterType, DynamicImage};
+
+// -- 4 levels per RGB channel
+const HISTOGRAM_BINS: usize = 64;
+const PHASH_SIZE: usize = 32;
+// -- share of the hashes in the similarity, the rest is the color histogram
+const HASH_WEIGHT: f32 = 0.6;
+
+/// Perceptual signature of an image: difference and DCT hashes for the
+/// structure, a coarse color histogram for the colors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSignature {
+    pub dhash: u64,
+    pub phash: u64,
+    pub histogram: [f32; HISTOGRAM_BINS],
+}
+
+impl ImageSignature {
+    pub fn from_image(img: &DynamicImage) -> Self {
+        Self {
+            dhash: dhash(img),
+            phash: phash(img),
+            histogram: histogram(img),
+        }
+    }
+
+    /// Similarity from 0 (nothing alike) to 1 (same picture).
+    pub fn similarity(&self, other: &ImageSignature) -> f32 {
+        let dhash = 1.0 - (self.dhash ^ other.dhash).count_ones() as f32 / 64.0;
+        let phash = 1.0 - (self.phash ^ other.phash).count_ones() as f32 / 64.0;
+        let colors: f32 = self
+            .histogram
+            .iter()
+            .zip(other.histogram.iter())
+            .map(|(a, b)| a.min(*b))
+            .sum();
+        HASH_WEIGHT * (dhash + phash) / 2.0 + (1.0 - HASH_WEIGHT) * colors
+    }
+
+    /// Differing bits of both hashes, from 0 to 128. Resized or re-encoded
+    /// copies stay within a few bits.
+    pub fn hash_distance(&self, other: &ImageSignature) -> u32 {
+        (self.dhash ^ other.dhash).count_ones() + (self.phash ^ other.phash).count_ones()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + HISTOGRAM_BINS * 4);
+        bytes.extend_from_slice(&self.dhash.to_le_bytes());
+        bytes.extend_from_slice(&self.phash.to_le_bytes());
+        for bin in self.histogram.iter() {
+            bytes.extend_from_slice(&bin.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 16 + HISTOGRAM_BINS * 4 {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let mut histogram = [0.0; HISTOGRAM_BINS];
+        for (i, chunk) in bytes[16..].chunks_exact(4).enumerate() {
+            histogram[i] = f32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Some(Self {
+            dhash: u64_at(0),
+            phash: u64_at(8),
+            histogram,
+        })
+    }
+}
+
+// -- one bit per pixel pair: is the left one brighter than its right neighbour
+fn dhash(img: &DynamicImage) -> u64 {
+    let gray = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = gray.get_pixel(x, y)[0] > gray.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | bit as u64;
+        }
+    }
+    hash
+}
+
+// -- lowest 8x8 frequencies of the DCT compared to their median
+fn phash(img: &DynamicImage) -> u64 {
+    let n = PHASH_SIZE;
+    let gray = img
+        .resize_exact(n as u32, n as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f32> = gray.pixels().map(|p| p[0] as f32).collect();
+
+    let cos: Vec<f32> = (0..8 * n)
+        .map(|i| {
+            let (u, x) = (i / n, i % n);
+            ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / (2 * n) as f32).cos()
+        })
+        .collect();
+    let mut coefficients = [0.0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..n {
+                for x in 0..n {
+                    sum += pixels[y * n + x] * cos[u * n + x] * cos[v * n + y];
+                }
+            }
+            coefficients[v * 8 + u] = sum;
+        }
+    }
+
+    // -- the DC term is the mean brightness and left out of the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+    coefficients
+        .iter()
+        .fold(0u64, |hash, c| (hash << 1) | (*c > median) as u64)
+}
+
+fn histogram(img: &DynamicImage) -> [f32; HISTOGRAM_BINS] {
+    let rgb = img.resize(64, 64, FilterType::Triangle).to_rgb8();
+    let mut histogram = [0.0f32; HISTOGRAM_BINS];
+    for p in rgb.pixels() {
+        let bin = (p[0] as usize >> 6) * 16 + (p[1] as usize >> 6) * 4 + (p[2] as usize >> 6);
+        histogram[bin] += 1.0;
+    }
+    let total = (rgb.width() * rgb.height()).max(1) as f32;
+    for bin in histogram.iter_mut() {
+        *bin /= total;
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, Rgb, RgbImage};
+
+    use super::*;
+
+    // -- the near-duplicate default of the duplicate finder and of the similar search
+    const MAX_DISTANCE: u32 = 6;
+    const MIN_SIMILARITY: f32 = 0.75;
+
+    // -- smooth gradients with a bright disc and a dark bar, like a simple photo
+    fn scene(width: u32, height: u32, disc: (f32, f32), tint: [u8; 3]) -> DynamicImage {
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            let (fx, fy) = (x as f32 / width as f32, y as f32 / height as f32);
+            let (dx, dy) = (fx - disc.0, fy - disc.1);
+            if dx * dx + dy * dy < 0.04 {
+                return Rgb([250, 240, 200]);
+            }
+            if (0.7..0.8).contains(&fy) && fx > 0.2 {
+                return Rgb([20, 20, 30]);
+            }
+            Rgb([
+                (fx * tint[0] as f32) as u8,
+                (fy * tint[1] as f32) as u8,
+                ((1.0 - fx) * tint[2] as f32) as u8,
+            ])
+        });
+        DynamicImage::ImageRgb8(img)
+    }
+
+    fn photo() -> DynamicImage {
+        scene(640, 480, (0.3, 0.35), [200, 160, 255])
+    }
+
+    fn jpeg(img: &DynamicImage, quality: u8) -> DynamicImage {
+        let mut bytes = vec![];
+        img.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))
+            .unwrap();
+        image::load(Cursor::new(bytes), image::ImageFormat::Jpeg).unwrap()
+    }
+
+    #[test]
+    fn resized_copy_is_near() {
+        let original = ImageSignature::from_image(&photo());
+        let resized = ImageSignature::from_image(&photo().resize(200, 150, FilterType::Lanczos3));
+        assert!(original.hash_distance(&resized) <= MAX_DISTANCE);
+        assert!(original.similarity(&resized) >= MIN_SIMILARITY);
+    }
+
+    #[test]
+    fn re_encoded_copy_is_near() {
+        let original = ImageSignature::from_image(&photo());
+        let encoded = ImageSignature::from_image(&jpeg(&photo(), 60));
+        assert!(original.hash_distance(&encoded) <= MAX_DISTANCE);
+        assert!(original.similarity(&encoded) >= MIN_SIMILARITY);
+    }
+
+    #[test]
+    fn unrelated_image_is_far() {
+        let original = ImageSignature::from_image(&photo());
+        let other =
+            ImageSignature::from_image(&scene(640, 480, (0.75, 0.6), [40, 255, 90]).fliph());
+        assert!(original.hash_distance(&other) > 4 * MAX_DISTANCE);
+        assert!(original.similarity(&other) < MIN_SIMILARITY);
+        assert_eq!(original.hash_distance(&original), 0);
+        assert!((original.similarity(&original) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let signature = ImageSignature::from_image(&photo());
+        assert_eq!(
+            ImageSignature::from_bytes(&signature.to_bytes()),
+            Some(signature)
+        );
+        assert_eq!(ImageSignature::from_bytes(&[0; 16]), None);
+    }
+}
@@ -0,0 +1,1566 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    enums::{
+        DirectoryFiles, FileError, FileFingerprint, FileWithLabel, ImageStructured, LabelSource,
+        PresetVersion, RescanSummary, SearchDoc,
+    },
+    image_signature::ImageSignature,
+    utils::{file_content_hash, file_format, project_dirs},
+};
+
+static INDEX_FILE_NAME: &str = "index.sqlite";
+static LEGACY_IMPORTED_KEY: &str = "legacy_app_state_imported";
+
+// -- files whose format is enabled for their directory, ?1 is the global comma list
+const FORMAT_FILTER: &str = "instr(',' || COALESCE(d.formats, ?1) || ',', ',' || f.ext || ',') > 0";
+
+// -- every entry is applied once, `PRAGMA user_version` holds how many already ran
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE directories (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        added_at INTEGER NOT NULL
+    );
+    CREATE TABLE files (
+        id INTEGER PRIMARY KEY,
+        dir_id INTEGER NOT NULL REFERENCES directories(id) ON DELETE CASCADE,
+        path TEXT NOT NULL UNIQUE,
+        size INTEGER NOT NULL DEFAULT 0,
+        mtime INTEGER NOT NULL DEFAULT 0,
+        labeled_at INTEGER,
+        run_id INTEGER REFERENCES labeling_runs(id) ON DELETE SET NULL
+    );
+    CREATE INDEX files_dir_id ON files(dir_id);
+    CREATE TABLE labels (
+        file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+        label TEXT NOT NULL,
+        position INTEGER NOT NULL
+    );
+    CREATE INDEX labels_file_id ON labels(file_id);
+    CREATE TABLE labeling_runs (
+        id INTEGER PRIMARY KEY,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER,
+        labeled INTEGER NOT NULL DEFAULT 0
+    );
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN hash TEXT;
+    CREATE INDEX files_hash ON files(hash);
+"#,
+    r#"
+    ALTER TABLE directories ADD COLUMN watch INTEGER NOT NULL DEFAULT 1;
+"#,
+    r#"
+    CREATE TABLE thumbnails (
+        key TEXT PRIMARY KEY,
+        file TEXT NOT NULL,
+        bytes INTEGER NOT NULL,
+        last_used INTEGER NOT NULL
+    );
+    CREATE INDEX thumbnails_last_used ON thumbnails(last_used);
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN error TEXT;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN ext TEXT;
+    ALTER TABLE directories ADD COLUMN formats TEXT;
+"#,
+    r#"
+    UPDATE files SET ext = NULL;
+    UPDATE directories SET formats = replace(formats, 'jpg', 'jpeg') WHERE formats IS NOT NULL;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN label_error TEXT;
+    ALTER TABLE files ADD COLUMN label_failed_at INTEGER;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN caption TEXT;
+    ALTER TABLE files ADD COLUMN detected_text TEXT;
+    ALTER TABLE files ADD COLUMN colors TEXT;
+    ALTER TABLE files ADD COLUMN confidence REAL;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN description TEXT;
+    ALTER TABLE files ADD COLUMN description_error TEXT;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN preset_id INTEGER;
+    ALTER TABLE files ADD COLUMN preset_version INTEGER;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN model TEXT;
+    ALTER TABLE files ADD COLUMN model_digest TEXT;
+"#,
+    r#"
+    CREATE TABLE embeddings (
+        file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+        model TEXT NOT NULL,
+        text TEXT NOT NULL,
+        vector BLOB NOT NULL,
+        PRIMARY KEY (file_id, model)
+    );
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN signature BLOB;
+"#,
+    r#"
+    ALTER TABLE files ADD COLUMN width INTEGER;
+    ALTER TABLE files ADD COLUMN height INTEGER;
+"#,
+    // -- read again upright and from RAW previews
+    r#"
+    UPDATE files SET width = NULL, height = NULL;
+"#,
+];
+
+/// Embedded SQLite store with every indexed directory, file, label and labeling run.
+///
+/// Cheap to clone, all clones share one connection.
+#[derive(Clone, Default)]
+pub struct IndexDb {
+    conn: Option<Arc<Mutex<Connection>>>,
+    search_docs: Arc<Mutex<SearchDocsCache>>,
+}
+
+// -- `version` goes up after every write the search docs depend on
+#[derive(Default)]
+struct SearchDocsCache {
+    version: u64,
+    read_at: Option<u64>,
+    docs: Arc<Vec<SearchDoc>>,
+}
+
+impl fmt::Debug for IndexDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexDb")
+            .field("open", &self.conn.is_some())
+            .finish()
+    }
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+// -- labels, caption and description as one text, in this order
+fn embedding_text(parts: &[Option<String>]) -> String {
+    parts
+        .iter()
+        .flatten()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>()
+        .join(". ")
+}
+
+pub fn index_dir() -> Option<PathBuf> {
+    project_dirs().map(|p| p.data_dir().to_path_buf())
+}
+
+struct IndexedFile {
+    id: i64,
+    size: i64,
+    mtime: i64,
+    hash: Option<String>,
+    format: Option<String>,
+}
+
+fn file_size_mtime(path: &str) -> (i64, i64) {
+    if let Ok(meta) = fs::metadata(path) {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        return (meta.len() as i64, mtime);
+    }
+    (0, 0)
+}
+
+impl IndexDb {
+    /// Opens the index in the user data dir, falling back to an in-memory one.
+    pub fn open_default() -> Self {
+        let conn = index_dir()
+            .and_then(|dir| fs::create_dir_all(&dir).ok().map(|_| dir))
+            .and_then(|dir| match Connection::open(dir.join(INDEX_FILE_NAME)) {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    println!("{:?} - Error opening index db", e);
+                    None
+                }
+            });
+
+        match conn {
+            Some(c) => Self::from_connection(c),
+            None => Self::open_in_memory(),
+        }
+    }
+
+    pub fn open_in_memory() -> Self {
+        match Connection::open_in_memory() {
+            Ok(c) => Self::from_connection(c),
+            Err(e) => {
+                println!("{:?} - Error opening in-memory index db", e);
+                Self::default()
+            }
+        }
+    }
+
+    fn from_connection(mut conn: Connection) -> Self {
+        if let Err(e) = Self::migrate(&mut conn) {
+            println!("{:?} - Error migrating index db", e);
+        }
+        Self {
+            conn: Some(Arc::new(Mutex::new(conn))),
+            search_docs: Arc::default(),
+        }
+    }
+
+    fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+            // -- a failing statement rolls the whole step back when `tx` is dropped
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Self::backfill_ext(conn)
+    }
+
+    fn backfill_ext(conn: &Connection) -> rusqlite::Result<()> {
+        let mut stmt = conn.prepare("SELECT id, path FROM files WHERE ext IS NULL")?;
+        let rows: Vec<(i64, String)> = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut update = conn.prepare("UPDATE files SET ext = ?1 WHERE id = ?2")?;
+        for (id, path) in rows.iter() {
+            update.execute(params![file_format(path), id])?;
+        }
+        Ok(())
+    }
+
+    fn with_conn<T: Default>(&self, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> T {
+        if let Some(conn) = &self.conn {
+            let mut conn = conn.lock().unwrap();
+            match f(&mut conn) {
+                Ok(v) => return v,
+                Err(e) => println!("{:?} - Index db error", e),
+            }
+        }
+        T::default()
+    }
+
+    fn docs_changed(&self) {
+        self.search_docs.lock().unwrap().version += 1;
+    }
+
+    // -- meta
+
+    fn get_meta(&self, key: &str) -> Option<String> {
+        self.with_conn(|c| {
+            c.query_row("SELECT value FROM meta WHERE key = ?1", [key], |r| r.get(0))
+                .optional()
+        })
+    }
+
+    fn set_meta(&self, key: &str, value: &str) {
+        self.with_conn(|c| {
+            c.execute(
+                "INSERT INTO meta (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                [key, value],
+            )
+            .map(|_| ())
+        })
+    }
+
+    /// One-time import of the directories and labels that used to live in eframe storage.
+    pub fn import_legacy(&self, directories: &[PathBuf], dir_files: &[DirectoryFiles]) {
+        if self.get_meta(LEGACY_IMPORTED_KEY).is_some() {
+            return;
+        }
+        println!(
+            "IMPORTING LEGACY APP STATE: {} dirs, {} dirs with files",
+            directories.len(),
+            dir_files.len()
+        );
+
+        for dir in directories.iter() {
+            self.add_directory(&dir.to_string_lossy());
+        }
+        for dir in dir_files.iter() {
+            let files: Vec<String> = dir
+                .files_with_labels
+                .iter()
+                .map(|f| f.file.clone())
+                .collect();
+            self.insert_files(&dir.dir, &files);
+            for file in dir.files_with_labels.iter() {
+                if !file.labels.is_empty() {
+                    self.set_labels(&file.file, &file.labels, None);
+                }
+            }
+        }
+        self.set_meta(LEGACY_IMPORTED_KEY, &now_secs().to_string());
+    }
+
+    // -- directories
+
+    pub fn add_directory(&self, dir: &str) -> i64 {
+        self.with_conn(|c| {
+            c.execute(
+                "INSERT OR IGNORE INTO directories (path, added_at) VALUES (?1, ?2)",
+                params![dir, now_secs()],
+            )?;
+            c.query_row("SELECT id FROM directories WHERE path = ?1", [dir], |r| {
+                r.get(0)
+            })
+        })
+    }
+
+    pub fn remove_directory(&self, dir: &str) {
+        self.with_conn(|c| {
+            c.execute("DELETE FROM directories WHERE path = ?1", [dir])
+                .map(|_| ())
+        });
+        self.docs_changed();
+    }
+
+    /// Directories with files not hashed yet, e.g. imported ones, a rescan hashes them.
+    pub fn unhashed_directories(&self) -> Vec<PathBuf> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT DISTINCT d.path FROM files f JOIN directories d ON d.id = f.dir_id
+                 WHERE f.hash IS NULL",
+            )?;
+            let rows = stmt.query_map([], |r| Ok(PathBuf::from(r.get::<_, String>(0)?)))?;
+            rows.collect()
+        })
+    }
+
+    pub fn directories(&self) -> Vec<PathBuf> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare("SELECT path FROM directories ORDER BY id")?;
+            let rows = stmt.query_map([], |r| r.get::<_, String>(0))?;
+            rows.map(|r| r.map(PathBuf::from)).collect()
+        })
+    }
+
+    pub fn watched_directories(&self) -> Vec<PathBuf> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare("SELECT path FROM directories WHERE watch = 1 ORDER BY id")?;
+            let rows = stmt.query_map([], |r| r.get::<_, String>(0))?;
+            rows.map(|r| r.map(PathBuf::from)).collect()
+        })
+    }
+
+    pub fn set_directory_watch(&self, dir: &str, watch: bool) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE directories SET watch = ?1 WHERE path = ?2",
+                params![watch, dir],
+            )
+            .map(|_| ())
+        })
+    }
+
+    /// Per directory format overrides, directories without one use the global formats.
+    pub fn directory_formats(&self) -> HashMap<PathBuf, Vec<String>> {
+        self.with_conn(|c| {
+            let mut stmt =
+                c.prepare("SELECT path, formats FROM directories WHERE formats IS NOT NULL")?;
+            let rows = stmt.query_map([], |r| {
+                let formats: String = r.get(1)?;
+                Ok((
+                    PathBuf::from(r.get::<_, String>(0)?),
+                    formats
+                        .split(',')
+                        .filter(|f| !f.is_empty())
+                        .map(String::from)
+                        .collect(),
+                ))
+            })?;
+            rows.collect()
+        })
+    }
+
+    pub fn set_directory_formats(&self, dir: &str, formats: Option<&[String]>) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE directories SET formats = ?1 WHERE path = ?2",
+                params![formats.map(|f| f.join(",")), dir],
+            )
+            .map(|_| ())
+        })
+    }
+
+    // -- files
+
+    /// Adds files to a directory, files already present are left untouched.
+    ///
+    /// They are not hashed, that would hold up a first start with a big library. The
+    /// next rescan of the directory hashes them, see `unhashed_directories`.
+    pub fn insert_files(&self, dir: &str, files: &[String]) {
+        let dir_id = self.add_directory(dir);
+        self.with_conn(|c| {
+            let tx = c.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO files (dir_id, path, size, mtime, ext)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?;
+                for file in files.iter() {
+                    let (size, mtime) = file_size_mtime(file);
+                    stmt.execute(params![dir_id, file, size, mtime, file_format(file)])?;
+                }
+            }
+            tx.commit()
+        });
+        self.docs_changed();
+    }
+
+    /// Diffs the files found on disk against the indexed ones and applies the changes.
+    ///
+    /// Files are matched by path first, a changed size or mtime triggers a content hash
+    /// check. New files whose hash matches an indexed file that is gone from disk are
+    /// treated as moves and keep their labels, modified files lose theirs.
+    ///
+    /// Indexed files of formats that are not searched right now are left alone, so
+    /// disabling a format only hides its files.
+    ///
+    /// `elsewhere` lists the (directory, file) pairs on disk in the other indexed
+    /// directories. It is only called when files are missing, to find the ones moved to
+    /// a directory that was not rescanned yet. Only files of `reachable` directories
+    /// can be moved here from another directory, an unmounted one keeps its files.
+    pub fn rescan_directory(
+        &self,
+        dir: &str,
+        disk_files: &[String],
+        formats: &[String],
+        elsewhere: impl FnOnce() -> Vec<(String, String)>,
+        reachable: &[String],
+        confirmed: bool,
+    ) -> RescanSummary {
+        let dir_id = self.add_directory(dir);
+        let indexed: HashMap<String, IndexedFile> = self.with_conn(|c| {
+            let mut stmt =
+                c.prepare("SELECT path, id, size, mtime, hash, ext FROM files WHERE dir_id = ?1")?;
+            let rows = stmt.query_map([dir_id], |r| {
+                Ok((
+                    r.get(0)?,
+                    IndexedFile {
+                        id: r.get(1)?,
+                        size: r.get(2)?,
+                        mtime: r.get(3)?,
+                        hash: r.get(4)?,
+                        format: r.get(5)?,
+                    },
+                ))
+            })?;
+            rows.collect()
+        });
+
+        let mut summary = RescanSummary::default();
+        // -- (file id, size, mtime, hash, format) to update, `true` drops the labels
+        let mut updates: Vec<(i64, i64, i64, Option<String>, String, bool)> = vec![];
+        let mut candidates: Vec<(String, i64, i64, Option<String>)> = vec![];
+
+        for file in disk_files.iter() {
+            let (size, mtime) = file_size_mtime(file);
+            match indexed.get(file) {
+                Some(i) => {
+                    if size == i.size && mtime == i.mtime && i.hash.is_some() {
+                        summary.unchanged += 1;
+                        continue;
+                    }
+                    let hash = file_content_hash(file);
+                    let modified = i.hash.is_some() && hash != i.hash;
+                    if modified {
+                        summary.modified.push(file.clone());
+                    } else {
+                        summary.unchanged += 1;
+                    }
+                    updates.push((i.id, size, mtime, hash, file_format(file), modified));
+                }
+                None => {
+                    candidates.push((file.clone(), size, mtime, file_content_hash(file)));
+                }
+            }
+        }
+
+        let on_disk: HashSet<&String> = disk_files.iter().collect();
+        let mut missing: HashSet<String> = indexed
+            .iter()
+            .filter(|(p, i)| {
+                !on_disk.contains(p) && i.format.as_ref().is_some_and(|f| formats.contains(f))
+            })
+            .map(|(p, _)| p.clone())
+            .collect();
+        let moved_away = self.find_moved_away(&indexed, &missing, &candidates, elsewhere);
+
+        // -- every file gone is more likely an unmounted drive than a deleted folder,
+        // -- nothing is applied until the user confirms
+        let local: HashSet<&String> = candidates.iter().filter_map(|c| c.3.as_ref()).collect();
+        let removed = missing
+            .iter()
+            .filter(|p| !moved_away.iter().any(|(old, _, _)| old == *p))
+            .filter(|p| {
+                indexed[*p]
+                    .hash
+                    .as_ref()
+                    .map_or(true, |h| !local.contains(h))
+            })
+            .count();
+        let shown = indexed
+            .values()
+            .filter(|i| i.format.as_ref().is_some_and(|f| formats.contains(f)))
+            .count();
+        if !confirmed && removed > 0 && removed == shown {
+            return RescanSummary {
+                unconfirmed: removed,
+                ..Default::default()
+            };
+        }
+
+        self.with_conn(|c| {
+            let tx = c.transaction()?;
+            for (id, size, mtime, hash, format, modified) in updates.iter() {
+                tx.execute(
+                    "UPDATE files SET size = ?1, mtime = ?2, hash = ?3, ext = ?4 WHERE id = ?5",
+                    params![size, mtime, hash, format, id],
+                )?;
+                if *modified {
+                    Self::clear_labels(&tx, *id)?;
+                    tx.execute(
+                        "UPDATE files SET signature = NULL, width = NULL, height = NULL
+                         WHERE id = ?1",
+                        [id],
+                    )?;
+                }
+            }
+
+            for (file, size, mtime, hash) in candidates.iter() {
+                // -- a file with the same content that is no longer on disk was moved here
+                let mut moved_from: Option<(i64, String)> = None;
+                if let Some(hash) = hash {
+                    let mut stmt = tx.prepare_cached(
+                        "SELECT f.id, f.path, d.path FROM files f
+                         JOIN directories d ON d.id = f.dir_id WHERE f.hash = ?1",
+                    )?;
+                    let rows = stmt.query_map([hash], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+                    for row in rows {
+                        let (id, path, from_dir): (i64, String, String) = row?;
+                        let gone_elsewhere =
+                            reachable.contains(&from_dir) && !Path::new(&path).exists();
+                        if missing.contains(&path) || gone_elsewhere {
+                            moved_from = Some((id, path));
+                            break;
+                        }
+                    }
+                }
+
+                match moved_from {
+                    Some((id, old_path)) => {
+                        tx.execute(
+                            "UPDATE files SET dir_id = ?1, path = ?2, size = ?3, mtime = ?4,
+                             ext = ?5 WHERE id = ?6",
+                            params![dir_id, file, size, mtime, file_format(file), id],
+                        )?;
+                        missing.remove(&old_path);
+                        summary.moved.push((old_path, file.clone()));
+                    }
+                    None => {
+                        tx.execute(
+                            "INSERT INTO files (dir_id, path, size, mtime, hash, ext)
+                             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                            params![dir_id, file, size, mtime, hash, file_format(file)],
+                        )?;
+                        summary.added.push(file.clone());
+                    }
+                }
+            }
+
+            for (old_path, other_dir, file) in moved_away.iter() {
+                // -- taken by a file moved inside the directory
+                if !missing.contains(old_path) {
+                    continue;
+                }
+                let dir_id: i64 = tx.query_row(
+                    "SELECT id FROM directories WHERE path = ?1",
+                    [other_dir],
+                    |r| r.get(0),
+                )?;
+                let (size, mtime) = file_size_mtime(file);
+                tx.execute(
+                    "UPDATE files SET dir_id = ?1, path = ?2, size = ?3, mtime = ?4, ext = ?5
+                     WHERE path = ?6",
+                    params![dir_id, file, size, mtime, file_format(file), old_path],
+                )?;
+                missing.remove(old_path);
+                summary.moved.push((old_path.clone(), file.clone()));
+            }
+
+            for path in missing.iter() {
+                tx.execute("DELETE FROM files WHERE path = ?1", [path])?;
+                summary.removed.push(path.clone());
+            }
+            tx.commit()
+        });
+        self.docs_changed();
+
+        summary
+    }
+
+    // -- (old path, directory, new path) of missing files whose content turned up in
+    // -- another indexed directory, matched by size before hashing
+    fn find_moved_away(
+        &self,
+        indexed: &HashMap<String, IndexedFile>,
+        missing: &HashSet<String>,
+        candidates: &[(String, i64, i64, Option<String>)],
+        elsewhere: impl FnOnce() -> Vec<(String, String)>,
+    ) -> Vec<(String, String, String)> {
+        let local: HashSet<&String> = candidates.iter().filter_map(|c| c.3.as_ref()).collect();
+        let mut by_size: HashMap<i64, Vec<(&String, &String)>> = HashMap::new();
+        for path in missing.iter() {
+            if let Some(i) = indexed.get(path) {
+                match i.hash.as_ref() {
+                    Some(hash) if !local.contains(hash) => {
+                        by_size.entry(i.size).or_default().push((path, hash));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if by_size.is_empty() {
+            return vec![];
+        }
+
+        let known: HashSet<String> = self.with_conn(|c| {
+            let mut stmt = c.prepare("SELECT path FROM files")?;
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect()
+        });
+        let mut moved = vec![];
+        for (other_dir, file) in elsewhere() {
+            if known.contains(&file) {
+                continue;
+            }
+            let Some(paths) = by_size.get_mut(&file_size_mtime(&file).0) else {
+                continue;
+            };
+            let Some(hash) = file_content_hash(&file) else {
+                continue;
+            };
+            if let Some(pos) = paths.iter().position(|(_, h)| **h == hash) {
+                let (old_path, _) = paths.remove(pos);
+                moved.push((old_path.clone(), other_dir, file));
+            }
+        }
+        moved
+    }
+
+    fn clear_labels(c: &Connection, file_id: i64) -> rusqlite::Result<()> {
+        c.execute("DELETE FROM labels WHERE file_id = ?1", [file_id])?;
+        c.execute(
+            "UPDATE files SET labeled_at = NULL, run_id = NULL, label_error = NULL,
+             label_failed_at = NULL, caption = NULL, detected_text = NULL, colors = NULL,
+             confidence = NULL, description = NULL, description_error = NULL, preset_id = NULL,
+             preset_version = NULL, model = NULL, model_digest = NULL WHERE id = ?1",
+            [file_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn dir_files(&self, dir: &str) -> DirectoryFiles {
+        let files_with_labels = self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT f.id, f.path, f.error, f.ext, COALESCE(f.description, f.caption)
+                 FROM files f
+                 JOIN directories d ON d.id = f.dir_id
+                 WHERE d.path = ?1 ORDER BY f.path",
+            )?;
+            let rows = stmt.query_map([dir], |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get(1)?,
+                    r.get::<_, Option<String>>(2)?,
+                    r.get::<_, Option<String>>(3)?,
+                    r.get::<_, Option<String>>(4)?,
+                ))
+            })?;
+            let mut files = vec![];
+            for row in rows {
+                let (id, file, error, format, description) = row?;
+                files.push(FileWithLabel {
+                    file,
+                    labels: Self::file_labels(c, id)?,
+                    error: error.map(|e| FileError::from_db(&e)),
+                    format: format.unwrap_or_default(),
+                    description: description.unwrap_or_default(),
+                });
+            }
+            Ok(files)
+        });
+        DirectoryFiles {
+            dir: dir.to_string(),
+            files_with_labels,
+        }
+    }
+
+    pub fn set_file_error(&self, file: &str, error: Option<&FileError>) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET error = ?1 WHERE path = ?2",
+                params![error.map(|e| e.to_db()), file],
+            )
+            .map(|_| ())
+        })
+    }
+
+    fn file_labels(c: &Connection, file_id: i64) -> rusqlite::Result<Vec<String>> {
+        let mut stmt =
+            c.prepare_cached("SELECT label FROM labels WHERE file_id = ?1 ORDER BY position")?;
+        let rows = stmt.query_map([file_id], |r| r.get(0))?;
+        rows.collect()
+    }
+
+    // -- `formats` are the global ones, directory overrides are applied in SQL
+
+    pub fn count_files(&self, formats: &[String]) -> usize {
+        self.with_conn(|c| {
+            c.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM files f JOIN directories d ON d.id = f.dir_id
+                     WHERE {}",
+                    FORMAT_FILTER
+                ),
+                [formats.join(",")],
+                |r| r.get(0),
+            )
+        })
+    }
+
+    pub fn count_unlabeled(&self, formats: &[String]) -> usize {
+        self.with_conn(|c| {
+            c.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM files f JOIN directories d ON d.id = f.dir_id
+                     WHERE f.labeled_at IS NULL AND {}",
+                    FORMAT_FILTER
+                ),
+                [formats.join(",")],
+                |r| r.get(0),
+            )
+        })
+    }
+
+    /// Files to label, the ones that failed before wait for an explicit retry.
+    pub fn unlabeled_files(&self, formats: &[String]) -> Vec<String> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(&format!(
+                "SELECT f.path FROM files f JOIN directories d ON d.id = f.dir_id
+                 WHERE f.labeled_at IS NULL AND f.label_error IS NULL AND {}",
+                FORMAT_FILTER
+            ))?;
+            let rows = stmt.query_map([formats.join(",")], |r| r.get(0))?;
+            rows.collect()
+        })
+    }
+
+    pub fn count_label_failures(&self, formats: &[String]) -> usize {
+        self.with_conn(|c| {
+            c.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM files f JOIN directories d ON d.id = f.dir_id
+                     WHERE f.labeled_at IS NULL AND f.label_error IS NOT NULL AND {}",
+                    FORMAT_FILTER
+                ),
+                [formats.join(",")],
+                |r| r.get(0),
+            )
+        })
+    }
+
+    /// (file, error) of the most recent labeling and description failures.
+    pub fn label_failures(&self, formats: &[String], limit: usize) -> Vec<(String, String)> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(&format!(
+                "SELECT f.path, COALESCE(f.description_error, f.label_error)
+                 FROM files f JOIN directories d ON d.id = f.dir_id
+                 WHERE ((f.labeled_at IS NULL AND f.label_error IS NOT NULL)
+                     OR f.description_error IS NOT NULL) AND {}
+                 ORDER BY f.label_failed_at DESC LIMIT ?2",
+                FORMAT_FILTER
+            ))?;
+            let rows = stmt.query_map(params![formats.join(","), limit as i64], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })?;
+            rows.collect()
+        })
+    }
+
+    pub fn set_label_error(&self, file: &str, error: &str) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET label_error = ?1, label_failed_at = ?2 WHERE path = ?3",
+                params![error, now_secs(), file],
+            )
+            .map(|_| ())
+        })
+    }
+
+    /// Makes every failed file eligible for labeling and describing again.
+    pub fn clear_label_errors(&self) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET label_error = NULL, description_error = NULL,
+                 label_failed_at = NULL
+                 WHERE label_error IS NOT NULL OR description_error IS NOT NULL",
+                [],
+            )
+            .map(|_| ())
+        })
+    }
+
+    // -- descriptions
+
+    /// Labeled files still waiting for their description.
+    pub fn undescribed_files(&self, formats: &[String]) -> Vec<String> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(&format!(
+                "SELECT f.path FROM files f JOIN directories d ON d.id = f.dir_id
+                 WHERE f.labeled_at IS NOT NULL AND f.description IS NULL
+                 AND f.description_error IS NULL AND {}",
+                FORMAT_FILTER
+            ))?;
+            let rows = stmt.query_map([formats.join(",")], |r| r.get(0))?;
+            rows.collect()
+        })
+    }
+
+    pub fn count_undescribed(&self, formats: &[String]) -> usize {
+        self.with_conn(|c| {
+            c.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM files f JOIN directories d ON d.id = f.dir_id
+                     WHERE f.labeled_at IS NOT NULL AND f.description IS NULL
+                     AND f.description_error IS NULL AND {}",
+                    FORMAT_FILTER
+                ),
+                [formats.join(",")],
+                |r| r.get(0),
+            )
+        })
+    }
+
+    pub fn count_description_failures(&self, formats: &[String]) -> usize {
+        self.with_conn(|c| {
+            c.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM files f JOIN directories d ON d.id = f.dir_id
+                     WHERE f.description_error IS NOT NULL AND {}",
+                    FORMAT_FILTER
+                ),
+                [formats.join(",")],
+                |r| r.get(0),
+            )
+        })
+    }
+
+    pub fn set_description(&self, file: &str, description: &str) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET description = ?1, description_error = NULL WHERE path = ?2",
+                params![description, file],
+            )
+            .map(|_| ())
+        });
+        self.docs_changed();
+    }
+
+    pub fn set_description_error(&self, file: &str, error: &str) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET description_error = ?1, label_failed_at = ?2 WHERE path = ?3",
+                params![error, now_secs(), file],
+            )
+            .map(|_| ())
+        })
+    }
+
+    // -- labels
+
+    pub fn set_labels(&self, file: &str, labels: &[String], run_id: Option<i64>) {
+        self.with_conn(|c| {
+            let tx = c.transaction()?;
+            let file_id: Option<i64> = tx
+                .query_row("SELECT id FROM files WHERE path = ?1", [file], |r| r.get(0))
+                .optional()?;
+            if let Some(file_id) = file_id {
+                tx.execute("DELETE FROM labels WHERE file_id = ?1", [file_id])?;
+                {
+                    let mut stmt = tx.prepare(
+                        "INSERT INTO labels (file_id, label, position) VALUES (?1, ?2, ?3)",
+                    )?;
+                    for (i, label) in labels.iter().enumerate() {
+                        stmt.execute(params![file_id, label, i as i64])?;
+                    }
+                }
+                tx.execute(
+                    "UPDATE files SET labeled_at = ?1, run_id = ?2, label_error = NULL,
+                     label_failed_at = NULL WHERE id = ?3",
+                    params![now_secs(), run_id, file_id],
+                )?;
+                if let Some(run_id) = run_id {
+                    tx.execute(
+                        "UPDATE labeling_runs SET labeled = labeled + 1 WHERE id = ?1",
+                        [run_id],
+                    )?;
+                }
+            }
+            tx.commit()
+        });
+        self.docs_changed();
+    }
+
+    /// Stores the rest of a structured answer, the preset and the model that made it.
+    pub fn set_answer_details(&self, file: &str, answer: &ImageStructured, source: &LabelSource) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET caption = ?1, detected_text = ?2, colors = ?3, confidence = ?4,
+                 preset_id = ?5, preset_version = ?6, model = ?7, model_digest = ?8
+                 WHERE path = ?9",
+                params![
+                    answer.caption,
+                    answer.text,
+                    answer.colors.join(","),
+                    answer.confidence,
+                    source.preset.id,
+                    source.preset.version,
+                    source.model,
+                    source.digest,
+                    file
+                ],
+            )
+            .map(|_| ())
+        });
+        self.docs_changed();
+    }
+
+    // -- perceptual signatures
+
+    pub fn has_signature(&self, file: &str) -> bool {
+        self.with_conn(|c| {
+            c.query_row(
+                "SELECT signature IS NOT NULL FROM files WHERE path = ?1",
+                [file],
+                |r| r.get(0),
+            )
+            .optional()
+            .map(|v| v.unwrap_or(false))
+        })
+    }
+
+    pub fn signature(&self, file: &str) -> Option<ImageSignature> {
+        let bytes: Option<Vec<u8>> = self.with_conn(|c| {
+            c.query_row("SELECT signature FROM files WHERE path = ?1", [file], |r| {
+                r.get(0)
+            })
+            .optional()
+            .map(Option::flatten)
+        });
+        ImageSignature::from_bytes(&bytes?)
+    }
+
+    pub fn set_signature(&self, file: &str, signature: &ImageSignature) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET signature = ?1 WHERE path = ?2",
+                params![signature.to_bytes(), file],
+            )
+            .map(|_| ())
+        })
+    }
+
+    // -- image dimensions, 0 when the header could not be read
+
+    pub fn has_dimensions(&self, file: &str) -> bool {
+        self.with_conn(|c| {
+            c.query_row(
+                "SELECT width IS NOT NULL FROM files WHERE path = ?1",
+                [file],
+                |r| r.get(0),
+            )
+            .optional()
+            .map(|v| v.unwrap_or(false))
+        })
+    }
+
+    pub fn set_dimensions(&self, file: &str, width: u32, height: u32) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE files SET width = ?1, height = ?2 WHERE path = ?3",
+                params![width, height, file],
+            )
+            .map(|_| ())
+        });
+        self.docs_changed();
+    }
+
+    /// Every indexed file with its content hash and signature, for the duplicate finder.
+    pub fn fingerprints(&self) -> Vec<FileFingerprint> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT f.path, d.path, f.size, f.mtime, f.hash, f.signature,
+                        COALESCE(f.width, 0), COALESCE(f.height, 0)
+                 FROM files f JOIN directories d ON d.id = f.dir_id",
+            )?;
+            let rows = stmt.query_map([], |r| {
+                let signature: Option<Vec<u8>> = r.get(5)?;
+                Ok(FileFingerprint {
+                    path: r.get(0)?,
+                    dir: PathBuf::from(r.get::<_, String>(1)?),
+                    size: r.get::<_, i64>(2)? as u64,
+                    mtime: r.get(3)?,
+                    hash: r.get(4)?,
+                    signature: signature.and_then(|b| ImageSignature::from_bytes(&b)),
+                    width: r.get(6)?,
+                    height: r.get(7)?,
+                })
+            })?;
+            rows.collect()
+        })
+    }
+
+    /// Signatures of every file, or of the files in one directory.
+    pub fn signatures(&self, dir: Option<&str>) -> Vec<(String, ImageSignature)> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT f.path, f.signature FROM files f JOIN directories d ON d.id = f.dir_id
+                 WHERE f.signature IS NOT NULL AND (?1 IS NULL OR d.path = ?1)",
+            )?;
+            let rows = stmt.query_map([dir], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, Vec<u8>>(1)?))
+            })?;
+            let mut signatures = vec![];
+            for row in rows {
+                let (path, bytes) = row?;
+                if let Some(signature) = ImageSignature::from_bytes(&bytes) {
+                    signatures.push((path, signature));
+                }
+            }
+            Ok(signatures)
+        })
+    }
+
+    // -- embeddings
+
+    /// Labeled files with the text to embed, when it has no embedding of `model`
+    /// or the text changed since.
+    pub fn files_to_embed(&self, model: &str) -> Vec<(String, String)> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT f.path,
+                    (SELECT group_concat(label, ', ') FROM
+                        (SELECT label FROM labels WHERE file_id = f.id ORDER BY position)),
+                    f.caption, f.description, e.text
+                 FROM files f
+                 LEFT JOIN embeddings e ON e.file_id = f.id AND e.model = ?1
+                 WHERE f.labeled_at IS NOT NULL",
+            )?;
+            let rows = stmt.query_map([model], |r| {
+                let parts: [Option<String>; 3] = [r.get(1)?, r.get(2)?, r.get(3)?];
+                let embedded: Option<String> = r.get(4)?;
+                Ok((r.get::<_, String>(0)?, embedding_text(&parts), embedded))
+            })?;
+            let mut files = vec![];
+            for row in rows {
+                let (path, text, embedded) = row?;
+                if !text.is_empty() && embedded.as_ref() != Some(&text) {
+                    files.push((path, text));
+                }
+            }
+            Ok(files)
+        })
+    }
+
+    pub fn set_embeddings(&self, model: &str, embeddings: &[(String, String, Vec<f32>)]) {
+        self.with_conn(|c| {
+            let tx = c.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO embeddings (file_id, model, text, vector)
+                     SELECT id, ?1, ?2, ?3 FROM files WHERE path = ?4
+                     ON CONFLICT(file_id, model) DO UPDATE
+                     SET text = excluded.text, vector = excluded.vector",
+                )?;
+                for (file, text, vector) in embeddings.iter() {
+                    let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+                    stmt.execute(params![model, text, bytes, file])?;
+                }
+            }
+            tx.commit()
+        })
+    }
+
+    /// Every stored embedding of `model`, by file.
+    pub fn embeddings(&self, model: &str) -> Vec<(String, Vec<f32>)> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT f.path, e.vector FROM embeddings e JOIN files f ON f.id = e.file_id
+                 WHERE e.model = ?1 AND f.labeled_at IS NOT NULL",
+            )?;
+            let rows = stmt.query_map([model], |r| {
+                let bytes: Vec<u8> = r.get(1)?;
+                let vector = bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                Ok((r.get(0)?, vector))
+            })?;
+            rows.collect()
+        })
+    }
+
+    /// Labeled files per preset version, files labeled before presets are not counted.
+    pub fn count_labeled_by_preset(&self, formats: &[String]) -> HashMap<(u32, u32), usize> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare(&format!(
+                "SELECT f.preset_id, f.preset_version, COUNT(*)
+                 FROM files f JOIN directories d ON d.id = f.dir_id
+                 WHERE f.labeled_at IS NOT NULL AND f.preset_id IS NOT NULL AND {}
+                 GROUP BY f.preset_id, f.preset_version",
+                FORMAT_FILTER
+            ))?;
+            let rows = stmt.query_map([formats.join(",")], |r| {
+                Ok(((r.get(0)?, r.get(1)?), r.get(2)?))
+            })?;
+            rows.collect()
+        })
+    }
+
+    /// Clears the labels made by any other preset (or version), they are labeled again
+    /// by the next labeling run.
+    pub fn clear_outdated_labels(&self, preset: PresetVersion, formats: &[String]) -> usize {
+        let cleared = self.with_conn(|c| {
+            let tx = c.transaction()?;
+            let ids: Vec<i64> = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT f.id FROM files f JOIN directories d ON d.id = f.dir_id
+                     WHERE f.labeled_at IS NOT NULL AND (f.preset_id IS NULL
+                         OR f.preset_id != ?2 OR f.preset_version != ?3) AND {}",
+                    FORMAT_FILTER
+                ))?;
+                let rows = stmt
+                    .query_map(params![formats.join(","), preset.id, preset.version], |r| {
+                        r.get(0)
+                    })?;
+                rows.collect::<rusqlite::Result<_>>()?
+            };
+            for id in ids.iter() {
+                Self::clear_labels(&tx, *id)?;
+            }
+            tx.commit()?;
+            Ok(ids.len())
+        });
+        self.docs_changed();
+        cleared
+    }
+
+    /// Every indexed file with what the search query can filter on.
+    ///
+    /// Read again only after the index changed.
+    pub fn search_docs(&self) -> Arc<Vec<SearchDoc>> {
+        let version = {
+            let cache = self.search_docs.lock().unwrap();
+            if cache.read_at == Some(cache.version) {
+                return cache.docs.clone();
+            }
+            cache.version
+        };
+        let docs: Vec<SearchDoc> = self.with_conn(|c| {
+            let mut stmt = c.prepare(
+                "SELECT f.path, f.mtime, f.width, f.height, f.caption, f.description,
+                     (SELECT group_concat(label, char(31)) FROM
+                         (SELECT label FROM labels WHERE file_id = f.id ORDER BY position)),
+                     f.ext
+                 FROM files f",
+            )?;
+            let rows = stmt.query_map([], |r| {
+                let labels: Option<String> = r.get(6)?;
+                let width: Option<u32> = r.get(2)?;
+                let height: Option<u32> = r.get(3)?;
+                Ok(SearchDoc {
+                    path: r.get(0)?,
+                    mtime: r.get(1)?,
+                    width: width.filter(|w| *w > 0),
+                    height: height.filter(|h| *h > 0),
+                    labels: labels
+                        .map(|l| l.split('\u{1f}').map(String::from).collect())
+                        .unwrap_or_default(),
+                    caption: r.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                    description: r.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                    format: r.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                })
+            })?;
+            rows.collect()
+        });
+        let docs = Arc::new(docs);
+
+        // -- a write during the read leaves the version behind, so it is read again
+        let mut cache = self.search_docs.lock().unwrap();
+        if cache.version == version {
+            cache.read_at = Some(version);
+            cache.docs = docs.clone();
+        }
+        docs
+    }
+
+    // -- labeling runs
+
+    pub fn start_labeling_run(&self) -> Option<i64> {
+        self.with_conn(|c| {
+            c.execute(
+                "INSERT INTO labeling_runs (started_at) VALUES (?1)",
+                [now_secs()],
+            )?;
+            Ok(Some(c.last_insert_rowid()))
+        })
+    }
+
+    pub fn finish_labeling_run(&self, run_id: i64) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE labeling_runs SET finished_at = ?1 WHERE id = ?2",
+                params![now_secs(), run_id],
+            )
+            .map(|_| ())
+        })
+    }
+
+    // -- thumbnail cache
+
+    pub fn add_thumbnail(&self, key: &str, file: &str, bytes: u64) {
+        self.with_conn(|c| {
+            c.execute(
+                "INSERT INTO thumbnails (key, file, bytes, last_used) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(key) DO UPDATE SET bytes = excluded.bytes, last_used = excluded.last_used",
+                params![key, file, bytes as i64, now_secs()],
+            )
+            .map(|_| ())
+        })
+    }
+
+    pub fn touch_thumbnail(&self, key: &str) {
+        self.with_conn(|c| {
+            c.execute(
+                "UPDATE thumbnails SET last_used = ?1 WHERE key = ?2",
+                params![now_secs(), key],
+            )
+            .map(|_| ())
+        })
+    }
+
+    pub fn thumbnails_size(&self) -> u64 {
+        self.with_conn(|c| {
+            c.query_row("SELECT COALESCE(SUM(bytes), 0) FROM thumbnails", [], |r| {
+                r.get::<_, i64>(0)
+            })
+        }) as u64
+    }
+
+    pub fn thumbnails_count(&self) -> usize {
+        self.with_conn(|c| c.query_row("SELECT COUNT(*) FROM thumbnails", [], |r| r.get(0)))
+    }
+
+    /// (key, bytes) of all cached thumbnails, least recently used first.
+    pub fn thumbnails_by_last_use(&self) -> Vec<(String, u64)> {
+        self.with_conn(|c| {
+            let mut stmt = c.prepare("SELECT key, bytes FROM thumbnails ORDER BY last_used")?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get::<_, i64>(1)? as u64)))?;
+            rows.collect()
+        })
+    }
+
+    /// Thumbnails whose source file is no longer indexed.
+    pub fn orphan_thumbnails(&self) -> Vec<String> {
+        self.with_conn(|c| {
+            let mut stmt =
+                c.prepare("SELECT key FROM thumbnails WHERE file NOT IN (SELECT path FROM files)")?;
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect()
+        })
+    }
+
+    pub fn remove_thumbnails(&self, keys: &[String]) {
+        self.with_conn(|c| {
+            let tx = c.transaction()?;
+            {
+                let mut stmt = tx.prepare("DELETE FROM thumbnails WHERE key = ?1")?;
+                for key in keys.iter() {
+                    stmt.execute([key])?;
+                }
+            }
+            tx.commit()
+        })
+    }
+
+    pub fn clear_thumbnails(&self) {
+        self.with_conn(|c| c.execute("DELETE FROM thumbnails", []).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: &[&str] = &["png"];
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("desk_imager_index_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn rescan(index: &IndexDb, dir: &Path, reachable: &[String], confirmed: bool) -> RescanSummary {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| e.path().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        let formats: Vec<String> = FORMATS.iter().map(|f| f.to_string()).collect();
+        let dir = dir.to_string_lossy();
+        index.rescan_directory(&dir, &files, &formats, Vec::new, reachable, confirmed)
+    }
+
+    fn labels(index: &IndexDb, dir: &Path, file: &str) -> Option<Vec<String>> {
+        index
+            .dir_files(&dir.to_string_lossy())
+            .files_with_labels
+            .into_iter()
+            .find(|f| f.file == file)
+            .map(|f| f.labels)
+    }
+
+    #[test]
+    fn rescan_finds_added_removed_moved_and_modified_files() {
+        let dir = temp_dir("rescan");
+        let index = IndexDb::open_in_memory();
+        let a = write(&dir, "a.png", "content a");
+        let b = write(&dir, "b.png", "content b");
+        let c = write(&dir, "c.png", "content c");
+        let summary = rescan(&index, &dir, &[], false);
+        assert_eq!(summary.added.len(), 3);
+        index.set_labels(&b, &["boat".to_string()], None);
+        index.set_labels(&c, &["cat".to_string()], None);
+
+        fs::remove_file(&a).unwrap();
+        write(&dir, "b.png", "content b, edited");
+        let d = dir.join("d.png").to_string_lossy().to_string();
+        fs::rename(&c, &d).unwrap();
+        let e = write(&dir, "e.png", "content e");
+
+        let summary = rescan(&index, &dir, &[], false);
+        assert_eq!(summary.added, vec![e]);
+        assert_eq!(summary.removed, vec![a]);
+        assert_eq!(summary.moved, vec![(c, d.clone())]);
+        assert_eq!(summary.modified, vec![b.clone()]);
+        assert_eq!(labels(&index, &dir, &b), Some(vec![]));
+        assert_eq!(labels(&index, &dir, &d), Some(vec!["cat".to_string()]));
+    }
+
+    #[test]
+    fn rescan_asks_before_removing_every_file() {
+        let dir = temp_dir("unconfirmed");
+        let index = IndexDb::open_in_memory();
+        let a = write(&dir, "a.png", "content a");
+        write(&dir, "b.png", "content b");
+        rescan(&index, &dir, &[], false);
+        index.set_labels(&a, &["apple".to_string()], None);
+
+        fs::remove_dir_all(&dir).unwrap();
+        let summary = rescan(&index, &dir, &[], false);
+        assert_eq!(summary.unconfirmed, 2);
+        assert!(summary.removed.is_empty());
+        assert_eq!(labels(&index, &dir, &a), Some(vec!["apple".to_string()]));
+
+        let summary = rescan(&index, &dir, &[], true);
+        assert_eq!(summary.removed.len(), 2);
+        assert_eq!(labels(&index, &dir, &a), None);
+    }
+
+    #[test]
+    fn rescan_takes_files_over_only_from_reachable_directories() {
+        let here = temp_dir("moved_here");
+        let away = temp_dir("moved_away");
+        let index = IndexDb::open_in_memory();
+        let old = write(&away, "x.png", "content x");
+        rescan(&index, &away, &[], false);
+        index.set_labels(&old, &["fox".to_string()], None);
+
+        // -- `away` is unmounted, its file is not moved
+        fs::remove_dir_all(&away).unwrap();
+        let new = write(&here, "x.png", "content x");
+        let summary = rescan(&index, &here, &[], false);
+        assert_eq!(summary.added, vec![new.clone()]);
+        assert!(summary.moved.is_empty());
+        assert_eq!(labels(&index, &away, &old), Some(vec!["fox".to_string()]));
+
+        // -- reachable but without the file, it was moved
+        fs::remove_file(&new).unwrap();
+        rescan(&index, &here, &[], true);
+        fs::create_dir_all(&away).unwrap();
+        let new = write(&here, "x.png", "content x");
+        let reachable = vec![away.to_string_lossy().to_string()];
+        let summary = rescan(&index, &here, &reachable, false);
+        assert_eq!(summary.moved, vec![(old.clone(), new.clone())]);
+        assert_eq!(labels(&index, &here, &new), Some(vec!["fox".to_string()]));
+        assert_eq!(labels(&index, &away, &old), None);
+    }
+
+    #[test]
+    fn migrations_upgrade_an_old_index() {
+        let conn = Connection::open_in_memory().unwrap();
+        // -- an index from before formats were sniffed, `jpg` was still an extension
+        for sql in MIGRATIONS[..6].iter() {
+            conn.execute_batch(sql).unwrap();
+        }
+        conn.execute_batch(
+            "PRAGMA user_version = 6;
+             INSERT INTO directories (id, path, added_at, formats) VALUES (1, '/photos', 0, 'jpg,png');
+             INSERT INTO files (id, dir_id, path, ext) VALUES (1, 1, '/photos/a.jpg', 'jpg');
+             INSERT INTO labels (file_id, label, position) VALUES (1, 'dog', 0);",
+        )
+        .unwrap();
+
+        let index = IndexDb::from_connection(conn);
+        let version: usize =
+            index.with_conn(|c| c.query_row("PRAGMA user_version", [], |r| r.get(0)));
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(
+            index.directory_formats().get(Path::new("/photos")),
+            Some(&vec!["jpeg".to_string(), "png".to_string()])
+        );
+        let files = index.dir_files("/photos").files_with_labels;
+        assert_eq!(files[0].format, "jpeg");
+        assert_eq!(files[0].labels, vec!["dog".to_string()]);
+
+        // -- opening it again applies nothing twice
+        let conn = Arc::try_unwrap(index.conn.unwrap()).ok().unwrap();
+        let index = IndexDb::from_connection(conn.into_inner().unwrap());
+        assert_eq!(index.dir_files("/photos").files_with_labels.len(), 1);
+    }
+
+    #[test]
+    fn import_legacy_keeps_labels_once() {
+        let dir = temp_dir("legacy");
+        let a = write(&dir, "a.png", "content a");
+        let b = write(&dir, "b.png", "content b");
+        let dir_string = dir.to_string_lossy().to_string();
+        let legacy = vec![DirectoryFiles {
+            dir: dir_string.clone(),
+            files_with_labels: vec![
+                FileWithLabel {
+                    file: a.clone(),
+                    labels: vec!["apple".to_string(), "red".to_string()],
+                    ..Default::default()
+                },
+                FileWithLabel {
+                    file: b.clone(),
+                    ..Default::default()
+                },
+            ],
+        }];
+
+        let directories = vec![dir.clone()];
+        let index = IndexDb::open_in_memory();
+        index.import_legacy(&directories, &legacy);
+        assert_eq!(index.directories(), vec![dir.clone()]);
+        assert_eq!(
+            labels(&index, &dir, &a),
+            Some(vec!["apple".to_string(), "red".to_string()])
+        );
+        assert_eq!(labels(&index, &dir, &b), Some(vec![]));
+        // -- hashed by the first rescan, which keeps the labels
+        assert_eq!(index.unhashed_directories(), vec![dir.clone()]);
+        let summary = rescan(&index, &dir, &[], false);
+        assert!(summary.is_empty());
+        assert!(index.unhashed_directories().is_empty());
+        assert_eq!(labels(&index, &dir, &a).map(|l| l.len()), Some(2));
+
+        index.set_labels(&a, &["pear".to_string()], None);
+        index.import_legacy(&directories, &legacy);
+        assert_eq!(labels(&index, &dir, &a), Some(vec!["pear".to_string()]));
+    }
+
+    #[test]
+    fn search_docs_are_read_again_after_a_change() {
+        let index = IndexDb::open_in_memory();
+        index.insert_files("/photos", &["/photos/a.jpg".to_string()]);
+
+        let docs = index.search_docs();
+        assert_eq!(docs.len(), 1);
+        assert!(Arc::ptr_eq(&docs, &index.search_docs()));
+
+        index.set_labels("/photos/a.jpg", &["cat".to_string()], None);
+        let docs = index.search_docs();
+        assert_eq!(docs[0].labels, vec!["cat".to_string()]);
+
+        index.remove_directory("/photos");
+        assert!(index.search_docs().is_empty());
+    }
+
+    #[test]
+    fn search_doc_labels_keep_their_position() {
+        let index = IndexDb::open_in_memory();
+        index.insert_files("/photos", &["/photos/a.jpg".to_string()]);
+        // -- rows stored out of position order
+        index.with_conn(|c| {
+            for (label, position) in [("mango", 2), ("apple", 1), ("zebra", 0)] {
+                c.execute(
+                    "INSERT INTO labels (file_id, label, position)
+                     SELECT id, ?1, ?2 FROM files WHERE path = '/photos/a.jpg'",
+                    params![label, position],
+                )?;
+            }
+            Ok(())
+        });
+        index.docs_changed();
+
+        assert_eq!(index.search_docs()[0].labels, ["zebra", "apple", "mango"]);
+    }
+}
@@ -4,8 +4,16 @@ mod app;
 mod app_state;
 mod components;
 mod config;
+mod duplicates;
 mod enums;
+mod image_loader;
+mod image_signature;
+mod index_db;
 mod ollama_state;
+mod query;
+mod raw_preview;
+mod thumbnail_cache;
+mod thumbnail_pipeline;
 mod utils;
 
 pub use app::DeskApp;
@@ -1,23 +1,87 @@
 use crate::{
-    enums::{BroadcastMsg, OllamaModel, OllamaTagsResult},
+    enums::{
+        BroadcastMsg, LabelingSettings, ModelCapabilities, ModelPullProgress, OllamaModel,
+        OllamaPsResult, OllamaServerStatus, OllamaShowResult, OllamaTagsResult,
+        OllamaVersionResult, PromptPresets, SearchSettings, VisionPreprocess, VisionUploadStats,
+    },
     utils::spawn,
 };
 use futures::TryFutureExt;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::UnboundedSender;
 
+const HEALTH_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default, Debug, Clone)]
+struct HealthMonitor {
+    checking: bool,
+    next_check: Option<Instant>,
+    // -- failed checks in a row
+    failures: u32,
+    running: Option<bool>,
+}
+
+impl HealthMonitor {
+    // -- a finished check, the next one backs off while the server stays away
+    fn checked(&mut self, running: bool, now: Instant) -> Option<bool> {
+        self.checking = false;
+        let was_running = self.running.replace(running);
+        match running {
+            true => self.failures = 0,
+            false => self.failures += 1,
+        }
+        self.next_check = Some(now + self.interval());
+        was_running
+    }
+
+    // -- a request found the server away, a check on its way still counts the failure
+    fn seen_away(&mut self, now: Instant) {
+        self.running = Some(false);
+        self.next_check = Some(now + self.interval());
+    }
+
+    fn interval(&self) -> Duration {
+        (HEALTH_INTERVAL * 2u32.pow(self.failures.min(5))).min(MAX_HEALTH_INTERVAL)
+    }
+}
+
 #[derive(serde::Deserialize, Default, serde::Serialize, Debug, Clone)]
 pub struct OllamaState {
     #[serde(skip)]
     action_tx: Option<UnboundedSender<BroadcastMsg>>,
     pub url: String,
+    #[serde(default)]
+    pub labeling: LabelingSettings,
+    #[serde(default)]
+    pub prompts: PromptPresets,
+    // -- chosen by the user, the first vision model until then
+    #[serde(default)]
+    pub vision_model: Option<String>,
+    #[serde(default)]
+    pub search: SearchSettings,
+    // -- image preparation and upload statistics, per vision model
+    #[serde(default)]
+    pub vision_preprocess: BTreeMap<String, VisionPreprocess>,
+    #[serde(default)]
+    pub upload_stats: BTreeMap<String, VisionUploadStats>,
     #[serde(skip)]
     pub models: Vec<OllamaModel>,
+    // -- models of the extra labeling servers, the main one's are `models`
+    #[serde(skip)]
+    pub server_models: HashMap<String, Vec<OllamaModel>>,
+    // -- per server URL, the main one included
+    #[serde(skip)]
+    health: HashMap<String, HealthMonitor>,
 }
 
+#[allow(dead_code)]
 static OLLAMA_STATE_KEY: &str = "ollama_state";
 
 impl OllamaState {
-    pub fn new(cc: &eframe::CreationContext<'_>, url: String) -> Self {
+    pub fn new(_cc: &eframe::CreationContext<'_>, url: String) -> Self {
         // -- get storage values
         // if let Some(storage) = cc.storage {
         //     return eframe::get_value(storage, OLLAMA_STATE_KEY).unwrap_or_default();
@@ -32,33 +96,110 @@ impl OllamaState {
         Self {
             action_tx: None,
             url,
+            labeling: LabelingSettings::default(),
+            prompts: PromptPresets::default(),
+            vision_model: None,
+            search: SearchSettings::default(),
+            vision_preprocess: BTreeMap::new(),
+            upload_stats: BTreeMap::new(),
             models: vec![],
+            server_models: HashMap::new(),
+            health: HashMap::new(),
         }
     }
 
-    pub fn save(&mut self, storage: &mut dyn eframe::Storage) {
+    pub fn save(&mut self, _storage: &mut dyn eframe::Storage) {
         // eframe::set_value(storage, OLLAMA_STATE_KEY, self);
     }
 
     pub fn init(&mut self) {
-        self.send_check_ollama_url();
+        self.check_servers();
         self.send_get_tags();
     }
 
+    /// Main URL first, then the extra labeling servers.
+    pub fn labeling_urls(&self) -> Vec<String> {
+        let mut urls = vec![self.url.clone()];
+        for url in self.labeling.extra_urls.iter() {
+            let url = url.trim().trim_end_matches('/').to_string();
+            if !url.is_empty() && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+
+    /// How images are prepared for `model`, the defaults until the user changes them.
+    pub fn preprocess(&self, model: &str) -> VisionPreprocess {
+        self.vision_preprocess
+            .get(model)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Labeling servers that are not known to be away and have the chosen vision model.
+    pub fn labeling_servers(&self) -> Vec<String> {
+        let Some(name) = self.vision_model.as_ref() else {
+            return vec![];
+        };
+        self.labeling_urls()
+            .into_iter()
+            .filter(|url| self.health.get(url).and_then(|h| h.running) != Some(false))
+            .filter(|url| {
+                let models = match *url == self.url {
+                    true => Some(&self.models),
+                    false => self.server_models.get(url),
+                };
+                models.is_some_and(|m| m.iter().any(|m| m.name == *name))
+            })
+            .collect()
+    }
+
+    pub fn any_server_up(&self) -> bool {
+        self.labeling_urls()
+            .iter()
+            .any(|url| self.health.get(url).and_then(|h| h.running) != Some(false))
+    }
+
     pub fn get_vision_models(&self) -> Vec<OllamaModel> {
         // let models = self.models.iter().filter(|m| m.details.families.)
 
         let mut models = vec![];
         for m in self.models.clone() {
-            if m.details.families.contains(&"clip".to_string())
-                || m.details.families.contains(&"mllama".to_string())
-            {
+            if m.is_vision() {
                 models.push(m);
             }
         }
         models
     }
 
+    /// Chosen vision model, `None` while it is not available on any labeling server.
+    pub fn labeling_model(&self) -> Option<OllamaModel> {
+        let name = self.vision_model.as_ref()?;
+        self.models
+            .iter()
+            .chain(self.server_models.values().flatten())
+            .find(|m| m.name == *name)
+            .cloned()
+    }
+
+    // -- the first vision model is kept, so a new tag order does not change it
+    fn check_vision_model(&mut self) {
+        match self.vision_model.clone() {
+            None => {
+                self.vision_model = self.get_vision_models().first().map(|m| m.name.clone());
+                if let Some(tx) = self.action_tx.clone() {
+                    let _ = tx.send(BroadcastMsg::VisionModel(self.vision_model.clone()));
+                }
+            }
+            Some(name) => {
+                if !self.models.iter().any(|m| m.name == name) {
+                    println!("LABELING MODEL {} IS NOT AVAILABLE", name);
+                }
+            }
+        }
+    }
+
     pub fn update(&mut self, msg: BroadcastMsg) {
         let action_tx = self.action_tx.clone();
         match msg {
@@ -70,12 +211,82 @@ impl OllamaState {
                 if let Some(tx) = action_tx {
                     let _ = tx.send(BroadcastMsg::OllamaModels(self.models.clone()));
                 }
+                self.check_vision_model();
+            }
+            BroadcastMsg::SetVisionModel(name) => {
+                self.vision_model = Some(name);
+            }
+            BroadcastMsg::GetVisionModel => {
+                if let Some(tx) = action_tx {
+                    let _ = tx.send(BroadcastMsg::VisionModel(self.vision_model.clone()));
+                }
+            }
+            BroadcastMsg::SetVisionPreprocess(model, settings) => {
+                self.vision_preprocess.insert(model, settings);
+            }
+            BroadcastMsg::VisionUploaded(model, stats) => {
+                self.upload_stats.entry(model).or_default().add(&stats);
+            }
+            BroadcastMsg::ResetVisionUploadStats => {
+                self.upload_stats.clear();
             }
             BroadcastMsg::GetOllamaURL => {
                 if let Some(tx) = action_tx {
                     let _ = tx.send(BroadcastMsg::OllamaURL(self.url.clone()));
                 }
             }
+            BroadcastMsg::SetLabelingSettings(settings) => {
+                self.labeling = settings;
+                let urls = self.labeling_urls();
+                self.health.retain(|url, _| urls.contains(url));
+                self.server_models.retain(|url, _| urls.contains(url));
+                self.check_servers();
+            }
+            BroadcastMsg::SetPromptPresets(prompts) => {
+                self.prompts = prompts;
+            }
+            BroadcastMsg::GetLabelingSettings => {
+                if let Some(tx) = action_tx {
+                    let _ = tx.send(BroadcastMsg::LabelingSettings(self.labeling.clone()));
+                }
+            }
+            BroadcastMsg::GetOllamaRunning => {
+                self.check_servers();
+            }
+            BroadcastMsg::ServerRunning(url, status) => {
+                self.health_checked(&url, status.is_ok());
+                if url == self.url {
+                    if let Some(tx) = action_tx {
+                        let _ = tx.send(BroadcastMsg::OllamaRunning(status));
+                    }
+                }
+            }
+            BroadcastMsg::VisionServerAway(url, _, _) => {
+                // -- labeling pauses on it until a health check finds it back
+                if let Some(health) = self.health.get_mut(&url) {
+                    health.seen_away(Instant::now());
+                }
+            }
+            BroadcastMsg::SetServerModels(url, models) => {
+                self.server_models.insert(url, models);
+            }
+            BroadcastMsg::SetSearchSettings(settings) => {
+                self.search = settings;
+            }
+            BroadcastMsg::GetSearchSettings => {
+                if let Some(tx) = action_tx {
+                    let _ = tx.send(BroadcastMsg::SearchSettings(self.search.clone()));
+                }
+            }
+            BroadcastMsg::RefreshOllamaModels => {
+                self.send_get_tags();
+            }
+            BroadcastMsg::PullOllamaModel(name) => {
+                spawn(Self::pull_model(self.url.clone(), name, action_tx));
+            }
+            BroadcastMsg::DeleteOllamaModel(name) => {
+                spawn(Self::delete_model(self.url.clone(), name, action_tx));
+            }
             BroadcastMsg::GetOllamaModels => {
                 if let Some(tx) = action_tx {
                     let _ = tx.send(BroadcastMsg::OllamaModels(self.models.clone()));
@@ -85,29 +296,69 @@ impl OllamaState {
         }
     }
 
-    fn send_check_ollama_url(&mut self) {
-        spawn(Self::check_ollama_url(
-            self.url.clone(),
-            self.action_tx.clone(),
-        ));
+    // -- every labeling server is checked on its own schedule
+    fn check_servers(&mut self) {
+        for url in self.labeling_urls() {
+            let health = self.health.entry(url.clone()).or_default();
+            let due = match health.next_check {
+                Some(next) => Instant::now() >= next,
+                None => true,
+            };
+            if due && !health.checking {
+                health.checking = true;
+                spawn(Self::check_health(url, self.action_tx.clone()));
+            }
+        }
     }
 
-    async fn check_ollama_url(url: String, action_tx: Option<UnboundedSender<BroadcastMsg>>) {
-        let ollama_status = reqwest::get(url).and_then(reqwest::Response::text).await;
-        match ollama_status {
-            Ok(s) => {
-                if s == "Ollama is running" {
-                    if let Some(tx) = action_tx {
-                        let _ = tx.send(BroadcastMsg::OllamaRunning(Ok(())));
-                    }
-                }
+    // -- version answers mean running, the loaded models are extra
+    async fn check_health(url: String, action_tx: Option<UnboundedSender<BroadcastMsg>>) {
+        let client = reqwest::Client::new();
+        let started = Instant::now();
+        let version: Result<OllamaVersionResult, _> = client
+            .get(format!("{}/api/version", url))
+            .timeout(HEALTH_TIMEOUT)
+            .send()
+            .and_then(reqwest::Response::json)
+            .await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+
+        let status = match version {
+            Ok(v) => {
+                let ps: Result<OllamaPsResult, _> = client
+                    .get(format!("{}/api/ps", url))
+                    .timeout(HEALTH_TIMEOUT)
+                    .send()
+                    .and_then(reqwest::Response::json)
+                    .await;
+                Ok(OllamaServerStatus {
+                    version: v.version,
+                    latency_ms,
+                    loaded: ps.map(|p| p.models).unwrap_or_default(),
+                })
             }
-            Err(_e) => {
-                if let Some(tx) = action_tx {
-                    let _ = tx.send(BroadcastMsg::OllamaRunning(Err(
-                        "Ollama is not running".to_string()
-                    )));
-                }
+            Err(_e) => Err("Ollama is not running".to_string()),
+        };
+        if let Some(tx) = action_tx {
+            let _ = tx.send(BroadcastMsg::ServerRunning(url, status));
+        }
+    }
+
+    fn health_checked(&mut self, url: &str, running: bool) {
+        let Some(health) = self.health.get_mut(url) else {
+            return;
+        };
+        let was_running = health.checked(running, Instant::now());
+
+        // -- the server is new or came back, models may have changed meanwhile
+        if running && was_running != Some(true) {
+            match url == self.url {
+                true if was_running == Some(false) => self.send_get_tags(),
+                true => {}
+                false => spawn(Self::get_server_models(
+                    url.to_string(),
+                    self.action_tx.clone(),
+                )),
             }
         }
     }
@@ -115,23 +366,59 @@ impl OllamaState {
     fn set_ollama_url(&mut self, url: String) {
         self.url = url;
 
-        // -- check if ollama is connected
-        self.send_check_ollama_url();
+        // -- check if ollama is connected, the old server's backoff does not apply
+        self.health.clear();
+        self.check_servers();
         // -- ollama url has changed, we need to download new tags
         self.send_get_tags();
     }
 
     fn send_get_tags(&mut self) {
-        spawn(Self::get_tags(self.url.clone(), self.action_tx.clone()));
+        spawn(Self::get_tags(
+            self.url.clone(),
+            self.models.clone(),
+            self.action_tx.clone(),
+        ));
+    }
+
+    async fn get_capabilities(url: &str, model: &OllamaModel) -> ModelCapabilities {
+        let show: Result<OllamaShowResult, _> = reqwest::Client::new()
+            .post(format!("{}/api/show", url))
+            .json(&serde_json::json!({ "model": model.name }))
+            .send()
+            .and_then(reqwest::Response::json)
+            .await;
+
+        match show {
+            Ok(show) => ModelCapabilities::from_show(&show, &model.details),
+            Err(e) => {
+                println!("{:?} - Error getting capabilities of {}", e, model.name);
+                ModelCapabilities::from_families(&model.details)
+            }
+        }
     }
 
-    async fn get_tags(url: String, action_tx: Option<UnboundedSender<BroadcastMsg>>) {
+    // -- known models keep their capabilities while the digest is the same
+    async fn get_tags(
+        url: String,
+        known: Vec<OllamaModel>,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    ) {
         let tags: Result<OllamaTagsResult, _> = reqwest::get(format!("{}/api/tags", url))
             .and_then(reqwest::Response::json)
             .await;
 
         match tags {
-            Ok(t) => {
+            Ok(mut t) => {
+                for model in t.models.iter_mut() {
+                    let cached = known
+                        .iter()
+                        .find(|k| k.name == model.name && k.digest == model.digest);
+                    model.capabilities = match cached {
+                        Some(k) => k.capabilities.clone(),
+                        None => Self::get_capabilities(&url, model).await,
+                    };
+                }
                 if let Some(tx) = action_tx {
                     // println!("{:?} - ollama models tags", t.models);
                     let _ = tx.send(BroadcastMsg::SetOllamaModels(t.models));
@@ -143,7 +430,186 @@ impl OllamaState {
         }
     }
 
+    // -- the models of an extra labeling server, only to know whether it can label
+    async fn get_server_models(url: String, action_tx: Option<UnboundedSender<BroadcastMsg>>) {
+        let tags: Result<OllamaTagsResult, _> = reqwest::get(format!("{}/api/tags", url))
+            .and_then(reqwest::Response::json)
+            .await;
+        match tags {
+            Ok(t) => {
+                if let Some(tx) = action_tx {
+                    let _ = tx.send(BroadcastMsg::SetServerModels(url, t.models));
+                }
+            }
+            Err(e) => println!("{:?} - Error getting ollama tags of {}", e, url),
+        }
+    }
+
+    /// Embeddings of `input` from `/api/embed`, in the same order.
+    pub async fn embed(
+        url: String,
+        model: String,
+        input: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, String> {
+        #[derive(serde::Deserialize)]
+        struct EmbedResult {
+            embeddings: Vec<Vec<f32>>,
+        }
+
+        let count = input.len();
+        let result: EmbedResult = reqwest::Client::new()
+            .post(format!("{}/api/embed", url))
+            .json(&serde_json::json!({ "model": model, "input": input }))
+            .send()
+            .and_then(|r| async { r.error_for_status() })
+            .and_then(reqwest::Response::json)
+            .await
+            .map_err(|e| e.to_string())?;
+        if result.embeddings.len() != count {
+            return Err(format!(
+                "{} embeddings for {} texts",
+                result.embeddings.len(),
+                count
+            ));
+        }
+        Ok(result.embeddings)
+    }
+
+    async fn pull_model(
+        url: String,
+        name: String,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    ) {
+        let send = |progress: ModelPullProgress| {
+            if let Some(tx) = action_tx.clone() {
+                let _ = tx.send(BroadcastMsg::ModelPullProgress(name.clone(), progress));
+            }
+        };
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/api/pull", url))
+            .json(&serde_json::json!({ "model": name, "stream": true }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        let mut response = match response {
+            Ok(r) => r,
+            Err(e) => {
+                send(ModelPullProgress {
+                    error: Some(e.to_string()),
+                    done: true,
+                    ..Default::default()
+                });
+                return;
+            }
+        };
+
+        // -- the answer is one JSON object per line, chunks can split lines
+        let percent = |p: &ModelPullProgress| p.completed * 100 / p.total.max(1);
+        let mut buf = vec![];
+        let mut last = ModelPullProgress::default();
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    last.error = Some(e.to_string());
+                    break;
+                }
+            };
+            buf.extend_from_slice(&chunk);
+            while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=pos).collect();
+                let Ok(progress) = serde_json::from_slice::<ModelPullProgress>(&line) else {
+                    continue;
+                };
+                // -- one message per percent is enough for the progress bar
+                if progress.status != last.status
+                    || percent(&progress) != percent(&last)
+                    || progress.error.is_some()
+                {
+                    send(progress.clone());
+                }
+                last = progress;
+            }
+        }
+
+        last.done = true;
+        if let Some(e) = &last.error {
+            println!("{} - Error pulling ollama model {}", e, name);
+        }
+        send(last);
+        if let Some(tx) = action_tx.clone() {
+            let _ = tx.send(BroadcastMsg::RefreshOllamaModels);
+        }
+    }
+
+    async fn delete_model(
+        url: String,
+        name: String,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+    ) {
+        let deleted = reqwest::Client::new()
+            .delete(format!("{}/api/delete", url))
+            .json(&serde_json::json!({ "model": name }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(e) = deleted {
+            println!("{:?} - Error deleting ollama model {}", e, name);
+        }
+        if let Some(tx) = action_tx {
+            let _ = tx.send(BroadcastMsg::RefreshOllamaModels);
+        }
+    }
+
     pub fn register_tx(&mut self, action_tx: UnboundedSender<BroadcastMsg>) {
         self.action_tx = Some(action_tx);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(health: &HealthMonitor, now: Instant) -> u64 {
+        (health.next_check.unwrap() - now).as_secs()
+    }
+
+    #[test]
+    fn backoff_while_away() {
+        let now = Instant::now();
+        let mut health = HealthMonitor::default();
+        let waits: Vec<u64> = (0..6)
+            .map(|_| {
+                health.checked(false, now);
+                secs(&health, now)
+            })
+            .collect();
+        assert_eq!(waits, [4, 8, 16, 30, 30, 30]);
+
+        assert_eq!(health.checked(true, now), Some(false));
+        assert_eq!(health.failures, 0);
+        assert_eq!(secs(&health, now), 2);
+    }
+
+    #[test]
+    fn server_seen_away_during_a_check() {
+        let now = Instant::now();
+        let mut health = HealthMonitor {
+            checking: true,
+            running: Some(true),
+            ..Default::default()
+        };
+        health.seen_away(now);
+        assert!(health.checking);
+        assert_eq!(health.running, Some(false));
+        assert_eq!(health.failures, 0);
+        assert_eq!(secs(&health, now), 2);
+
+        // -- the check on its way counts the outage once
+        health.checked(false, now);
+        assert_eq!(health.failures, 1);
+        assert_eq!(secs(&health, now), 4);
+    }
+}
@@ -0,0 +1,811 @@
+use std::{
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::Chars,
+};
+
+use image::ImageFormat;
+
+use crate::{
+    enums::SearchDoc,
+    raw_preview::RAW_EXTENSIONS,
+    utils::{date_secs, format_date, format_name},
+};
+
+const FIELDS: &str = "label, caption, description, ext, dir, width, height or modified";
+
+/// Inclusive range of numbers or of seconds, open where `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl Bounds {
+    fn contains(&self, value: i64) -> bool {
+        self.min.map_or(true, |min| min <= value) && self.max.map_or(true, |max| value <= max)
+    }
+}
+
+/// Parsed search box query.
+///
+/// Words are ANDed, `OR` and commas join alternatives, `-word` or `NOT word`
+/// leave images out, `"red car"` is a phrase and parentheses group. Fields
+/// look at one thing: `label:tree`, `caption:wedding`, `description:sunset`,
+/// `ext:png`, `dir:~/Pictures`, `width:>3000`, `height:1000..2000` and
+/// `modified:2024-..`. `ext:` is the format found in the file, so `ext:jpg`
+/// finds JPEGs whatever their extension and `ext:cr2` every RAW file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    // -- in a label, the caption or the description, lowercased
+    Text(String),
+    Label(String),
+    Caption(String),
+    Description(String),
+    // -- format name as in `SUPPORTED_IMAGE_FORMATS`
+    Ext(String),
+    // -- path prefix when absolute, part of the directory path otherwise
+    Dir(String),
+    Width(Bounds),
+    Height(Bounds),
+    // -- seconds since the epoch
+    Modified(Bounds),
+    Not(Box<Query>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Comma,
+    And,
+    Or,
+    Not,
+    // -- `field` is the lowercased name before a colon
+    Term {
+        field: Option<String>,
+        value: String,
+    },
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, String> {
+        let tokens = tokenize(input)?;
+        if tokens.is_empty() {
+            return Err("Empty query".to_string());
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let query = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(query),
+            // -- everything else is taken by the parser
+            Some(_) => Err("Unexpected \")\"".to_string()),
+        }
+    }
+
+    pub fn matches(&self, doc: &SearchDoc) -> bool {
+        self.eval(doc, false, false)
+    }
+
+    /// Like `matches`, but the plain words count as found unless they are
+    /// negated: the images the semantic search may add.
+    pub fn matches_filters(&self, doc: &SearchDoc) -> bool {
+        self.eval(doc, true, false)
+    }
+
+    fn eval(&self, doc: &SearchDoc, relax_text: bool, negated: bool) -> bool {
+        match self {
+            Query::Text(text) => (relax_text && !negated) || contains_text(doc, text),
+            Query::Label(text) => doc.labels.iter().any(|l| contains(l, text)),
+            Query::Caption(text) => contains(&doc.caption, text),
+            Query::Description(text) => contains(&doc.description, text),
+            Query::Ext(format) => doc.format == *format,
+            Query::Dir(dir) => {
+                let parent = Path::new(&doc.path).parent().unwrap_or(Path::new(""));
+                match Path::new(dir).is_absolute() {
+                    true => parent.starts_with(dir),
+                    false => contains(&parent.to_string_lossy(), dir),
+                }
+            }
+            Query::Width(bounds) => doc.width.is_some_and(|w| bounds.contains(w as i64)),
+            Query::Height(bounds) => doc.height.is_some_and(|h| bounds.contains(h as i64)),
+            Query::Modified(bounds) => bounds.contains(doc.mtime),
+            Query::Not(query) => !query.eval(doc, relax_text, !negated),
+            Query::And(queries) => queries.iter().all(|q| q.eval(doc, relax_text, negated)),
+            Query::Or(queries) => queries.iter().any(|q| q.eval(doc, relax_text, negated)),
+        }
+    }
+
+    /// Plain words and phrases outside of negations, what the query is about.
+    pub fn text_terms(&self) -> Vec<String> {
+        let mut terms = vec![];
+        self.collect_terms(&mut terms, false);
+        terms
+    }
+
+    /// Words to highlight in the found images, field values included.
+    pub fn highlight_terms(&self) -> Vec<String> {
+        let mut terms = vec![];
+        self.collect_terms(&mut terms, true);
+        terms
+    }
+
+    fn collect_terms(&self, terms: &mut Vec<String>, fields: bool) {
+        match self {
+            Query::Text(text) => terms.push(text.clone()),
+            Query::Label(text) | Query::Caption(text) | Query::Description(text) if fields => {
+                terms.push(text.clone())
+            }
+            Query::And(queries) | Query::Or(queries) => {
+                for query in queries.iter() {
+                    query.collect_terms(terms, fields);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Share of the plain words found in the image, 1 for queries without any.
+    pub fn text_score(&self, doc: &SearchDoc) -> f32 {
+        let terms = self.text_terms();
+        if terms.is_empty() {
+            return 1.0;
+        }
+        let found = terms.iter().filter(|t| contains_text(doc, t)).count();
+        found as f32 / terms.len() as f32
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(needle)
+}
+
+// -- `jpg` is `jpeg`, `tif` is `tiff` and RAW extensions are `raw`
+fn ext_format(ext: &str) -> String {
+    if RAW_EXTENSIONS.contains(&ext) {
+        return "raw".to_string();
+    }
+    ImageFormat::from_extension(ext)
+        .and_then(format_name)
+        .map(String::from)
+        .unwrap_or_else(|| ext.to_string())
+}
+
+fn contains_text(doc: &SearchDoc, text: &str) -> bool {
+    doc.labels.iter().any(|l| contains(l, text))
+        || contains(&doc.caption, text)
+        || contains(&doc.description, text)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' | '-' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    ',' => Token::Comma,
+                    _ => Token::Not,
+                });
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Term {
+                    field: None,
+                    value: quoted(&mut chars)?,
+                });
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | ',' | '"') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+
+                // -- field:"quoted value"
+                if word.ends_with(':') && chars.peek() == Some(&'"') {
+                    chars.next();
+                    word.pop();
+                    tokens.push(Token::Term {
+                        field: Some(word.to_lowercase()),
+                        value: quoted(&mut chars)?,
+                    });
+                    continue;
+                }
+
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => match word.split_once(':') {
+                        Some((field, value))
+                            if !field.is_empty()
+                                && field.chars().all(|c| c.is_ascii_alphabetic()) =>
+                        {
+                            Token::Term {
+                                field: Some(field.to_lowercase()),
+                                value: value.to_string(),
+                            }
+                        }
+                        _ => Token::Term {
+                            field: None,
+                            value: word,
+                        },
+                    },
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// -- the opening quote is already taken
+fn quoted(chars: &mut Peekable<Chars<'_>>) -> Result<String, String> {
+    let mut value = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            return Ok(value);
+        }
+        value.push(c);
+    }
+    Err("Missing closing quote".to_string())
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // -- nothing left to join or negate
+    fn at_operand_end(&self) -> bool {
+        matches!(
+            self.peek(),
+            None | Some(Token::Close | Token::Comma | Token::And | Token::Or)
+        )
+    }
+
+    fn skip_commas(&mut self) {
+        while self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Query, String> {
+        // -- label lists may start or end with a comma
+        self.skip_commas();
+        let mut queries = vec![self.parse_and()?];
+        loop {
+            match self.peek() {
+                Some(Token::Or) => {
+                    self.pos += 1;
+                    if self.at_operand_end() {
+                        return Err("OR needs a term on both sides".to_string());
+                    }
+                    queries.push(self.parse_and()?);
+                }
+                Some(Token::Comma) => {
+                    self.skip_commas();
+                    if !matches!(self.peek(), None | Some(Token::Close)) {
+                        queries.push(self.parse_and()?);
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok(join(queries, Query::Or))
+    }
+
+    fn parse_and(&mut self) -> Result<Query, String> {
+        let mut queries = vec![self.parse_not()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.pos += 1;
+                    if self.at_operand_end() {
+                        return Err("AND needs a term on both sides".to_string());
+                    }
+                    queries.push(self.parse_not()?);
+                }
+                Some(Token::Open | Token::Not | Token::Term { .. }) => {
+                    queries.push(self.parse_not()?);
+                }
+                _ => break,
+            }
+        }
+        Ok(join(queries, Query::And))
+    }
+
+    fn parse_not(&mut self) -> Result<Query, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            if self.at_operand_end() {
+                return Err("Nothing to leave out after \"-\" or NOT".to_string());
+            }
+            return Ok(Query::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                if self.peek() == Some(&Token::Close) {
+                    return Err("Empty parentheses".to_string());
+                }
+                let query = self.parse_or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("Missing closing parenthesis".to_string());
+                }
+                self.pos += 1;
+                Ok(query)
+            }
+            Some(Token::Term { field, value }) => term(field.as_deref(), value),
+            Some(Token::Close) => Err("Unexpected \")\"".to_string()),
+            Some(Token::And) => Err("AND needs a term on both sides".to_string()),
+            Some(Token::Or) => Err("OR needs a term on both sides".to_string()),
+            Some(Token::Comma) | Some(Token::Not) | None => {
+                Err("The query ends too early".to_string())
+            }
+        }
+    }
+}
+
+fn join(mut queries: Vec<Query>, op: fn(Vec<Query>) -> Query) -> Query {
+    match queries.len() {
+        1 => queries.remove(0),
+        _ => op(queries),
+    }
+}
+
+fn term(field: Option<&str>, value: String) -> Result<Query, String> {
+    let Some(field) = field else {
+        return match value.trim().is_empty() {
+            true => Err("Empty phrase".to_string()),
+            false => Ok(Query::Text(value.to_lowercase())),
+        };
+    };
+    if value.is_empty() {
+        return Err(format!("Missing a value after \"{}:\"", field));
+    }
+    let text = value.to_lowercase();
+    match field {
+        "label" | "labels" => Ok(Query::Label(text)),
+        "caption" => Ok(Query::Caption(text)),
+        "description" | "desc" => Ok(Query::Description(text)),
+        "ext" => Ok(Query::Ext(ext_format(text.trim_start_matches('.')))),
+        "dir" => Ok(Query::Dir(expand_home(&value))),
+        "width" => bounds(&value, parse_number)
+            .map(Query::Width)
+            .ok_or(format!(
+                "Invalid width \"{}\", use a number like 3000, >3000 or 1000..2000",
+                value
+            )),
+        "height" => bounds(&value, parse_number)
+            .map(Query::Height)
+            .ok_or(format!(
+                "Invalid height \"{}\", use a number like 2000, <2000 or 1000..2000",
+                value
+            )),
+        "modified" | "date" => bounds(&value, parse_date)
+            .map(Query::Modified)
+            .ok_or(format!(
+                "Invalid date \"{}\", use YYYY, YYYY-MM or YYYY-MM-DD, like 2024-06 or 2024-..",
+                value
+            )),
+        _ => Err(format!("Unknown field \"{}\", use {}", field, FIELDS)),
+    }
+}
+
+fn expand_home(dir: &str) -> String {
+    let home = directories::BaseDirs::new().map(|d| d.home_dir().to_path_buf());
+    match (dir.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            let rest = rest.trim_start_matches(['/', '\\']);
+            let path: PathBuf = match rest.is_empty() {
+                true => home,
+                false => home.join(rest),
+            };
+            path.to_string_lossy().to_string()
+        }
+        _ if Path::new(dir).is_absolute() => dir.to_string(),
+        _ => dir.to_lowercase(),
+    }
+}
+
+// -- `=v`, `>v`, `>=v`, `<v`, `<=v` or `from..to` with an open end, for values
+// -- standing for a span like a whole month
+fn bounds(value: &str, parse: fn(&str) -> Option<(i64, i64)>) -> Option<Bounds> {
+    if let Some((from, to)) = value.split_once("..") {
+        let min = match from.is_empty() {
+            true => None,
+            false => Some(parse(from)?.0),
+        };
+        let max = match to.is_empty() {
+            true => None,
+            false => Some(parse(to)?.1),
+        };
+        return (min.is_some() || max.is_some()).then_some(Bounds { min, max });
+    }
+
+    let (op, value) = [">=", "<=", ">", "<", "="]
+        .iter()
+        .find_map(|op| value.strip_prefix(op).map(|v| (*op, v)))
+        .unwrap_or(("=", value));
+    let (first, last) = parse(value)?;
+    Some(match op {
+        ">" => Bounds {
+            min: Some(last + 1),
+            max: None,
+        },
+        ">=" => Bounds {
+            min: Some(first),
+            max: None,
+        },
+        "<" => Bounds {
+            min: None,
+            max: Some(first - 1),
+        },
+        "<=" => Bounds {
+            min: None,
+            max: Some(last),
+        },
+        _ => Bounds {
+            min: Some(first),
+            max: Some(last),
+        },
+    })
+}
+
+fn parse_number(value: &str) -> Option<(i64, i64)> {
+    let n = value.parse::<u32>().ok()? as i64;
+    Some((n, n))
+}
+
+// -- first and last second of a year, month or day, in UTC
+fn parse_date(value: &str) -> Option<(i64, i64)> {
+    // -- `2024-..` leaves the dash of an unfinished date
+    let value = value.trim_end_matches('-');
+    let parts: Vec<&str> = value.split('-').collect();
+    let year = match parts[0].len() {
+        4 => parts[0].parse::<i64>().ok()?,
+        _ => return None,
+    };
+    let number = |i: usize| -> Option<Option<u32>> {
+        match parts.get(i) {
+            Some(p) if p.len() == 2 => p.parse().ok().map(Some),
+            Some(_) => None,
+            None => Some(None),
+        }
+    };
+    let (month, day) = (number(1)?, number(2)?);
+    if parts.len() > 3 {
+        return None;
+    }
+
+    match (month, day) {
+        (None, _) => Some((date_secs(year, 1, 1), date_secs(year + 1, 1, 1) - 1)),
+        (Some(month), None) if (1..=12).contains(&month) => {
+            let next = match month {
+                12 => date_secs(year + 1, 1, 1),
+                _ => date_secs(year, month + 1, 1),
+            };
+            Some((date_secs(year, month, 1), next - 1))
+        }
+        (Some(month), Some(day)) => {
+            let start = date_secs(year, month, day);
+            // -- rejects days the month does not have, like 2023-02-30
+            let valid = format_date(start) == format!("{:04}-{:02}-{:02}", year, month, day);
+            valid.then_some((start, start + 86399))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(path: &str, labels: &[&str], caption: &str) -> SearchDoc {
+        let ext = Path::new(path).extension().unwrap_or_default();
+        SearchDoc {
+            path: path.to_string(),
+            format: ext_format(&ext.to_string_lossy().to_lowercase()),
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            caption: caption.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn text(t: &str) -> Query {
+        Query::Text(t.to_string())
+    }
+
+    fn not(q: Query) -> Query {
+        Query::Not(Box::new(q))
+    }
+
+    #[test]
+    fn parses_words_as_and() {
+        assert_eq!(Query::parse("dog").unwrap(), text("dog"));
+        assert_eq!(
+            Query::parse("dog AND Beach").unwrap(),
+            Query::And(vec![text("dog"), text("beach")])
+        );
+        assert_eq!(
+            Query::parse("dog beach").unwrap(),
+            Query::parse("dog AND beach").unwrap()
+        );
+    }
+
+    #[test]
+    fn or_binds_weaker_than_and() {
+        assert_eq!(
+            Query::parse("a b OR c").unwrap(),
+            Query::Or(vec![Query::And(vec![text("a"), text("b")]), text("c")])
+        );
+        assert_eq!(
+            Query::parse("a (b OR c)").unwrap(),
+            Query::And(vec![text("a"), Query::Or(vec![text("b"), text("c")])])
+        );
+    }
+
+    #[test]
+    fn commas_are_or() {
+        assert_eq!(
+            Query::parse("dog, cat,").unwrap(),
+            Query::Or(vec![text("dog"), text("cat")])
+        );
+        assert_eq!(Query::parse(", dog").unwrap(), text("dog"));
+    }
+
+    #[test]
+    fn parses_negation() {
+        assert_eq!(
+            Query::parse("cat -indoor").unwrap(),
+            Query::And(vec![text("cat"), not(text("indoor"))])
+        );
+        assert_eq!(
+            Query::parse("NOT (a OR b)").unwrap(),
+            not(Query::Or(vec![text("a"), text("b")]))
+        );
+        // -- a dash inside a word is part of it
+        assert_eq!(Query::parse("x-ray").unwrap(), text("x-ray"));
+    }
+
+    #[test]
+    fn parses_phrases() {
+        assert_eq!(Query::parse("\"Red car\"").unwrap(), text("red car"));
+        assert_eq!(
+            Query::parse("-\"red car\" \"AND\"").unwrap(),
+            Query::And(vec![not(text("red car")), text("and")])
+        );
+        assert_eq!(
+            Query::parse("caption:\"at the beach\"").unwrap(),
+            Query::Caption("at the beach".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(
+            Query::parse("label:Tree").unwrap(),
+            Query::Label("tree".to_string())
+        );
+        assert_eq!(
+            Query::parse("caption:wedding").unwrap(),
+            Query::Caption("wedding".to_string())
+        );
+        assert_eq!(
+            Query::parse("EXT:.PNG").unwrap(),
+            Query::Ext("png".to_string())
+        );
+        assert_eq!(
+            Query::parse("dir:/home/me/Pictures").unwrap(),
+            Query::Dir("/home/me/Pictures".to_string())
+        );
+        assert_eq!(
+            Query::parse("dir:holidays").unwrap(),
+            Query::Dir("holidays".to_string())
+        );
+    }
+
+    #[test]
+    fn expands_home_in_dir() {
+        let Some(home) = directories::BaseDirs::new().map(|d| d.home_dir().to_path_buf()) else {
+            return;
+        };
+        assert_eq!(
+            Query::parse("dir:~/Pictures").unwrap(),
+            Query::Dir(home.join("Pictures").to_string_lossy().to_string())
+        );
+    }
+
+    #[test]
+    fn parses_numbers() {
+        let width = |min, max| Query::Width(Bounds { min, max });
+        assert_eq!(
+            Query::parse("width:>3000").unwrap(),
+            width(Some(3001), None)
+        );
+        assert_eq!(
+            Query::parse("width:>=3000").unwrap(),
+            width(Some(3000), None)
+        );
+        assert_eq!(Query::parse("width:<=100").unwrap(), width(None, Some(100)));
+        assert_eq!(
+            Query::parse("width:800").unwrap(),
+            width(Some(800), Some(800))
+        );
+        assert_eq!(
+            Query::parse("width:1000..2000").unwrap(),
+            width(Some(1000), Some(2000))
+        );
+        assert_eq!(
+            Query::parse("height:..2000").unwrap(),
+            Query::Height(Bounds {
+                min: None,
+                max: Some(2000)
+            })
+        );
+    }
+
+    #[test]
+    fn parses_dates() {
+        let modified = |min, max| Query::Modified(Bounds { min, max });
+        let y2024 = date_secs(2024, 1, 1);
+        let y2025 = date_secs(2025, 1, 1);
+        assert_eq!(y2024, 1_704_067_200);
+        assert_eq!(
+            Query::parse("modified:2024-..").unwrap(),
+            modified(Some(y2024), None)
+        );
+        assert_eq!(
+            Query::parse("modified:2024").unwrap(),
+            modified(Some(y2024), Some(y2025 - 1))
+        );
+        assert_eq!(
+            Query::parse("modified:..2024-02").unwrap(),
+            modified(None, Some(date_secs(2024, 3, 1) - 1))
+        );
+        assert_eq!(
+            Query::parse("modified:2024-12..2024-12-31").unwrap(),
+            modified(Some(date_secs(2024, 12, 1)), Some(y2025 - 1))
+        );
+        assert_eq!(
+            Query::parse("modified:>2024").unwrap(),
+            modified(Some(y2025), None)
+        );
+        assert_eq!(
+            Query::parse("modified:<2024-01-01").unwrap(),
+            modified(None, Some(y2024 - 1))
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |q: &str| Query::parse(q).unwrap_err();
+        assert_eq!(error(""), "Empty query");
+        assert_eq!(error("   "), "Empty query");
+        assert_eq!(error("\"red car"), "Missing closing quote");
+        assert_eq!(error("dog \" \""), "Empty phrase");
+        assert_eq!(error("(a OR b"), "Missing closing parenthesis");
+        assert_eq!(error("a)"), "Unexpected \")\"");
+        assert_eq!(error("()"), "Empty parentheses");
+        assert_eq!(error("a OR"), "OR needs a term on both sides");
+        assert_eq!(error("AND a"), "AND needs a term on both sides");
+        assert_eq!(error("a -"), "Nothing to leave out after \"-\" or NOT");
+        assert_eq!(error("label:"), "Missing a value after \"label:\"");
+        assert!(error("color:red").starts_with("Unknown field \"color\""));
+        assert!(error("width:big").starts_with("Invalid width"));
+        assert!(error("modified:2024-13").starts_with("Invalid date"));
+        assert!(error("modified:2023-02-29").starts_with("Invalid date"));
+        assert!(error("modified:24").starts_with("Invalid date"));
+        assert!(error("modified:..").starts_with("Invalid date"));
+    }
+
+    #[test]
+    fn matches_text_in_labels_caption_and_description() {
+        let mut d = doc("/p/dog.jpg", &["Dog", "sandy beach"], "A dog running");
+        d.description = "Late afternoon, a red car parked behind".to_string();
+        let matches = |q: &str| Query::parse(q).unwrap().matches(&d);
+
+        assert!(matches("dog beach"));
+        assert!(matches("\"red car\""));
+        assert!(!matches("\"car red\""));
+        assert!(!matches("dog AND cat"));
+        assert!(matches("cat OR running"));
+        assert!(matches("dog -cat"));
+        assert!(!matches("dog -beach"));
+        assert!(matches("label:beach"));
+        assert!(!matches("label:running"));
+        assert!(matches("caption:running"));
+        assert!(!matches("caption:car"));
+        assert!(matches("description:car"));
+    }
+
+    #[test]
+    fn matches_file_fields() {
+        let mut d = doc("/home/me/Pictures/2024/IMG_1.PNG", &[], "");
+        d.width = Some(4000);
+        d.height = Some(3000);
+        d.mtime = date_secs(2024, 5, 17) + 3600;
+        let matches = |q: &str| Query::parse(q).unwrap().matches(&d);
+
+        assert!(matches("ext:png"));
+        assert!(matches("ext:.PNG"));
+        assert!(!matches("ext:jpg"));
+        assert!(matches("dir:/home/me/Pictures"));
+        assert!(!matches("dir:/home/me/Pic"));
+        assert!(matches("dir:pictures/2024"));
+        assert!(matches("width:>3000 height:3000"));
+        assert!(!matches("width:<4000"));
+        assert!(matches("modified:2024-.."));
+        assert!(matches("modified:2024-05-17"));
+        assert!(!matches("modified:2024-05-18.."));
+        assert!(!matches("modified:..2023"));
+
+        // -- unknown dimensions match no size filter
+        d.width = None;
+        assert!(!Query::parse("width:>0").unwrap().matches(&d));
+        assert!(Query::parse("-width:>0").unwrap().matches(&d));
+    }
+
+    #[test]
+    fn filters_ignore_plain_words_unless_negated() {
+        let d = doc("/p/a.png", &["cat", "sofa"], "");
+        let filters = |q: &str| Query::parse(q).unwrap().matches_filters(&d);
+
+        assert!(filters("dog ext:png"));
+        assert!(!filters("dog ext:jpg"));
+        assert!(!filters("dog -sofa"));
+        assert!(filters("dog -(cat bird)"));
+        assert!(!filters("dog label:dog"));
+    }
+
+    #[test]
+    fn collects_terms_and_scores() {
+        let q = Query::parse("(dog OR \"red car\") -cat label:tree ext:png").unwrap();
+        assert_eq!(q.text_terms(), vec!["dog", "red car"]);
+        assert_eq!(q.highlight_terms(), vec!["dog", "red car", "tree"]);
+
+        let d = doc("/p/a.png", &["dog", "tree"], "");
+        assert_eq!(q.text_score(&d), 0.5);
+        assert_eq!(Query::parse("ext:png").unwrap().text_score(&d), 1.0);
+    }
+
+    #[test]
+    fn ext_matches_the_format_found_in_the_file() {
+        // -- a PNG saved with a .jpg extension
+        let mut d = doc("/p/misnamed.jpg", &[], "");
+        d.format = "png".to_string();
+        let matches = |q: &str| Query::parse(q).unwrap().matches(&d);
+        assert!(matches("ext:png"));
+        assert!(!matches("ext:jpg"));
+        assert!(!matches("ext:jpeg"));
+
+        let d = doc("/p/a.jpeg", &[], "");
+        assert!(Query::parse("ext:jpg").unwrap().matches(&d));
+        let d = doc("/p/a.CR2", &[], "");
+        assert_eq!(d.format, "raw");
+        assert!(Query::parse("ext:nef").unwrap().matches(&d));
+        assert!(Query::parse("ext:raw").unwrap().matches(&d));
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::enums::FileError;
+
+// -- TIFF based RAW containers, their embedded previews are plain JPEGs
+pub const RAW_EXTENSIONS: [&str; 13] = [
+    "cr2", "nef", "nrw", "arw", "srf", "sr2", "srw", "dng", "pef", "3fr", "erf", "kdc", "mos",
+];
+
+const TAG_COMPRESSION: u16 = 0x103;
+const TAG_ORIENTATION: u16 = 0x112;
+const TAG_STRIP_OFFSETS: u16 = 0x111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x117;
+const TAG_SUB_IFDS: u16 = 0x14a;
+const TAG_JPEG_OFFSET: u16 = 0x201;
+const TAG_JPEG_LENGTH: u16 = 0x202;
+
+// -- guards against broken or looping IFD chains
+const MAX_IFDS: usize = 32;
+const MAX_IFD_ENTRIES: u16 = 1000;
+
+fn has_raw_extension(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| RAW_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// RAW file by extension and TIFF header, a file that cannot be read (e.g. deleted)
+/// is judged by its extension only.
+pub fn is_raw(path: &Path) -> bool {
+    if !has_raw_extension(path) {
+        return false;
+    }
+    let mut head = [0u8; 4];
+    match fs::File::open(path).and_then(|mut f| f.read_exact(&mut head)) {
+        Ok(()) => head == *b"II*\0" || head == *b"MM\0*",
+        Err(_) => true,
+    }
+}
+
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: [u8; 4],
+}
+
+struct TiffReader<R: Read + Seek> {
+    r: R,
+    le: bool,
+    len: u64,
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    fn new(mut r: R) -> Result<Self, FileError> {
+        let len = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(0))?;
+        let mut head = [0u8; 4];
+        r.read_exact(&mut head)?;
+        let le = match &head {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => {
+                return Err(FileError::UnsupportedFormat(
+                    "not a TIFF based RAW".to_string(),
+                ))
+            }
+        };
+        Ok(Self { r, le, len })
+    }
+
+    fn u16(&self, b: [u8; 2]) -> u16 {
+        if self.le {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        }
+    }
+
+    fn u32(&self, b: [u8; 4]) -> u32 {
+        if self.le {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    }
+
+    fn read_at<const N: usize>(&mut self, offset: u64) -> Result<[u8; N], FileError> {
+        let mut buf = [0u8; N];
+        self.r.seek(SeekFrom::Start(offset))?;
+        self.r.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32_at(&mut self, offset: u64) -> Result<u32, FileError> {
+        let b = self.read_at::<4>(offset)?;
+        Ok(self.u32(b))
+    }
+
+    /// Entries of the IFD at `offset` and the offset of the next one.
+    fn ifd(&mut self, offset: u64) -> Result<(Vec<IfdEntry>, u32), FileError> {
+        let b = self.read_at::<2>(offset)?;
+        let count = self.u16(b).min(MAX_IFD_ENTRIES);
+        let mut entries = vec![];
+        for i in 0..count as u64 {
+            let e = self.read_at::<12>(offset + 2 + i * 12)?;
+            entries.push(IfdEntry {
+                tag: self.u16([e[0], e[1]]),
+                kind: self.u16([e[2], e[3]]),
+                count: self.u32([e[4], e[5], e[6], e[7]]),
+                value: [e[8], e[9], e[10], e[11]],
+            });
+        }
+        let next = self.read_u32_at(offset + 2 + count as u64 * 12)?;
+        Ok((entries, next))
+    }
+
+    // -- single SHORT or LONG value stored inline
+    fn value(&self, entry: &IfdEntry) -> Option<u32> {
+        match (entry.kind, entry.count) {
+            (3, 1) => Some(self.u16([entry.value[0], entry.value[1]]) as u32),
+            (4, 1) => Some(self.u32(entry.value)),
+            _ => None,
+        }
+    }
+
+    fn sub_ifds(&mut self, entry: &IfdEntry) -> Vec<u32> {
+        match entry.count {
+            1 => vec![self.u32(entry.value)],
+            n => {
+                let start = self.u32(entry.value) as u64;
+                (0..n.min(MAX_IFDS as u32) as u64)
+                    .filter_map(|i| self.read_u32_at(start + i * 4).ok())
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether the bytes at `offset` are a JPEG the `image` crate decodes, DNGs also
+    /// store lossless JPEG raw data that it does not.
+    fn is_decodable_jpeg(&mut self, offset: u64) -> bool {
+        let mut pos = offset;
+        if self.read_at::<2>(pos).ok() != Some([0xff, 0xd8]) {
+            return false;
+        }
+        pos += 2;
+        for _ in 0..64 {
+            let Ok([mark, kind, h, l]) = self.read_at::<4>(pos) else {
+                return false;
+            };
+            if mark != 0xff {
+                return false;
+            }
+            match kind {
+                // -- baseline, extended and progressive huffman
+                0xc0..=0xc2 => return true,
+                // -- lossless, hierarchical and arithmetic frames, or no frame at all
+                0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf | 0xda | 0xd9 => return false,
+                // -- tables (DHT, DAC), reserved JPG and other segments
+                _ => pos += 2 + u16::from_be_bytes([h, l]) as u64,
+            }
+        }
+        false
+    }
+
+    // -- previews rarely carry their own EXIF, the camera orientation is in IFD0
+    fn orientation(&mut self) -> Option<u8> {
+        let ifd0 = self.read_u32_at(4).ok()?;
+        let (entries, _) = self.ifd(ifd0 as u64).ok()?;
+        let entry = entries.iter().find(|e| e.tag == TAG_ORIENTATION)?;
+        self.value(entry).map(|o| o as u8)
+    }
+
+    /// (offset, length) of every JPEG preview found in the IFD tree.
+    fn previews(&mut self) -> Vec<(u64, u64)> {
+        let mut previews = vec![];
+        let mut visited = HashSet::new();
+        let mut queue = vec![self.read_u32_at(4).unwrap_or(0)];
+
+        while let Some(offset) = queue.pop() {
+            if offset == 0 || !visited.insert(offset) || visited.len() > MAX_IFDS {
+                continue;
+            }
+            let Ok((entries, next)) = self.ifd(offset as u64) else {
+                continue;
+            };
+            queue.push(next);
+
+            let find = |tag: u16| entries.iter().find(|e| e.tag == tag);
+            let jpeg = (find(TAG_JPEG_OFFSET), find(TAG_JPEG_LENGTH));
+            if let (Some(o), Some(l)) = jpeg {
+                if let (Some(o), Some(l)) = (self.value(o), self.value(l)) {
+                    previews.push((o as u64, l as u64));
+                }
+            }
+
+            // -- old style JPEG (6) or JPEG (7) compressed single strip
+            let compression = find(TAG_COMPRESSION).and_then(|e| self.value(e));
+            let strip = (find(TAG_STRIP_OFFSETS), find(TAG_STRIP_BYTE_COUNTS));
+            if let (Some(6 | 7), (Some(o), Some(l))) = (compression, strip) {
+                if let (Some(o), Some(l)) = (self.value(o), self.value(l)) {
+                    previews.push((o as u64, l as u64));
+                }
+            }
+
+            if let Some(sub) = find(TAG_SUB_IFDS) {
+                queue.extend(self.sub_ifds(sub));
+            }
+        }
+
+        let len = self.len;
+        previews.retain(|(o, l)| *l > 0 && o + l <= len);
+        previews.retain(|(o, _)| self.is_decodable_jpeg(*o));
+        previews
+    }
+
+    fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, FileError> {
+        let mut buf = vec![0u8; len as usize];
+        self.r.seek(SeekFrom::Start(offset))?;
+        self.r.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub struct RawPreview {
+    pub jpeg: Vec<u8>,
+    /// EXIF orientation of the camera, if present.
+    pub orientation: Option<u8>,
+}
+
+/// Extracts the largest embedded JPEG preview of a TIFF based RAW file.
+pub fn extract_preview(path: &str) -> Result<RawPreview, FileError> {
+    let mut tiff = TiffReader::new(fs::File::open(path)?)?;
+    let (offset, len) = tiff
+        .previews()
+        .into_iter()
+        .max_by_key(|(_, len)| *len)
+        .ok_or_else(|| FileError::UnsupportedFormat("RAW without a JPEG preview".to_string()))?;
+    Ok(RawPreview {
+        jpeg: tiff.read_range(offset, len)?,
+        orientation: tiff.orientation(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+
+    use super::*;
+
+    fn jpeg() -> Vec<u8> {
+        let mut out = vec![];
+        JpegEncoder::new(&mut out)
+            .encode(&[128; 8 * 8 * 3], 8, 8, ExtendedColorType::Rgb8)
+            .unwrap();
+        out
+    }
+
+    // -- header, IFD0 with the orientation and the JPEG preview tags, then the JPEG
+    fn tiff(le: bool, jpeg: &[u8]) -> Vec<u8> {
+        let u16b = |v: u16| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+        let u32b = |v: u32| if le { v.to_le_bytes() } else { v.to_be_bytes() };
+        let jpeg_offset = 8 + 2 + 3 * 12 + 4;
+
+        let mut out = vec![];
+        out.extend_from_slice(if le { b"II*\0" } else { b"MM\0*" });
+        out.extend_from_slice(&u32b(8));
+        out.extend_from_slice(&u16b(3));
+        let entries = [
+            (TAG_ORIENTATION, 3, 6),
+            (TAG_JPEG_OFFSET, 4, jpeg_offset),
+            (TAG_JPEG_LENGTH, 4, jpeg.len() as u32),
+        ];
+        for (tag, kind, value) in entries {
+            out.extend_from_slice(&u16b(tag));
+            out.extend_from_slice(&u16b(kind));
+            out.extend_from_slice(&u32b(1));
+            match kind {
+                3 => out.extend_from_slice(&[u16b(value as u16), [0, 0]].concat()),
+                _ => out.extend_from_slice(&u32b(value)),
+            }
+        }
+        out.extend_from_slice(&u32b(0));
+        out.extend_from_slice(jpeg);
+        out
+    }
+
+    fn previews(data: Vec<u8>) -> Vec<(u64, u64)> {
+        TiffReader::new(Cursor::new(data)).unwrap().previews()
+    }
+
+    // -- the segments of a JPEG as (marker, whole segment), up to the scan
+    fn segments(jpeg: &[u8]) -> (Vec<(u8, Vec<u8>)>, Vec<u8>) {
+        let mut pos = 2;
+        let mut segments = vec![];
+        while jpeg[pos + 1] != 0xda {
+            let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+            segments.push((jpeg[pos + 1], jpeg[pos..pos + 2 + len].to_vec()));
+            pos += 2 + len;
+        }
+        (segments, jpeg[pos..].to_vec())
+    }
+
+    #[test]
+    fn preview_of_both_byte_orders() {
+        let jpeg = jpeg();
+        for le in [true, false] {
+            let data = tiff(le, &jpeg);
+            let mut reader = TiffReader::new(Cursor::new(data.clone())).unwrap();
+            assert_eq!(reader.orientation(), Some(6));
+            let previews = reader.previews();
+            assert_eq!(previews.len(), 1);
+            let (offset, len) = previews[0];
+            let preview = &data[offset as usize..(offset + len) as usize];
+            assert_eq!(preview, jpeg.as_slice());
+            assert!(image::load_from_memory(preview).is_ok());
+        }
+    }
+
+    #[test]
+    fn preview_with_tables_before_the_frame() {
+        let (parts, scan) = segments(&jpeg());
+        let (mut tables, frame): (Vec<_>, Vec<_>) =
+            parts.into_iter().partition(|(m, _)| *m != 0xc0);
+        tables.sort_by_key(|(m, _)| *m != 0xc4);
+        let mut jpeg = vec![0xff, 0xd8];
+        for (_, segment) in tables.iter().chain(frame.iter()) {
+            jpeg.extend_from_slice(segment);
+        }
+        jpeg.extend_from_slice(&scan);
+        assert_eq!(segments(&jpeg).0[0].0, 0xc4);
+        assert!(image::load_from_memory(&jpeg).is_ok());
+
+        for le in [true, false] {
+            assert_eq!(previews(tiff(le, &jpeg)).len(), 1);
+        }
+    }
+
+    #[test]
+    fn dac_and_jpg_segments_are_skipped() {
+        let mut jpeg = vec![0xff, 0xd8];
+        jpeg.extend_from_slice(&[0xff, 0xcc, 0x00, 0x04, 0x00, 0x11]);
+        jpeg.extend_from_slice(&[0xff, 0xc8, 0x00, 0x02]);
+        jpeg.extend_from_slice(&[0xff, 0xc0, 0x00, 0x02]);
+        assert_eq!(previews(tiff(false, &jpeg)).len(), 1);
+    }
+
+    #[test]
+    fn lossless_jpeg_is_skipped() {
+        let mut jpeg = jpeg();
+        let frame = jpeg.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        jpeg[frame + 1] = 0xc3;
+        assert!(previews(tiff(true, &jpeg)).is_empty());
+    }
+
+    #[test]
+    fn not_a_tiff() {
+        assert!(TiffReader::new(Cursor::new(b"\xff\xd8\xff\xe0".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use image::{DynamicImage, ImageReader};
+
+use crate::{enums::ThumbnailCacheStats, index_db::IndexDb, utils::thumbnails_dir};
+
+pub const THUMBNAIL_SIZE: u32 = 160;
+// -- bumped whenever thumbnails are rendered differently, old ones age out of the LRU
+const THUMBNAIL_VERSION: u32 = 2;
+
+/// Thumbnails on disk keyed by a hash of (canonical path, size, mtime), so equally named
+/// files never collide and an edited image gets a fresh thumbnail.
+///
+/// Sizes and last use live in the index, which drives the LRU eviction.
+#[derive(Clone)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    index: IndexDb,
+    budget_bytes: u64,
+}
+
+impl ThumbnailCache {
+    pub fn new(index: IndexDb, budget_mb: u64) -> Option<Self> {
+        let dir = thumbnails_dir()?;
+        if let Err(e) = fs::create_dir_all(&dir) {
+            println!("{:?} - Error creating thumbnail cache dir", e);
+            return None;
+        }
+        Some(Self {
+            dir,
+            index,
+            budget_bytes: budget_mb * 1000 * 1000,
+        })
+    }
+
+    pub fn key(file: &str) -> Option<String> {
+        let canonical = fs::canonicalize(file).ok()?;
+        let meta = fs::metadata(&canonical).ok()?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        hasher.update(&THUMBNAIL_VERSION.to_le_bytes());
+        hasher.update(canonical.to_string_lossy().as_bytes());
+        hasher.update(&meta.len().to_le_bytes());
+        hasher.update(&mtime.to_le_bytes());
+        Some(format!("{:032x}", hasher.digest128()))
+    }
+
+    fn key_path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.png", key))
+    }
+
+    pub fn load(&self, key: &str) -> Option<DynamicImage> {
+        let path = self.key_path(key);
+        let thumb = ImageReader::open(&path).ok()?.decode().ok()?;
+        self.index.touch_thumbnail(key);
+        Some(thumb)
+    }
+
+    pub fn store(&self, key: &str, file: &str, thumb: &DynamicImage) {
+        let path = self.key_path(key);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        match thumb.save(&path) {
+            Ok(()) => {
+                let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or_default();
+                self.index.add_thumbnail(key, file, bytes);
+            }
+            Err(e) => println!(
+                "{:?} - Error saving thumbnail {}",
+                e,
+                path.to_string_lossy()
+            ),
+        }
+    }
+
+    /// Removes the least recently used thumbnails until the cache fits its budget.
+    pub fn evict(&self) -> usize {
+        let mut size = self.index.thumbnails_size();
+        if size <= self.budget_bytes {
+            return 0;
+        }
+
+        let mut evicted = vec![];
+        for (key, bytes) in self.index.thumbnails_by_last_use() {
+            if size <= self.budget_bytes {
+                break;
+            }
+            let _ = fs::remove_file(self.key_path(&key));
+            size = size.saturating_sub(bytes);
+            evicted.push(key);
+        }
+        self.index.remove_thumbnails(&evicted);
+        println!("EVICTED {} THUMBNAILS", evicted.len());
+        evicted.len()
+    }
+
+    pub fn clear(&self) -> usize {
+        let removed = self.index.thumbnails_by_last_use().len();
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            println!("{:?} - Error clearing thumbnail cache", e);
+        }
+        let _ = fs::create_dir_all(&self.dir);
+        self.index.clear_thumbnails();
+        removed
+    }
+
+    /// Drops thumbnails of files that are no longer indexed, cache files unknown to the
+    /// index and index entries whose cache file is gone.
+    pub fn check(&self) -> usize {
+        let mut orphans = self.index.orphan_thumbnails();
+        for key in orphans.iter() {
+            let _ = fs::remove_file(self.key_path(key));
+        }
+
+        let known: HashSet<String> = self
+            .index
+            .thumbnails_by_last_use()
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| !orphans.contains(key))
+            .collect();
+
+        let mut on_disk = HashSet::new();
+        let mut removed = orphans.len();
+        for path in Self::cache_files(&self.dir) {
+            let key = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if known.contains(&key) {
+                on_disk.insert(key);
+            } else if fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+
+        for key in known.into_iter() {
+            if !on_disk.contains(&key) {
+                orphans.push(key);
+                removed += 1;
+            }
+        }
+        self.index.remove_thumbnails(&orphans);
+        removed
+    }
+
+    fn cache_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    files.extend(Self::cache_files(&path));
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files
+    }
+
+    pub fn stats(&self) -> ThumbnailCacheStats {
+        ThumbnailCacheStats {
+            files: self.index.thumbnails_count(),
+            bytes: self.index.thumbnails_size(),
+            budget_bytes: self.budget_bytes,
+            removed: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::{thread, time::Duration};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("desk_imager_thumbnails_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn cache(dir: &Path, budget_bytes: u64) -> ThumbnailCache {
+        ThumbnailCache {
+            dir: dir.join("cache"),
+            index: IndexDb::open_in_memory(),
+            budget_bytes,
+        }
+    }
+
+    fn thumb() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        }))
+    }
+
+    // -- source images, indexed so their thumbnails are not orphans
+    fn sources(cache: &ThumbnailCache, dir: &Path, names: &[&str]) -> Vec<(String, String)> {
+        let files: Vec<String> = names
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                thumb().save(&path).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        cache.index.insert_files(&dir.to_string_lossy(), &files);
+        files
+            .into_iter()
+            .map(|file| (ThumbnailCache::key(&file).unwrap(), file))
+            .collect()
+    }
+
+    #[test]
+    fn least_recently_used_thumbnails_are_evicted() {
+        let dir = temp_dir("evict");
+        let mut cache = cache(&dir, u64::MAX);
+        let files = sources(&cache, &dir, &["a.png", "b.png", "c.png"]);
+        let [(a, a_file), (b, b_file), (c, c_file)] = files.as_slice() else {
+            unreachable!()
+        };
+
+        // -- last use is kept in seconds, so: b, then c, then a loaded again
+        cache.store(a, a_file, &thumb());
+        cache.store(b, b_file, &thumb());
+        thread::sleep(Duration::from_millis(1100));
+        cache.store(c, c_file, &thumb());
+        thread::sleep(Duration::from_millis(1100));
+        assert!(cache.load(a).is_some());
+
+        assert_eq!(cache.evict(), 0);
+        let bytes = cache.stats().bytes;
+        cache.budget_bytes = bytes - 1;
+        assert_eq!(cache.evict(), 1);
+        assert!(!cache.key_path(b).exists());
+        assert!(cache.key_path(a).exists() && cache.key_path(c).exists());
+        assert_eq!(cache.stats().files, 2);
+
+        cache.budget_bytes = 0;
+        assert_eq!(cache.evict(), 2);
+        assert!(!cache.key_path(c).exists() && !cache.key_path(a).exists());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn check_drops_orphans() {
+        let dir = temp_dir("check");
+        let cache = cache(&dir, u64::MAX);
+        let files = sources(&cache, &dir, &["kept.png", "gone.png"]);
+        let [(kept, kept_file), (gone, gone_file)] = files.as_slice() else {
+            unreachable!()
+        };
+        cache.store(kept, kept_file, &thumb());
+        cache.store(gone, gone_file, &thumb());
+
+        // -- a thumbnail of a file never indexed
+        let unindexed = format!("{:032x}", 1);
+        cache.store(&unindexed, "/nowhere/unindexed.png", &thumb());
+        // -- a cache file the index does not know
+        let stray = format!("{:032x}", 2);
+        thumb().save(cache.key_path(&stray)).unwrap();
+        // -- an index entry whose cache file is gone
+        fs::remove_file(cache.key_path(gone)).unwrap();
+
+        assert_eq!(cache.check(), 3);
+        assert_eq!(
+            cache.index.thumbnails_by_last_use(),
+            vec![(
+                kept.clone(),
+                fs::metadata(cache.key_path(kept)).unwrap().len()
+            )]
+        );
+        assert!(!cache.key_path(&unindexed).exists());
+        assert!(!cache.key_path(&stray).exists());
+        assert_eq!(cache.check(), 0);
+    }
+}
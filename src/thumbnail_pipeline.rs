@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use egui::TextureOptions;
+use image::DynamicImage;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    enums::{BroadcastMsg, DirectoryImage, FileError, FileWithLabel},
+    image_loader::{open_image, upright_dimensions},
+    image_signature::ImageSignature,
+    index_db::IndexDb,
+    thumbnail_cache::{ThumbnailCache, THUMBNAIL_SIZE},
+};
+
+const MAX_WORKERS: usize = 4;
+
+struct ThumbnailJob {
+    dir: PathBuf,
+    file: FileWithLabel,
+    cache: Option<ThumbnailCache>,
+}
+
+#[derive(Default)]
+struct ThumbnailQueue {
+    jobs: VecDeque<ThumbnailJob>,
+    // -- (done, total) per directory, reset once a directory is finished
+    progress: HashMap<PathBuf, (usize, usize)>,
+}
+
+/// Decodes and resizes thumbnails on a pool of worker threads.
+///
+/// Every finished image is sent as `ThumbnailReady`, together with the directory
+/// progress, so the grid fills in while the rest is still decoding.
+pub struct ThumbnailPipeline {
+    queue: Arc<(Mutex<ThumbnailQueue>, Condvar)>,
+}
+
+pub fn create_thumbnail(
+    cache: &Option<ThumbnailCache>,
+    file: &str,
+) -> Result<DynamicImage, FileError> {
+    let key = ThumbnailCache::key(file);
+    if let (Some(cache), Some(key)) = (cache, key.as_ref()) {
+        if let Some(thumb) = cache.load(key) {
+            return Ok(thumb);
+        }
+    }
+
+    println!(">THUMBING IMG FILE: {}", file);
+    let thumb = open_image(file)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+    if let (Some(cache), Some(key)) = (cache, key.as_ref()) {
+        cache.store(key, file, &thumb);
+    }
+    Ok(thumb)
+}
+
+impl ThumbnailPipeline {
+    pub fn new(
+        ctx: egui::Context,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+        index: IndexDb,
+    ) -> Self {
+        let queue = Arc::new((Mutex::new(ThumbnailQueue::default()), Condvar::new()));
+
+        // -- keep one core for the UI
+        let workers = thread::available_parallelism()
+            .map(|n| n.get().saturating_sub(1))
+            .unwrap_or(1)
+            .clamp(1, MAX_WORKERS);
+        for i in 0..workers {
+            let queue = queue.clone();
+            let ctx = ctx.clone();
+            let action_tx = action_tx.clone();
+            let index = index.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("thumbnailer-{}", i))
+                .spawn(move || Self::worker(queue, ctx, action_tx, index));
+            if let Err(e) = spawned {
+                println!("{:?} - Error spawning thumbnail worker", e);
+            }
+        }
+
+        Self { queue }
+    }
+
+    fn worker(
+        queue: Arc<(Mutex<ThumbnailQueue>, Condvar)>,
+        ctx: egui::Context,
+        action_tx: Option<UnboundedSender<BroadcastMsg>>,
+        index: IndexDb,
+    ) {
+        let (lock, cvar) = &*queue;
+        loop {
+            let job = {
+                let mut q = lock.lock().unwrap();
+                loop {
+                    if let Some(job) = q.jobs.pop_front() {
+                        break job;
+                    }
+                    q = cvar.wait(q).unwrap();
+                }
+            };
+
+            let (texture, error) = match create_thumbnail(&job.cache, &job.file.file) {
+                Ok(thumb) => {
+                    // -- from the thumbnail, so cached ones need no decoding
+                    if !index.has_signature(&job.file.file) {
+                        let signature = ImageSignature::from_image(&thumb);
+                        index.set_signature(&job.file.file, &signature);
+                    }
+                    // -- only the header is read, for the width and height search filters
+                    if !index.has_dimensions(&job.file.file) {
+                        let (width, height) = upright_dimensions(&job.file.file).unwrap_or((0, 0));
+                        index.set_dimensions(&job.file.file, width, height);
+                    }
+                    let rgba = thumb.to_rgba8();
+                    let img = egui::ColorImage::from_rgba_unmultiplied(
+                        [thumb.width() as usize, thumb.height() as usize],
+                        rgba.as_raw(),
+                    );
+                    let texture =
+                        ctx.load_texture(job.file.file.to_string(), img, TextureOptions::default());
+                    (Some(texture), None)
+                }
+                Err(e) => {
+                    println!("{} - Error thumbing {}", e.describe(), job.file.file);
+                    (None, Some(e))
+                }
+            };
+            if error != job.file.error {
+                index.set_file_error(&job.file.file, error.as_ref());
+            }
+
+            let (done, total) = {
+                let mut q = lock.lock().unwrap();
+                let progress = q.progress.entry(job.dir.clone()).or_insert((0, 0));
+                progress.0 += 1;
+                let (done, total) = *progress;
+                if done >= total {
+                    q.progress.remove(&job.dir);
+                }
+                (done, total)
+            };
+
+            if done >= total {
+                if let Some(cache) = job.cache.as_ref() {
+                    cache.evict();
+                }
+            }
+
+            if let Some(tx) = action_tx.clone() {
+                let _ = tx.send(BroadcastMsg::ThumbnailReady(
+                    job.dir.clone(),
+                    DirectoryImage {
+                        file: job.file.file,
+                        labels: job.file.labels,
+                        description: job.file.description,
+                        texture,
+                        error,
+                    },
+                ));
+                let _ = tx.send(BroadcastMsg::ThumbnailProgress(job.dir, done, total));
+            }
+            ctx.request_repaint();
+        }
+    }
+
+    pub fn enqueue(&self, dir: PathBuf, files: Vec<FileWithLabel>, cache: Option<ThumbnailCache>) {
+        if files.is_empty() {
+            return;
+        }
+        let (lock, cvar) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        q.progress.entry(dir.clone()).or_insert((0, 0)).1 += files.len();
+        for file in files {
+            q.jobs.push_back(ThumbnailJob {
+                dir: dir.clone(),
+                file,
+                cache: cache.clone(),
+            });
+        }
+        cvar.notify_all();
+    }
+
+    /// Moves queued jobs of the given files (e.g. the ones on screen) to the front.
+    pub fn prioritize(&self, files: &[String]) {
+        let wanted: HashSet<&String> = files.iter().collect();
+        let (lock, _) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        let (front, rest): (VecDeque<ThumbnailJob>, VecDeque<ThumbnailJob>) = q
+            .jobs
+            .drain(..)
+            .partition(|job| wanted.contains(&job.file.file));
+        q.jobs = front;
+        q.jobs.extend(rest);
+    }
+
+    pub fn cancel_dir(&self, dir: &PathBuf) {
+        let (lock, _) = &*self.queue;
+        let mut q = lock.lock().unwrap();
+        q.jobs.retain(|job| job.dir != *dir);
+        q.progress.remove(dir);
+    }
+}
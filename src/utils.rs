@@ -1,16 +1,26 @@
 use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
 use ollama_rs::generation::images::Image;
 use rust_search_fork::FilterExt;
 use rust_search_fork::SearchBuilder;
 use std::cmp;
+use std::collections::HashSet;
 use std::fs;
 use std::future::Future;
+use std::io::{Cursor, Read};
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::config::SUPPORTED_IMAGE_FORMATS;
-use crate::enums::ImageBase64Search;
+use crate::config::{AVIF_SUPPORTED, AVIF_UNSUPPORTED_NOTE, SUPPORTED_IMAGE_FORMATS};
+use crate::enums::{FileError, ImageBase64Search, ImageStructured, VisionPreprocess};
+use crate::image_loader::{needs_correction, open_image};
+use crate::raw_preview::is_raw;
+
+const MAX_ANSWER_ITEMS: usize = 10;
+const MAX_ANSWER_ITEM_CHARS: usize = 40;
 
 pub fn spawn(f: impl Future<Output = ()> + Send + 'static) {
     tokio::spawn(f);
@@ -39,37 +49,481 @@ pub fn bytes_convert(num: f64) -> String {
     format!("{}{}", pretty_bytes, unit)
 }
 
-pub fn img_path_to_base64(img: String) -> Option<ImageBase64Search> {
-    let path = Path::new(&img);
-    let bytes = fs::read(path);
-    if let Ok(img_bytes) = bytes {
-        let b64_img = base64::engine::general_purpose::STANDARD.encode(&img_bytes);
-        return Some(ImageBase64Search {
-            base64: Image::from_base64(b64_img),
-            path: img.to_string(),
-        });
+/// Hex xxh3-128 digest of the file content, read in chunks.
+pub fn file_content_hash(path: &str) -> Option<String> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).ok()?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Some(format!("{:032x}", hasher.digest128()))
+}
+
+/// Reads and prepares an image for the vision model, see `VisionPreprocess`.
+pub fn img_path_to_base64(img: String, settings: &VisionPreprocess) -> Option<ImageBase64Search> {
+    let started = Instant::now();
+    match vision_image_bytes(&img, settings) {
+        Ok(img_bytes) => {
+            let b64_img = base64::engine::general_purpose::STANDARD.encode(&img_bytes);
+            Some(ImageBase64Search {
+                base64: Image::from_base64(b64_img),
+                path: img.to_string(),
+                original_bytes: fs::metadata(&img).map(|m| m.len()).unwrap_or_default(),
+                sent_bytes: img_bytes.len() as u64,
+                prepare_ms: started.elapsed().as_millis() as u64,
+            })
+        }
+        Err(e) => {
+            println!("{} - Error reading {}", e.describe(), img);
+            None
+        }
+    }
+}
+
+/// Prepares an image decoded already (e.g. a crop) for the vision model, `name` only
+/// identifies it.
+pub fn image_to_base64(
+    name: String,
+    img: &DynamicImage,
+    settings: &VisionPreprocess,
+) -> Option<ImageBase64Search> {
+    let started = Instant::now();
+    match encode_for_vision(img.clone(), settings) {
+        Ok(img_bytes) => {
+            let b64_img = base64::engine::general_purpose::STANDARD.encode(&img_bytes);
+            Some(ImageBase64Search {
+                base64: Image::from_base64(b64_img),
+                path: name,
+                original_bytes: img.as_bytes().len() as u64,
+                sent_bytes: img_bytes.len() as u64,
+                prepare_ms: started.elapsed().as_millis() as u64,
+            })
+        }
+        Err(e) => {
+            println!("{} - Error encoding {}", e.describe(), name);
+            None
+        }
+    }
+}
+
+/// PNGs and JPEGs that already fit the settings are sent as they are. Everything else
+/// is decoded upright in sRGB, downscaled and re-encoded, which also drops metadata.
+fn vision_image_bytes(img: &str, settings: &VisionPreprocess) -> Result<Vec<u8>, FileError> {
+    let path = Path::new(img);
+    let format = image_format(path);
+    let fits = |(w, h): (u32, u32)| settings.max_edge == 0 || w.max(h) <= settings.max_edge;
+    let passthrough = !settings.strip_metadata
+        && matches!(format, Some(ImageFormat::Png) | Some(ImageFormat::Jpeg))
+        && !is_raw(path)
+        // -- the long edge is the same upright, rotated files are re-encoded anyway
+        && image::image_dimensions(path).is_ok_and(fits)
+        && !needs_correction(img);
+    if passthrough {
+        return Ok(fs::read(path)?);
+    }
+
+    encode_for_vision(open_image(img)?, settings)
+}
+
+/// Downscales and encodes a decoded image the way `VisionPreprocess` asks for.
+pub fn encode_for_vision(
+    mut decoded: DynamicImage,
+    settings: &VisionPreprocess,
+) -> Result<Vec<u8>, FileError> {
+    let fits = |(w, h): (u32, u32)| settings.max_edge == 0 || w.max(h) <= settings.max_edge;
+    if !fits((decoded.width(), decoded.height())) {
+        decoded = decoded.resize(settings.max_edge, settings.max_edge, FilterType::Triangle);
+    }
+
+    let mut bytes = Cursor::new(vec![]);
+    if decoded.color().has_alpha() && !settings.flatten_alpha {
+        decoded.to_rgba8().write_to(&mut bytes, ImageFormat::Png)?;
+    } else {
+        let rgb = if decoded.color().has_alpha() {
+            flatten_on_white(&decoded)
+        } else {
+            decoded.to_rgb8()
+        };
+        JpegEncoder::new_with_quality(&mut bytes, settings.jpeg_quality.clamp(1, 100))
+            .encode_image(&rgb)?;
+    }
+    Ok(bytes.into_inner())
+}
+
+fn flatten_on_white(img: &DynamicImage) -> RgbImage {
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// Splits an Ollama URL into the base URL and port `ollama_rs` expects.
+pub fn split_ollama_url(url: &str) -> (String, u16) {
+    if let Some((base_url, port)) = url.rsplit_once(':') {
+        if let Ok(port_num) = port.trim_end_matches('/').parse::<u16>() {
+            return (base_url.to_string(), port_num);
+        }
+    }
+    ("http://localhost/".to_string(), 11343)
+}
+
+/// JSON answer in the `ImageStructured` schema, `None` when the model answered otherwise.
+pub fn parse_structured_answer(answer: &str) -> Option<ImageStructured> {
+    serde_json::from_str(answer.trim()).ok()
+}
+
+/// Free text answer of comma (or line) separated labels, for models without structured
+/// output.
+pub fn parse_label_list(answer: &str) -> ImageStructured {
+    ImageStructured {
+        labels: answer.split([',', '\n']).map(String::from).collect(),
+        ..Default::default()
+    }
+}
+
+/// Whether an error status body from Ollama says the answer format (the JSON schema)
+/// is not supported, as opposed to a missing model or an overloaded server.
+pub fn is_format_refused(error: &str) -> bool {
+    let error = error.to_lowercase();
+    (error.contains("format") || error.contains("schema"))
+        && [
+            "invalid",
+            "unsupported",
+            "not supported",
+            "cannot unmarshal",
+            "unknown",
+        ]
+        .iter()
+        .any(|w| error.contains(w))
+}
+
+/// Cleans up an answer, `None` when not a single usable label is left.
+pub fn validate_answer(mut answer: ImageStructured) -> Option<ImageStructured> {
+    answer.labels = clean_answer_list(&answer.labels);
+    answer.colors = clean_answer_list(&answer.colors);
+    answer.caption = answer.caption.trim().to_string();
+    answer.text = answer.text.trim().to_string();
+    answer.confidence = if answer.confidence.is_finite() {
+        answer.confidence.clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (!answer.labels.is_empty()).then_some(answer)
+}
+
+// -- trimmed, without sentences posing as labels and without duplicates
+fn clean_answer_list(items: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match s.rsplit_once(':') {
+            // -- "Here are the labels: dog"
+            Some((_, item)) if i == 0 => clean_answer_item(item),
+            _ => clean_answer_item(s),
+        })
+        .filter(|s| !s.is_empty() && s.chars().count() <= MAX_ANSWER_ITEM_CHARS)
+        .filter(|s| seen.insert(s.to_lowercase()))
+        .take(MAX_ANSWER_ITEMS)
+        .map(String::from)
+        .collect()
+}
+
+// -- without list markers ("-", "*", "1.", "2)"), quotes and markdown emphasis
+fn clean_answer_item(item: &str) -> &str {
+    let is_noise = |c: char| {
+        c.is_whitespace() || matches!(c, '"' | '\'' | '`' | '.' | '*' | '“' | '”' | '‘' | '’')
+    };
+    let mut item = item.trim_matches(is_noise);
+    if let Some(rest) = item.strip_prefix(['-', '•', '·']) {
+        item = rest;
+    }
+    let digits = item.len() - item.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits > 0 {
+        // -- "1.5 liter bottle" is a label, "1. bottle" a numbered one
+        match item[digits..].strip_prefix(['.', ')']) {
+            Some(rest) if rest.starts_with(char::is_whitespace) => item = rest,
+            _ => {}
+        }
+    }
+    item.trim_matches(is_noise)
+}
+
+/// Unix seconds as a `YYYY-MM-DD` date in UTC.
+pub fn format_date(secs: i64) -> String {
+    // -- civil from days, proleptic Gregorian calendar
+    let days = secs.div_euclid(86400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Seconds since the epoch at the start of a day, the inverse of `format_date`.
+pub fn date_secs(year: i64, month: u32, day: u32) -> i64 {
+    // -- days from civil, March is the first month of the computed year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe - 719_468) * 86400
+}
+
+/// Cosine similarity, 0 for vectors of different length or without length.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+pub fn project_dirs() -> Option<directories::ProjectDirs> {
+    directories::ProjectDirs::from("", "", "deskvision")
+}
+
+pub fn thumbnails_dir() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.cache_dir().join("thumbnails"))
+}
+
+/// Image format of a file, sniffed from its magic bytes with the extension as fallback
+/// (TGA has no signature, a deleted file cannot be read).
+///
+/// Files with a non-image extension are skipped without being opened.
+pub fn image_format(path: &Path) -> Option<ImageFormat> {
+    let by_ext = match path.extension() {
+        Some(ext) => Some(ImageFormat::from_extension(ext)?),
+        None => None,
+    };
+    let mut head = [0u8; 32];
+    let read = fs::File::open(path)
+        .and_then(|mut f| f.read(&mut head))
+        .unwrap_or(0);
+    image::guess_format(&head[..read]).ok().or(by_ext)
+}
+
+/// Name of a format as listed in `SUPPORTED_IMAGE_FORMATS`.
+pub fn format_name(format: ImageFormat) -> Option<&'static str> {
+    let name = match format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpeg",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Gif => "gif",
+        ImageFormat::Bmp => "bmp",
+        ImageFormat::Tiff => "tiff",
+        ImageFormat::Ico => "ico",
+        ImageFormat::Pnm => "pnm",
+        ImageFormat::Qoi => "qoi",
+        ImageFormat::Tga => "tga",
+        ImageFormat::Dds => "dds",
+        ImageFormat::OpenExr => "exr",
+        ImageFormat::Hdr => "hdr",
+        ImageFormat::Farbfeld => "farbfeld",
+        ImageFormat::Avif => "avif",
+        _ => return None,
+    };
+    SUPPORTED_IMAGE_FORMATS.contains(&name).then_some(name)
+}
+
+/// Format name for the format settings, with a note when this build cannot decode it.
+pub fn format_label(format: &str) -> String {
+    if format == "avif" && !AVIF_SUPPORTED {
+        return format!("{} ({})", format, AVIF_UNSUPPORTED_NOTE);
+    }
+    format.to_string()
+}
+
+/// Supported format of a file, empty when it is not a supported image.
+pub fn file_format(path: &str) -> String {
+    let path = Path::new(path);
+    // -- RAW files have a TIFF header, so they are told apart first
+    if is_raw(path) {
+        return "raw".to_string();
     }
-    None
+    image_format(path)
+        .and_then(format_name)
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub fn is_supported_image(path: &Path) -> bool {
+    is_raw(path) || image_format(path).and_then(format_name).is_some()
+}
+
+// -- an unmounted mount point usually still exists but sits on another device
+#[cfg(unix)]
+pub fn dir_device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+pub fn dir_device(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|_| 0)
+}
+
+pub fn is_non_empty_dir(path: &Path) -> bool {
+    fs::read_dir(path)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false)
 }
 
-pub fn search_images_at_path(path: PathBuf) -> Vec<String> {
+pub fn search_images_at_path(path: PathBuf, formats: &[String]) -> Vec<String> {
     let p = path.to_string_lossy().to_string();
     let search: Vec<String> = SearchBuilder::default()
         .location(&p)
         .dirs(false)
-        .custom_filter(|entry| {
-            let e = entry.metadata().unwrap();
-            if e.is_file() && !e.is_dir() {
-                let path = entry.path();
-                if let Some(ext) = path.extension() {
-                    if let Some(e) = ext.to_str() {
-                        return SUPPORTED_IMAGE_FORMATS.contains(&e);
-                    }
-                }
-            }
-            false
-        })
+        .custom_filter(|entry| entry.metadata().map(|e| e.is_file()).unwrap_or(false))
         .build()
         .collect();
+    // -- the search filter cannot capture, so the formats are checked here
     search
+        .into_iter()
+        .filter(|f| formats.contains(&file_format(f)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(answer: &str) -> Vec<String> {
+        validate_answer(parse_label_list(answer))
+            .map(|a| a.labels)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn plain_list() {
+        assert_eq!(
+            labels("dog, beach ball , sand"),
+            ["dog", "beach ball", "sand"]
+        );
+    }
+
+    #[test]
+    fn preamble() {
+        assert_eq!(labels("Here are the labels: dog, cat"), ["dog", "cat"]);
+        assert_eq!(labels("Labels:\n- dog\n- cat"), ["dog", "cat"]);
+    }
+
+    #[test]
+    fn numbering_and_bullets() {
+        assert_eq!(
+            labels("1. dog\n2) cat\n* tree\n• sky"),
+            ["dog", "cat", "tree", "sky"]
+        );
+        assert_eq!(
+            labels("1.5 liter bottle, 3D printer"),
+            ["1.5 liter bottle", "3D printer"]
+        );
+    }
+
+    #[test]
+    fn quotes_and_emphasis() {
+        assert_eq!(
+            labels("\"dog\", 'cat', “tree”, **sky**, `car`."),
+            ["dog", "cat", "tree", "sky", "car"]
+        );
+    }
+
+    #[test]
+    fn sentences_and_duplicates() {
+        assert_eq!(
+            labels("dog, Dog, this image shows a dog running on the beach at sunset"),
+            ["dog"]
+        );
+        assert!(labels("-\n\"\"\n**").is_empty());
+    }
+
+    #[test]
+    fn refused_format() {
+        assert!(is_format_refused(
+            r#"{"error":"json: cannot unmarshal object into Go struct field GenerateRequest.format of type string"}"#
+        ));
+        assert!(is_format_refused(
+            r#"{"error":"invalid format: schema not supported"}"#
+        ));
+    }
+
+    #[test]
+    fn server_errors_are_not_a_refused_format() {
+        assert!(!is_format_refused(
+            r#"{"error":"model \"llava\" not found, try pulling it first"}"#
+        ));
+        assert!(!is_format_refused(
+            r#"{"error":"model requires more system memory (8.0 GiB) than is available (4.0 GiB)"}"#
+        ));
+        assert!(!is_format_refused(
+            "server busy, please try again. maximum pending requests exceeded"
+        ));
+    }
+
+    fn sample_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("desk_imager_utils_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn png_bytes() -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4))
+            .write_to(&mut bytes, ImageFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn format_is_sniffed_from_the_content() {
+        let dir = sample_dir("sniff");
+        let misnamed = dir.join("photo.jpg");
+        fs::write(&misnamed, png_bytes()).unwrap();
+        let no_ext = dir.join("photo");
+        fs::write(&no_ext, png_bytes()).unwrap();
+        let notes = dir.join("notes.txt");
+        fs::write(&notes, "not an image").unwrap();
+
+        assert_eq!(file_format(&misnamed.to_string_lossy()), "png");
+        assert_eq!(file_format(&no_ext.to_string_lossy()), "png");
+        assert_eq!(file_format(&notes.to_string_lossy()), "");
+        // -- unreadable, e.g. deleted, files fall back to the extension
+        assert_eq!(file_format(&dir.join("gone.jpg").to_string_lossy()), "jpeg");
+    }
+
+    #[test]
+    fn search_keeps_files_of_the_chosen_formats() {
+        let dir = sample_dir("search");
+        fs::write(dir.join("misnamed.jpg"), png_bytes()).unwrap();
+        fs::write(dir.join("photo"), png_bytes()).unwrap();
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let mut found = search_images_at_path(dir.clone(), &["png".to_string()]);
+        found.sort();
+        let expected: Vec<String> = ["misnamed.jpg", "photo"]
+            .iter()
+            .map(|f| dir.join(f).to_string_lossy().to_string())
+            .collect();
+        assert_eq!(found, expected);
+        assert!(search_images_at_path(dir, &["jpeg".to_string()]).is_empty());
+    }
 }